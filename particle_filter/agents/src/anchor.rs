@@ -1,15 +1,24 @@
+use crate::clock::SPEED_OF_LIGHT;
 use crate::dynamics_model::DynamicsModel;
+use crate::likelihood::LikelihoodModel;
 use crate::swarm_element::SwarmElement;
+use crate::twr::TwrConfig;
 use crate::Measurements;
 
 use nalgebra::Vector3;
-use rand::rng;
-use rand_distr::{Distribution, Normal};
+use rand::Rng;
+use rand_distr::Normal;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Anchor {
     pub position: Vector3<f64>,
     pub ranging_noise: Normal<f64>,
+    // Model the filter uses to weight ranges from this anchor.
+    pub likelihood: LikelihoodModel,
+    // Model used to corrupt the simulated ranges this anchor produces.
+    pub range_errors: LikelihoodModel,
+    // Simulate ranges through a two-way ranging exchange instead of adding range errors.
+    pub twr: Option<TwrConfig>,
 }
 
 impl Anchor {
@@ -19,8 +28,36 @@ impl Anchor {
         Self {
             position,
             ranging_noise,
+            likelihood: LikelihoodModel::default(),
+            range_errors: LikelihoodModel::default(),
+            twr: None,
         }
     }
+
+    pub fn with_likelihood(mut self, likelihood: LikelihoodModel) -> Self {
+        self.likelihood = likelihood;
+        self
+    }
+
+    pub fn with_range_errors(mut self, range_errors: LikelihoodModel) -> Self {
+        self.range_errors = range_errors;
+        self
+    }
+
+    pub fn with_twr(mut self, twr: TwrConfig) -> Self {
+        self.twr = Some(twr);
+        self
+    }
+
+    // Raw arrival time (s) of a tag transmission, shifted by the tag's clock offset.
+    pub fn time_of_arrival<M: DynamicsModel>(
+        &self,
+        swarm_element: &SwarmElement<M>,
+        std_ranging: f64,
+        rng: &mut impl Rng,
+    ) -> f64 {
+        self.ranging(swarm_element, std_ranging, rng) / SPEED_OF_LIGHT + swarm_element.clock.offset
+    }
 }
 
 impl Default for Anchor {
@@ -29,15 +66,25 @@ impl Default for Anchor {
         Anchor {
             position: Vector3::zeros(),
             ranging_noise,
+            likelihood: LikelihoodModel::default(),
+            range_errors: LikelihoodModel::default(),
+            twr: None,
         }
     }
 }
 
 impl<M: DynamicsModel> Measurements<M> for Anchor {
-    fn ranging(&self, swarm_element: &SwarmElement<M>, std_raning: f64) -> f64 {
-        let noise = Normal::new(0.0, std_raning).unwrap();
+    fn ranging<R: Rng + ?Sized>(
+        &self,
+        swarm_element: &SwarmElement<M>,
+        std_raning: f64,
+        rng: &mut R,
+    ) -> f64 {
         let diff = self.position - swarm_element.dynamics_model.position();
-        diff.norm() + noise.sample(&mut rng())
+        match &self.twr {
+            Some(twr) => twr.measure_distance(diff.norm(), rng),
+            None => diff.norm() + self.range_errors.sample_error(std_raning, rng),
+        }
     }
 }
 
@@ -61,6 +108,8 @@ mod tests {
         assert_eq!(anchor.position.y, position.y);
         assert_eq!(anchor.position.z, position.z);
         assert_eq!(anchor.ranging_noise.std_dev(), sd_ranging_noise);
+        assert_eq!(anchor.likelihood, LikelihoodModel::Gaussian);
+        assert_eq!(anchor.range_errors, LikelihoodModel::Gaussian);
     }
 
     #[test]
@@ -96,15 +145,17 @@ mod tests {
         let num_samples = 100_000;
         let empirical_sum: f64 = (0..num_samples)
             .into_par_iter()
-            .map(|_| anchor.ranging(&swarm_element, sd_ranging_noise))
+            .map_init(rand::rng, |rng, _| {
+                anchor.ranging(&swarm_element, sd_ranging_noise, rng)
+            })
             .sum();
 
         let empirical_mean = empirical_sum / num_samples as f64;
 
         let empirical_variance: f64 = (0..num_samples)
             .into_par_iter()
-            .map(|_| {
-                let x = anchor.ranging(&swarm_element, sd_ranging_noise);
+            .map_init(rand::rng, |rng, _| {
+                let x = anchor.ranging(&swarm_element, sd_ranging_noise, rng);
                 (x - empirical_mean).powi(2)
             })
             .sum::<f64>()
@@ -123,4 +174,88 @@ mod tests {
         let is_nosiy = 0.0;
         assert!(empirical_variance > is_nosiy);
     }
+
+    #[test]
+    fn test_anchor_ranging_with_nlos_range_errors() {
+        let nlos = LikelihoodModel::Nlos {
+            nlos_prob: 0.5,
+            mean_excess: 3.0,
+        };
+        let anchor = Anchor::new(Vector3::new(0.0, 0.0, 0.0), 0.1)
+            .with_likelihood(nlos)
+            .with_range_errors(nlos);
+        assert_eq!(anchor.likelihood, nlos);
+
+        let swarm_element: SwarmElement<WhiteNoiseAcceleration> = SwarmElement {
+            dynamics_model: WhiteNoiseAcceleration::new(
+                Vector3::new(3.0, 4.0, 0.0),
+                Vector3::zeros(),
+                Vector3::zeros(),
+                Vector3::zeros(),
+            ),
+            ..Default::default()
+        };
+
+        let std_ranging = 0.1;
+        let num_samples = 100_000;
+        let empirical_mean: f64 = (0..num_samples)
+            .into_par_iter()
+            .map_init(rand::rng, |rng, _| {
+                anchor.ranging(&swarm_element, std_ranging, rng)
+            })
+            .sum::<f64>()
+            / num_samples as f64;
+
+        // true range 5 m plus an average excess delay of 0.5 * 3 m
+        let expected_mean = 5.0 + 0.5 * 3.0;
+        assert!((empirical_mean - expected_mean).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_time_of_arrival_includes_clock_offset() {
+        use crate::clock::ClockModel;
+
+        let anchor = Anchor::new(Vector3::zeros(), 0.1);
+        let swarm_element: SwarmElement<WhiteNoiseAcceleration> = SwarmElement {
+            dynamics_model: WhiteNoiseAcceleration::new(
+                Vector3::new(300.0, 400.0, 0.0),
+                Vector3::zeros(),
+                Vector3::zeros(),
+                Vector3::zeros(),
+            ),
+            ..Default::default()
+        }
+        .with_clock(ClockModel::new(1e-6, 0.0, 0.0, 0.0));
+
+        let std_ranging = 1e-9;
+        let toa = anchor.time_of_arrival(&swarm_element, std_ranging, &mut rand::rng());
+        let expected = 500.0 / SPEED_OF_LIGHT + 1e-6;
+        assert!((toa - expected).abs() <= 1e-15, "toa {toa}");
+    }
+
+    #[test]
+    fn test_anchor_ranging_through_twr_exchange() {
+        use crate::twr::TwrProtocol;
+
+        let twr = TwrConfig::new(TwrProtocol::SingleSided, 1e-3)
+            .with_ppm(5.0, -5.0)
+            .with_timestamp_std(0.0);
+        let anchor = Anchor::new(Vector3::zeros(), 0.1).with_twr(twr);
+        assert_eq!(anchor.twr, Some(twr));
+
+        let swarm_element: SwarmElement<WhiteNoiseAcceleration> = SwarmElement {
+            dynamics_model: WhiteNoiseAcceleration::new(
+                Vector3::new(6.0, 8.0, 0.0),
+                Vector3::zeros(),
+                Vector3::zeros(),
+                Vector3::zeros(),
+            ),
+            ..Default::default()
+        };
+
+        // the crystal offset bias replaces the Gaussian range noise
+        let expected = 10.0 + 0.5 * 1e-3 * 10e-6 * SPEED_OF_LIGHT;
+        let ranging = anchor.ranging(&swarm_element, 0.1, &mut rand::rng());
+        assert!((ranging - expected).abs() <= 0.01, "ranging {ranging}");
+    }
 }
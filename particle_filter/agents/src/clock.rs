@@ -0,0 +1,88 @@
+use rand::Rng;
+use rand_distr::{Distribution, StandardNormal};
+use serde::{Deserialize, Serialize};
+
+pub const SPEED_OF_LIGHT: f64 = 299_792_458.0;
+
+// Tag clock error relative to the synchronised anchors. Offset (s) and drift (s/s)
+// both follow a random walk; the noise terms are standard deviations per sqrt(second).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClockModel {
+    pub offset: f64,
+    pub drift: f64,
+    pub offset_noise: f64,
+    pub drift_noise: f64,
+}
+
+impl ClockModel {
+    pub fn new(offset: f64, drift: f64, offset_noise: f64, drift_noise: f64) -> Self {
+        assert!(
+            offset_noise >= 0.0 && drift_noise >= 0.0,
+            "ClockModel: noise must be >= 0"
+        );
+        Self {
+            offset,
+            drift,
+            offset_noise,
+            drift_noise,
+        }
+    }
+
+    // Advance an (offset, drift) pair by `dt` under this model's process noise.
+    pub fn propagate<R: Rng + ?Sized>(
+        &self,
+        offset: f64,
+        drift: f64,
+        dt: f64,
+        rng: &mut R,
+    ) -> (f64, f64) {
+        let sqrt_dt = dt.max(0.0).sqrt();
+        let n_offset: f64 = StandardNormal.sample(rng);
+        let n_drift: f64 = StandardNormal.sample(rng);
+        (
+            offset + drift * dt + self.offset_noise * sqrt_dt * n_offset,
+            drift + self.drift_noise * sqrt_dt * n_drift,
+        )
+    }
+
+    pub fn step<R: Rng + ?Sized>(&mut self, dt: f64, rng: &mut R) {
+        (self.offset, self.drift) = self.propagate(self.offset, self.drift, dt, rng);
+    }
+
+    // Range error (metres) the current offset causes on a time-of-arrival.
+    pub fn bias_metres(&self) -> f64 {
+        self.offset * SPEED_OF_LIGHT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn noiseless_clock_drifts_linearly() {
+        let mut clock = ClockModel::new(1e-6, 2e-8, 0.0, 0.0);
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..10 {
+            clock.step(0.5, &mut rng);
+        }
+        assert!((clock.offset - (1e-6 + 5.0 * 2e-8)).abs() <= 1e-18);
+        assert_eq!(clock.drift, 2e-8);
+        assert!((clock.bias_metres() - clock.offset * SPEED_OF_LIGHT).abs() <= 1e-9);
+    }
+
+    #[test]
+    fn offset_random_walk_variance_grows_with_time() {
+        let clock = ClockModel::new(0.0, 0.0, 1e-9, 0.0);
+        let mut rng = StdRng::seed_from_u64(2);
+        let n = 50_000;
+        let var: f64 = (0..n)
+            .map(|_| clock.propagate(0.0, 0.0, 4.0, &mut rng).0.powi(2))
+            .sum::<f64>()
+            / n as f64;
+        // sigma^2 * dt
+        assert!((var / 4e-18 - 1.0).abs() <= 0.03, "var {var}");
+    }
+}
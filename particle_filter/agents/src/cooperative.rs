@@ -0,0 +1,151 @@
+use nalgebra::{Matrix3, Vector3};
+use rand::Rng;
+
+use crate::likelihood::LikelihoodModel;
+use crate::particle_filter::ParticleFilter;
+use crate::resampling::ResamplingScheme;
+
+// What one swarm element knows about a neighbour it ranged against.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NeighbourBelief {
+    // Equally weighted samples drawn from the neighbour's particle cloud.
+    Particles(Vec<Vector3<f64>>),
+    // The neighbour's estimate, with its covariance inflating the range noise.
+    Gaussian {
+        mean: Vector3<f64>,
+        covariance: Matrix3<f64>,
+    },
+}
+
+impl NeighbourBelief {
+    pub fn from_particles<R: Rng + ?Sized>(
+        particle_filter: &ParticleFilter,
+        samples: usize,
+        rng: &mut R,
+    ) -> Self {
+        let positions: Vec<Vector3<f64>> = particle_filter
+            .particles
+            .iter()
+            .map(|p| p.position)
+            .collect();
+        let w = particle_filter.linear_weights();
+        let picked = ResamplingScheme::Systematic
+            .indices(&w, samples, rng)
+            .into_iter()
+            .map(|i| positions[i])
+            .collect();
+        NeighbourBelief::Particles(picked)
+    }
+
+    pub fn from_estimate(particle_filter: &ParticleFilter) -> Self {
+        NeighbourBelief::Gaussian {
+            mean: particle_filter.posterior_mean(),
+            covariance: particle_filter.posterior_covariance(),
+        }
+    }
+
+    // Log-likelihood of a range measured from `position` to this neighbour.
+    pub fn log_likelihood(
+        &self,
+        ranging: f64,
+        position: &Vector3<f64>,
+        sigma: f64,
+        likelihood: &LikelihoodModel,
+    ) -> f64 {
+        match self {
+            NeighbourBelief::Particles(samples) => {
+                if samples.is_empty() {
+                    return 0.0;
+                }
+                // log of the mean likelihood over the neighbour's samples
+                let ll: Vec<f64> = samples
+                    .iter()
+                    .map(|y| likelihood.log_likelihood(ranging - (position - y).norm(), sigma))
+                    .collect();
+                let m = ll.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+                if m == f64::NEG_INFINITY {
+                    return m;
+                }
+                let sum: f64 = ll.iter().map(|l| (l - m).exp()).sum();
+                m + (sum / samples.len() as f64).ln()
+            }
+            NeighbourBelief::Gaussian { mean, covariance } => {
+                let diff = position - mean;
+                let dist = diff.norm();
+                // project the neighbour's uncertainty onto the line of sight
+                let radial_var = if dist > 1e-12 {
+                    let u = diff / dist;
+                    (u.transpose() * covariance * u)[(0, 0)].max(0.0)
+                } else {
+                    covariance.trace() / 3.0
+                };
+                let sigma_eff = (sigma * sigma + radial_var).sqrt();
+                likelihood.log_likelihood(ranging - dist, sigma_eff)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::particle_filter::{BoundingBox, Particle};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn point_beliefs_match_anchor_likelihood() {
+        let neighbour = Vector3::new(3.0, 4.0, 0.0);
+        let position = Vector3::zeros();
+        let sigma = 0.5;
+        let ranging = 5.3;
+        let expected = LikelihoodModel::Gaussian.log_likelihood(ranging - 5.0, sigma);
+
+        let cloud = NeighbourBelief::Particles(vec![neighbour; 4]);
+        let gaussian = NeighbourBelief::Gaussian {
+            mean: neighbour,
+            covariance: Matrix3::zeros(),
+        };
+
+        for belief in [cloud, gaussian] {
+            let got = belief.log_likelihood(ranging, &position, sigma, &LikelihoodModel::Gaussian);
+            assert!((got - expected).abs() <= 1e-12, "{belief:?}");
+        }
+    }
+
+    #[test]
+    fn gaussian_belief_inflates_noise_along_line_of_sight() {
+        let mean = Vector3::new(10.0, 0.0, 0.0);
+        // uncertain along x only
+        let covariance = Matrix3::from_diagonal(&Vector3::new(4.0, 0.0, 0.0));
+        let belief = NeighbourBelief::Gaussian { mean, covariance };
+        let sigma = 0.5;
+        let g = LikelihoodModel::Gaussian;
+
+        // along x the effective std is sqrt(0.25 + 4)
+        let along = belief.log_likelihood(12.0, &Vector3::zeros(), sigma, &g);
+        assert!((along - g.log_likelihood(2.0, 4.25_f64.sqrt())).abs() <= 1e-12);
+
+        // along y the neighbour's uncertainty is perpendicular and does not count
+        let across = belief.log_likelihood(12.0, &Vector3::new(10.0, -10.0, 0.0), sigma, &g);
+        assert!((across - g.log_likelihood(2.0, sigma)).abs() <= 1e-12);
+    }
+
+    #[test]
+    fn particle_belief_samples_follow_weights() {
+        let bounding_box =
+            BoundingBox::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 1.0)).unwrap();
+        let mut particle_filter = ParticleFilter::new(&bounding_box, 10, 0.5);
+        particle_filter.particles[3] = Particle::new(Vector3::new(7.0, 7.0, 7.0), 100.0);
+        particle_filter.normalize_weights();
+
+        let mut rng = StdRng::seed_from_u64(8);
+        let belief = NeighbourBelief::from_particles(&particle_filter, 25, &mut rng);
+        match belief {
+            NeighbourBelief::Particles(samples) => {
+                assert_eq!(samples.len(), 25);
+                assert!(samples.iter().all(|s| *s == Vector3::new(7.0, 7.0, 7.0)));
+            }
+            other => panic!("expected particle belief, got {other:?}"),
+        }
+    }
+}
@@ -8,6 +8,8 @@ pub trait DynamicsModel: Send {
     fn step(&mut self, dt: f64, rng: &mut impl Rng);
     fn position(&self) -> Vector3<f64>;
     fn velocity(&self) -> Vector3<f64>;
+    // Overwrite the true state, e.g. with recorded ground truth.
+    fn set_state(&mut self, position: Vector3<f64>, velocity: Vector3<f64>);
     fn predict_next_state(
         &self,
         dt: f64,
@@ -15,6 +17,13 @@ pub trait DynamicsModel: Send {
         velocity: Vector3<f64>,
         rng: &mut impl Rng,
     ) -> Vector3<f64>;
+    fn predict_next_state_with_velocity(
+        &self,
+        dt: f64,
+        position: Vector3<f64>,
+        velocity: Vector3<f64>,
+        rng: &mut impl Rng,
+    ) -> (Vector3<f64>, Vector3<f64>);
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -77,6 +86,11 @@ impl DynamicsModel for WhiteNoiseAcceleration {
         self.vel
     }
 
+    fn set_state(&mut self, position: Vector3<f64>, velocity: Vector3<f64>) {
+        self.pos = position;
+        self.vel = velocity;
+    }
+
     fn predict_next_state(
         &self,
         dt: f64,
@@ -91,6 +105,24 @@ impl DynamicsModel for WhiteNoiseAcceleration {
         );
         position + velocity * dt + 0.5 * a * dt * dt
     }
+
+    fn predict_next_state_with_velocity(
+        &self,
+        dt: f64,
+        position: Vector3<f64>,
+        velocity: Vector3<f64>,
+        rng: &mut impl Rng,
+    ) -> (Vector3<f64>, Vector3<f64>) {
+        let a: Vector3<f64> = Vector3::new(
+            self.accel_noise.x.sample(rng),
+            self.accel_noise.y.sample(rng),
+            self.accel_noise.z.sample(rng),
+        );
+        (
+            position + velocity * dt + 0.5 * a * dt * dt,
+            velocity + a * dt,
+        )
+    }
 }
 
 #[cfg(test)]
@@ -245,4 +277,26 @@ mod tests {
         let eps = 1e-12;
         assert!((got - pos0).abs().max() <= eps);
     }
+
+    #[test]
+    fn predict_next_state_with_velocity_matches_step() {
+        let pos0 = Vector3::new(1.0, 2.0, 3.0);
+        let vel0 = Vector3::new(0.3, -0.2, 0.1);
+        let mean_a = Vector3::new(0.1, 0.0, -0.1);
+        let sigma_a = Vector3::new(1.0, 2.0, 0.5);
+        let mut model = WhiteNoiseAcceleration::new(pos0, vel0, mean_a, sigma_a);
+        let dt = 0.1;
+
+        let seed: u64 = 11;
+        let mut rng_call = StdRng::seed_from_u64(seed);
+        let mut rng_step = StdRng::seed_from_u64(seed);
+
+        let (got_pos, got_vel) =
+            model.predict_next_state_with_velocity(dt, pos0, vel0, &mut rng_call);
+        model.step(dt, &mut rng_step);
+
+        let eps = 1e-12;
+        assert!((got_pos - model.position()).abs().max() <= eps);
+        assert!((got_vel - model.velocity()).abs().max() <= eps);
+    }
 }
@@ -0,0 +1,224 @@
+use nalgebra::Vector3;
+use serde::{Deserialize, Serialize};
+
+use crate::particle_filter::Particle;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PointEstimator {
+    #[default]
+    WeightedMean,
+    // Highest-weight particle (MAP over the particle set).
+    MaxWeight,
+    // Per-axis weighted median.
+    WeightedMedian,
+    // Gaussian-kernel mean shift started from the highest-weight particle.
+    MeanShift {
+        bandwidth: f64,
+        max_iterations: usize,
+        tolerance: f64,
+    },
+}
+
+impl PointEstimator {
+    pub fn mean_shift(bandwidth: f64) -> Self {
+        assert!(bandwidth > 0.0, "PointEstimator: bandwidth must be > 0");
+        PointEstimator::MeanShift {
+            bandwidth,
+            max_iterations: 50,
+            tolerance: 1e-6,
+        }
+    }
+
+    // For settings that bypass `mean_shift`, e.g. read from a config file.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        match *self {
+            PointEstimator::MeanShift { bandwidth, .. }
+                if !(bandwidth.is_finite() && bandwidth > 0.0) =>
+            {
+                Err("PointEstimator: bandwidth must be > 0")
+            }
+            PointEstimator::MeanShift { tolerance, .. }
+                if !(tolerance.is_finite() && tolerance >= 0.0) =>
+            {
+                Err("PointEstimator: tolerance must be >= 0")
+            }
+            PointEstimator::MeanShift {
+                max_iterations: 0, ..
+            } => Err("PointEstimator: max_iterations must be > 0"),
+            _ => Ok(()),
+        }
+    }
+
+    // `w` holds the normalized linear weights of `particles`.
+    pub fn estimate(&self, particles: &[Particle], w: &[f64]) -> Vector3<f64> {
+        if particles.is_empty() {
+            return Vector3::zeros();
+        }
+
+        match self {
+            PointEstimator::WeightedMean => weighted_mean(particles, w),
+            PointEstimator::MaxWeight => particles[heaviest(w)].position,
+            PointEstimator::WeightedMedian => Vector3::new(
+                weighted_median(particles, w, 0),
+                weighted_median(particles, w, 1),
+                weighted_median(particles, w, 2),
+            ),
+            PointEstimator::MeanShift {
+                bandwidth,
+                max_iterations,
+                tolerance,
+            } => {
+                let start = particles[heaviest(w)].position;
+                mean_shift(particles, w, start, *bandwidth, *max_iterations, *tolerance)
+            }
+        }
+    }
+}
+
+fn weighted_mean(particles: &[Particle], w: &[f64]) -> Vector3<f64> {
+    let mut mu = Vector3::zeros();
+    for (p, wi) in particles.iter().zip(w.iter()) {
+        mu += p.position * *wi;
+    }
+    mu
+}
+
+fn heaviest(w: &[f64]) -> usize {
+    w.iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+fn weighted_median(particles: &[Particle], w: &[f64], axis: usize) -> f64 {
+    let mut values: Vec<(f64, f64)> = particles
+        .iter()
+        .zip(w.iter())
+        .map(|(p, wi)| (p.position[axis], *wi))
+        .collect();
+    values.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let total: f64 = values.iter().map(|(_, wi)| wi).sum();
+    let half = 0.5 * total;
+    let mut csum = 0.0;
+    for (v, wi) in &values {
+        csum += wi;
+        if csum >= half {
+            return *v;
+        }
+    }
+    values.last().map(|(v, _)| *v).unwrap_or(0.0)
+}
+
+fn mean_shift(
+    particles: &[Particle],
+    w: &[f64],
+    start: Vector3<f64>,
+    bandwidth: f64,
+    max_iterations: usize,
+    tolerance: f64,
+) -> Vector3<f64> {
+    let inv_two_h2 = 0.5 / (bandwidth * bandwidth);
+    let mut x = start;
+    for _ in 0..max_iterations {
+        let mut num = Vector3::zeros();
+        let mut den = 0.0;
+        for (p, wi) in particles.iter().zip(w.iter()) {
+            let k = wi * (-(p.position - x).norm_squared() * inv_two_h2).exp();
+            num += p.position * k;
+            den += k;
+        }
+        if den <= 0.0 {
+            break;
+        }
+
+        let next = num / den;
+        let shift = (next - x).norm();
+        x = next;
+        if shift <= tolerance {
+            break;
+        }
+    }
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two clusters around -10 and +10 on x; the one at +10 carries 70% of the mass.
+    fn bimodal() -> (Vec<Particle>, Vec<f64>) {
+        let mut particles = Vec::new();
+        let mut w = Vec::new();
+        for i in 0..10 {
+            let offset = 0.1 * (i as f64 - 4.5);
+            particles.push(Particle::new(Vector3::new(-10.0 + offset, 0.0, 0.0), 0.0));
+            w.push(0.03);
+            particles.push(Particle::new(Vector3::new(10.0 + offset, 1.0, 0.0), 0.0));
+            w.push(0.07);
+        }
+        (particles, w)
+    }
+
+    #[test]
+    fn weighted_mean_lands_between_modes() {
+        let (particles, w) = bimodal();
+        let est = PointEstimator::WeightedMean.estimate(&particles, &w);
+        assert!((est.x - 4.0).abs() <= 1e-9, "got {est}");
+    }
+
+    #[test]
+    fn max_weight_picks_heaviest_particle() {
+        let (mut particles, mut w) = bimodal();
+        particles.push(Particle::new(Vector3::new(3.0, 3.0, 3.0), 0.0));
+        w.push(0.5);
+        let est = PointEstimator::MaxWeight.estimate(&particles, &w);
+        assert_eq!(est, Vector3::new(3.0, 3.0, 3.0));
+    }
+
+    #[test]
+    fn weighted_median_selects_heavier_cluster_per_axis() {
+        let (particles, w) = bimodal();
+        let est = PointEstimator::WeightedMedian.estimate(&particles, &w);
+        assert!((est.x - 10.0).abs() <= 0.5, "got {est}");
+        assert_eq!(est.y, 1.0);
+        assert_eq!(est.z, 0.0);
+    }
+
+    #[test]
+    fn mean_shift_converges_to_dominant_mode() {
+        let (particles, w) = bimodal();
+        let est = PointEstimator::mean_shift(1.0).estimate(&particles, &w);
+        assert!(
+            (est - Vector3::new(10.0, 1.0, 0.0)).norm() <= 0.1,
+            "got {est}"
+        );
+    }
+
+    #[test]
+    fn invalid_mean_shift_settings_fail_validation() {
+        assert!(PointEstimator::mean_shift(1.0).validate().is_ok());
+        assert!(PointEstimator::MaxWeight.validate().is_ok());
+        for (bandwidth, max_iterations, tolerance) in [
+            (0.0, 50, 1e-6),
+            (f64::NAN, 50, 1e-6),
+            (1.0, 0, 1e-6),
+            (1.0, 50, -1.0),
+        ] {
+            let estimator = PointEstimator::MeanShift {
+                bandwidth,
+                max_iterations,
+                tolerance,
+            };
+            assert!(estimator.validate().is_err(), "{estimator:?}");
+        }
+    }
+
+    #[test]
+    fn empty_particle_set_returns_origin() {
+        let est = PointEstimator::mean_shift(1.0).estimate(&[], &[]);
+        assert_eq!(est, Vector3::zeros());
+    }
+}
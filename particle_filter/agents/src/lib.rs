@@ -1,6 +1,17 @@
 pub mod anchor;
+pub mod clock;
+pub mod cooperative;
 pub mod measurements;
 pub use measurements::Measurements;
 pub mod dynamics_model;
+pub mod estimator;
+pub mod likelihood;
 pub mod particle_filter;
+pub mod resampling;
+pub mod rng;
+pub mod stats;
 pub mod swarm_element;
+pub mod tdoa;
+pub mod terrain;
+pub mod twr;
+pub mod uncertainty;
@@ -0,0 +1,288 @@
+use rand::Rng;
+use rand_distr::{Distribution, Exp, Normal, StudentT};
+use serde::{Deserialize, Serialize};
+
+use crate::stats::{ln_erfc, ln_gamma};
+
+const LN_SQRT_2PI: f64 = 0.918_938_533_204_672_8;
+
+// Range error models. Each one is both a likelihood for the filter and a generator
+// for simulated range errors, so the simulation can produce matching corrupted ranges.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LikelihoodModel {
+    #[default]
+    Gaussian,
+    // Heavy tailed, `nu` degrees of freedom scaled by sigma.
+    StudentT {
+        nu: f64,
+    },
+    // Gaussian with probability 1 - outlier_prob, otherwise uniform on [-outlier_range, outlier_range].
+    OutlierMixture {
+        outlier_prob: f64,
+        outlier_range: f64,
+    },
+    // Gaussian plus, with probability nlos_prob, an exponential positive excess delay
+    // with mean `mean_excess` (metres).
+    Nlos {
+        nlos_prob: f64,
+        mean_excess: f64,
+    },
+}
+
+impl LikelihoodModel {
+    // The parameters `sample_error` would otherwise panic on, or that make the
+    // likelihood meaningless.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let positive = |x: f64| x.is_finite() && x > 0.0;
+        let probability = |p: f64| (0.0..=1.0).contains(&p);
+        match *self {
+            LikelihoodModel::Gaussian => Ok(()),
+            LikelihoodModel::StudentT { nu } if !positive(nu) => {
+                Err("LikelihoodModel: nu must be > 0")
+            }
+            LikelihoodModel::OutlierMixture { outlier_prob, .. } if !probability(outlier_prob) => {
+                Err("LikelihoodModel: outlier_prob must be in [0, 1]")
+            }
+            LikelihoodModel::OutlierMixture { outlier_range, .. } if !positive(outlier_range) => {
+                Err("LikelihoodModel: outlier_range must be > 0")
+            }
+            LikelihoodModel::Nlos { nlos_prob, .. } if !probability(nlos_prob) => {
+                Err("LikelihoodModel: nlos_prob must be in [0, 1]")
+            }
+            LikelihoodModel::Nlos { mean_excess, .. } if !positive(mean_excess) => {
+                Err("LikelihoodModel: mean_excess must be > 0")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Log density of the residual `measured - predicted` for a nominal std `sigma`.
+    pub fn log_likelihood(&self, residual: f64, sigma: f64) -> f64 {
+        match *self {
+            LikelihoodModel::Gaussian => gaussian_ln_pdf(residual, sigma),
+            LikelihoodModel::StudentT { nu } => {
+                let z = residual / sigma;
+                ln_gamma(0.5 * (nu + 1.0))
+                    - ln_gamma(0.5 * nu)
+                    - 0.5 * (nu * std::f64::consts::PI).ln()
+                    - sigma.ln()
+                    - 0.5 * (nu + 1.0) * (z * z / nu).ln_1p()
+            }
+            LikelihoodModel::OutlierMixture {
+                outlier_prob,
+                outlier_range,
+            } => {
+                let inlier = (1.0 - outlier_prob).ln() + gaussian_ln_pdf(residual, sigma);
+                if residual.abs() > outlier_range {
+                    return inlier;
+                }
+                let outlier = outlier_prob.ln() - (2.0 * outlier_range).ln();
+                log_sum_exp(inlier, outlier)
+            }
+            LikelihoodModel::Nlos {
+                nlos_prob,
+                mean_excess,
+            } => {
+                let los = (1.0 - nlos_prob).ln() + gaussian_ln_pdf(residual, sigma);
+                let nlos =
+                    nlos_prob.ln() + exp_modified_gaussian_ln_pdf(residual, sigma, mean_excess);
+                log_sum_exp(los, nlos)
+            }
+        }
+    }
+
+    pub fn sample_error<R: Rng + ?Sized>(&self, sigma: f64, rng: &mut R) -> f64 {
+        let gaussian = Normal::new(0.0, sigma).expect("LikelihoodModel: invalid sigma");
+        match *self {
+            LikelihoodModel::Gaussian => gaussian.sample(rng),
+            LikelihoodModel::StudentT { nu } => {
+                let t = StudentT::new(nu).expect("LikelihoodModel: invalid nu");
+                sigma * t.sample(rng)
+            }
+            LikelihoodModel::OutlierMixture {
+                outlier_prob,
+                outlier_range,
+            } => {
+                if rng.random::<f64>() < outlier_prob {
+                    rng.random_range(-outlier_range..=outlier_range)
+                } else {
+                    gaussian.sample(rng)
+                }
+            }
+            LikelihoodModel::Nlos {
+                nlos_prob,
+                mean_excess,
+            } => {
+                let mut e = gaussian.sample(rng);
+                if rng.random::<f64>() < nlos_prob {
+                    let excess =
+                        Exp::new(1.0 / mean_excess).expect("LikelihoodModel: invalid mean_excess");
+                    e += excess.sample(rng);
+                }
+                e
+            }
+        }
+    }
+}
+
+fn gaussian_ln_pdf(x: f64, sigma: f64) -> f64 {
+    let z = x / sigma;
+    -0.5 * z * z - sigma.ln() - LN_SQRT_2PI
+}
+
+// Density of N(0, sigma^2) + Exp(1 / mean), evaluated in log space.
+fn exp_modified_gaussian_ln_pdf(x: f64, sigma: f64, mean: f64) -> f64 {
+    let lambda = 1.0 / mean;
+    let s2 = sigma * sigma;
+    (0.5 * lambda).ln()
+        + 0.5 * lambda * (lambda * s2 - 2.0 * x)
+        + ln_erfc((lambda * s2 - x) / (std::f64::consts::SQRT_2 * sigma))
+}
+
+fn log_sum_exp(a: f64, b: f64) -> f64 {
+    let m = a.max(b);
+    if m == f64::NEG_INFINITY {
+        return m;
+    }
+    m + ((a - m).exp() + (b - m).exp()).ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    const MODELS: [LikelihoodModel; 4] = [
+        LikelihoodModel::Gaussian,
+        LikelihoodModel::StudentT { nu: 3.0 },
+        LikelihoodModel::OutlierMixture {
+            outlier_prob: 0.1,
+            outlier_range: 20.0,
+        },
+        LikelihoodModel::Nlos {
+            nlos_prob: 0.3,
+            mean_excess: 2.0,
+        },
+    ];
+
+    #[test]
+    fn densities_integrate_to_one() {
+        let sigma = 0.5;
+        let (lo, hi, steps) = (-200.0, 200.0, 400_000);
+        let dx = (hi - lo) / steps as f64;
+
+        for model in MODELS {
+            let mass: f64 = (0..steps)
+                .map(|i| lo + (i as f64 + 0.5) * dx)
+                .map(|x| model.log_likelihood(x, sigma).exp() * dx)
+                .sum();
+            // Student-t with nu = 3 keeps a little mass beyond +-200 sigma
+            assert!((mass - 1.0).abs() <= 1e-3, "{model:?}: mass {mass}");
+        }
+    }
+
+    #[test]
+    fn gaussian_matches_closed_form() {
+        let sigma: f64 = 0.8;
+        let e: f64 = 1.3;
+        let expected =
+            -0.5 * (e / sigma).powi(2) - (sigma * (2.0 * std::f64::consts::PI).sqrt()).ln();
+        let got = LikelihoodModel::Gaussian.log_likelihood(e, sigma);
+        assert!((got - expected).abs() <= 1e-12);
+    }
+
+    #[test]
+    fn robust_models_penalize_outliers_less_than_gaussian() {
+        let sigma = 0.5;
+        let outlier = 8.0;
+        let gaussian = LikelihoodModel::Gaussian.log_likelihood(outlier, sigma);
+
+        for model in &MODELS[1..] {
+            let robust = model.log_likelihood(outlier, sigma);
+            assert!(
+                robust > gaussian + 50.0,
+                "{model:?}: {robust} vs {gaussian}"
+            );
+        }
+    }
+
+    #[test]
+    fn nlos_model_is_skewed_towards_positive_excess() {
+        let model = LikelihoodModel::Nlos {
+            nlos_prob: 0.3,
+            mean_excess: 2.0,
+        };
+        let sigma = 0.5;
+        assert!(model.log_likelihood(3.0, sigma) > model.log_likelihood(-3.0, sigma));
+    }
+
+    #[test]
+    fn sampled_errors_match_model_moments() {
+        let sigma = 0.5;
+        let n = 200_000;
+        let mut rng = StdRng::seed_from_u64(17);
+
+        let nlos = LikelihoodModel::Nlos {
+            nlos_prob: 0.3,
+            mean_excess: 2.0,
+        };
+        let mean: f64 = (0..n)
+            .map(|_| nlos.sample_error(sigma, &mut rng))
+            .sum::<f64>()
+            / n as f64;
+        assert!((mean - 0.3 * 2.0).abs() <= 0.02, "mean {mean}");
+
+        let mixture = LikelihoodModel::OutlierMixture {
+            outlier_prob: 0.1,
+            outlier_range: 20.0,
+        };
+        let far = (0..n)
+            .map(|_| mixture.sample_error(sigma, &mut rng))
+            .filter(|e| e.abs() > 5.0 * sigma)
+            .count() as f64
+            / n as f64;
+        // 10% outliers, of which (20 - 2.5) / 20 land beyond 5 sigma
+        assert!((far - 0.1 * 17.5 / 20.0).abs() <= 0.005, "far {far}");
+
+        for model in [
+            LikelihoodModel::Gaussian,
+            LikelihoodModel::StudentT { nu: 5.0 },
+        ] {
+            let mean: f64 = (0..n)
+                .map(|_| model.sample_error(sigma, &mut rng))
+                .sum::<f64>()
+                / n as f64;
+            assert!(mean.abs() <= 0.01, "{model:?}: mean {mean}");
+        }
+    }
+
+    #[test]
+    fn invalid_parameters_fail_validation() {
+        for model in MODELS {
+            assert_eq!(model.validate(), Ok(()), "{model:?}");
+        }
+        for model in [
+            LikelihoodModel::StudentT { nu: -1.0 },
+            LikelihoodModel::StudentT { nu: f64::NAN },
+            LikelihoodModel::OutlierMixture {
+                outlier_prob: 1.5,
+                outlier_range: 20.0,
+            },
+            LikelihoodModel::OutlierMixture {
+                outlier_prob: 0.1,
+                outlier_range: 0.0,
+            },
+            LikelihoodModel::Nlos {
+                nlos_prob: -0.1,
+                mean_excess: 2.0,
+            },
+            LikelihoodModel::Nlos {
+                nlos_prob: 0.3,
+                mean_excess: 0.0,
+            },
+        ] {
+            assert!(model.validate().is_err(), "{model:?}");
+        }
+    }
+}
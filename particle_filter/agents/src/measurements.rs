@@ -1,6 +1,13 @@
 use crate::dynamics_model::DynamicsModel;
 use crate::swarm_element::SwarmElement;
 
+use rand::Rng;
+
 pub trait Measurements<M: DynamicsModel> {
-    fn ranging(&self, swarm_element: &SwarmElement<M>, std_raning: f64) -> f64;
+    fn ranging<R: Rng + ?Sized>(
+        &self,
+        swarm_element: &SwarmElement<M>,
+        std_raning: f64,
+        rng: &mut R,
+    ) -> f64;
 }
@@ -1,17 +1,46 @@
 use std::usize;
 
-use nalgebra::Vector3;
+use nalgebra::{Matrix3, Vector3};
+use rand::distr::uniform::Error as UniformError;
+use rand::distr::weighted::{Error as WeightError, WeightedIndex};
 use rand::distr::Uniform;
-use rand::Rng;
-use rand_distr::{Distribution, StandardNormal};
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore};
+use rand_distr::{Distribution, Normal, NormalError, StandardNormal};
 use rayon::prelude::*;
 
+use crate::clock::{ClockModel, SPEED_OF_LIGHT};
+use crate::cooperative::NeighbourBelief;
 use crate::dynamics_model::DynamicsModel;
+use crate::estimator::PointEstimator;
+use crate::likelihood::LikelihoodModel;
+use crate::resampling::{KldSampling, ResamplingScheme};
+use crate::rng::{RngStream, CHUNK_SIZE};
+use crate::tdoa::TdoaMeasurement;
+use crate::terrain::{BelowGround, TerrainConstraint};
+use crate::uncertainty::CredibleEllipsoid;
 
 pub trait Enclosure {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Vector3<f64>;
 }
 
+// Object-safe form of `Enclosure`, so enclosures of different kinds can be mixed.
+pub trait DynEnclosure {
+    fn sample_dyn(&self, rng: &mut dyn RngCore) -> Vector3<f64>;
+}
+
+impl<E: Enclosure> DynEnclosure for E {
+    fn sample_dyn(&self, rng: &mut dyn RngCore) -> Vector3<f64> {
+        self.sample(rng)
+    }
+}
+
+impl Enclosure for Box<dyn DynEnclosure> {
+    fn sample<R: Rng + ?Sized>(&self, mut rng: &mut R) -> Vector3<f64> {
+        (**self).sample_dyn(&mut rng)
+    }
+}
+
 pub struct BoundingBox {
     dist_x: Uniform<f64>,
     dist_y: Uniform<f64>,
@@ -70,42 +99,469 @@ impl Enclosure for Sphere {
     }
 }
 
+// Upright cylinder standing on `base`, filled uniformly.
+pub struct Cylinder {
+    base: Vector3<f64>,
+    dist_r2: Uniform<f64>,
+    dist_azimuth: Uniform<f64>,
+    dist_z: Uniform<f64>,
+}
+
+impl Cylinder {
+    // A radius or height that is not > 0 leaves nothing to fill.
+    pub fn new(radius: f64, base: Vector3<f64>, height: f64) -> Result<Self, UniformError> {
+        if !(radius > 0.0 && height > 0.0) {
+            return Err(UniformError::EmptyRange);
+        }
+        Ok(Self {
+            base,
+            dist_r2: Uniform::new(0.0, radius * radius)?,
+            dist_azimuth: Uniform::new(0.0, 2.0 * std::f64::consts::PI)?,
+            dist_z: Uniform::new(base.z, base.z + height)?,
+        })
+    }
+}
+
+impl Enclosure for Cylinder {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Vector3<f64> {
+        let r = rng.sample(self.dist_r2).sqrt();
+        let azimuthal = rng.sample(self.dist_azimuth);
+        Vector3::new(
+            self.base.x + r * azimuthal.cos(),
+            self.base.y + r * azimuthal.sin(),
+            rng.sample(self.dist_z),
+        )
+    }
+}
+
+// Smallest share of its bounding box a polygon footprint may cover, so drawing a
+// point takes at most some 10^4 attempts on average.
+pub const MIN_POLYGON_FILL: f64 = 1e-4;
+
+// A horizontal polygon footprint, e.g. the outline of an operating area, between
+// two heights. Drawn by rejection from its bounding box.
+pub struct ExtrudedPolygon {
+    vertices: Vec<[f64; 2]>,
+    dist_x: Uniform<f64>,
+    dist_y: Uniform<f64>,
+    dist_z: Uniform<f64>,
+}
+
+impl ExtrudedPolygon {
+    // The footprint must not cross itself; closing it is optional. It must also fill
+    // `MIN_POLYGON_FILL` of its bounding box, or the rejection sampling may never end.
+    pub fn new(vertices: Vec<[f64; 2]>, min_z: f64, max_z: f64) -> Result<Self, UniformError> {
+        if vertices.len() < 3 {
+            return Err(UniformError::EmptyRange);
+        }
+        let (mut min, mut max) = ([f64::INFINITY; 2], [f64::NEG_INFINITY; 2]);
+        for v in &vertices {
+            for i in 0..2 {
+                min[i] = min[i].min(v[i]);
+                max[i] = max[i].max(v[i]);
+            }
+        }
+        let box_area = (max[0] - min[0]) * (max[1] - min[1]);
+        if !(polygon_area(&vertices) >= MIN_POLYGON_FILL * box_area && box_area > 0.0) {
+            return Err(UniformError::EmptyRange);
+        }
+        Ok(Self {
+            dist_x: Uniform::new(min[0], max[0])?,
+            dist_y: Uniform::new(min[1], max[1])?,
+            dist_z: Uniform::new(min_z, max_z)?,
+            vertices,
+        })
+    }
+
+    // Even-odd rule.
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        let n = self.vertices.len();
+        let mut inside = false;
+        for i in 0..n {
+            let [xi, yi] = self.vertices[i];
+            let [xj, yj] = self.vertices[(i + n - 1) % n];
+            if (yi > y) != (yj > y) && x < xi + (y - yi) * (xj - xi) / (yj - yi) {
+                inside = !inside;
+            }
+        }
+        inside
+    }
+}
+
+fn polygon_area(vertices: &[[f64; 2]]) -> f64 {
+    let n = vertices.len();
+    let twice: f64 = (0..n)
+        .map(|i| {
+            let ([x0, y0], [x1, y1]) = (vertices[i], vertices[(i + 1) % n]);
+            x0 * y1 - x1 * y0
+        })
+        .sum();
+    0.5 * twice.abs()
+}
+
+impl Enclosure for ExtrudedPolygon {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Vector3<f64> {
+        loop {
+            let (x, y) = (rng.sample(self.dist_x), rng.sample(self.dist_y));
+            if self.contains(x, y) {
+                return Vector3::new(x, y, rng.sample(self.dist_z));
+            }
+        }
+    }
+}
+
+// Independent normal errors per axis around a guessed position.
+pub struct Gaussian {
+    mean: Vector3<f64>,
+    axes: [Normal<f64>; 3],
+}
+
+impl Gaussian {
+    pub fn new(mean: Vector3<f64>, std: Vector3<f64>) -> Result<Self, NormalError> {
+        if !std.iter().all(|&s| s >= 0.0) {
+            return Err(NormalError::BadVariance);
+        }
+        Ok(Self {
+            mean,
+            axes: [
+                Normal::new(0.0, std.x)?,
+                Normal::new(0.0, std.y)?,
+                Normal::new(0.0, std.z)?,
+            ],
+        })
+    }
+}
+
+impl Enclosure for Gaussian {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Vector3<f64> {
+        self.mean + Vector3::from_fn(|i, _| self.axes[i].sample(rng))
+    }
+}
+
+// Between two spheres around `center`, filled uniformly.
+pub struct SphericalShell {
+    center: Vector3<f64>,
+    dist_r3: Uniform<f64>,
+    dist_cos_polar: Uniform<f64>,
+    dist_azimuth: Uniform<f64>,
+}
+
+impl SphericalShell {
+    pub fn new(center: Vector3<f64>, inner: f64, outer: f64) -> Result<Self, UniformError> {
+        if inner.is_nan() || inner < 0.0 {
+            return Err(UniformError::EmptyRange);
+        }
+        Ok(Self {
+            center,
+            dist_r3: Uniform::new(inner.powi(3), outer.powi(3))?,
+            dist_cos_polar: Uniform::new_inclusive(-1.0, 1.0)?,
+            dist_azimuth: Uniform::new(0.0, 2.0 * std::f64::consts::PI)?,
+        })
+    }
+
+    // Where a tag `range` away from an anchor at `center` can be, out to `sigmas`
+    // standard deviations of the range either side.
+    pub fn around_range(
+        center: Vector3<f64>,
+        range: f64,
+        std: f64,
+        sigmas: f64,
+    ) -> Result<Self, UniformError> {
+        let half_width = sigmas * std;
+        Self::new(center, (range - half_width).max(0.0), range + half_width)
+    }
+}
+
+impl Enclosure for SphericalShell {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Vector3<f64> {
+        let r = rng.sample(self.dist_r3).cbrt();
+        let cos_polar = rng.sample(self.dist_cos_polar);
+        let sin_polar = (1.0 - cos_polar * cos_polar).sqrt();
+        let azimuthal = rng.sample(self.dist_azimuth);
+        self.center
+            + r * Vector3::new(
+                sin_polar * azimuthal.cos(),
+                sin_polar * azimuthal.sin(),
+                cos_polar,
+            )
+    }
+}
+
+// Each draw comes from one of the parts, picked in proportion to its weight.
+pub struct Union {
+    parts: Vec<Box<dyn DynEnclosure>>,
+    choose: WeightedIndex<f64>,
+}
+
+impl Union {
+    pub fn new(parts: Vec<(f64, Box<dyn DynEnclosure>)>) -> Result<Self, WeightError> {
+        let choose = WeightedIndex::new(parts.iter().map(|(weight, _)| *weight))?;
+        Ok(Self {
+            parts: parts.into_iter().map(|(_, part)| part).collect(),
+            choose,
+        })
+    }
+}
+
+impl Enclosure for Union {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Vector3<f64> {
+        let part = &self.parts[self.choose.sample(rng)];
+        part.sample(rng)
+    }
+}
+
 #[derive(Debug, Clone, Copy, Default, PartialEq)]
 pub struct Particle {
     pub position: Vector3<f64>,
+    pub velocity: Vector3<f64>,
+    // Tag clock offset (s) and drift (s/s), only used with a clock state.
+    pub clock_bias: f64,
+    pub clock_drift: f64,
     pub log_weight: f64,
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FilterState {
+    // Particles carry position only and are pushed by a measured velocity.
+    #[default]
+    Position,
+    // Particles carry their own velocity, propagated by the dynamics model.
+    PositionVelocity,
+}
+
+impl FilterState {
+    pub fn dimension(&self) -> usize {
+        match self {
+            FilterState::Position => 3,
+            FilterState::PositionVelocity => 6,
+        }
+    }
+}
+
+// Roughening constant applied after every resample.
+pub const DEFAULT_ROUGHENING: f64 = 0.5;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct ParticleFilter {
     pub particles: Vec<Particle>,
     ess_tau: f64,
+    resampling_scheme: ResamplingScheme,
+    kld_sampling: Option<KldSampling>,
+    state: FilterState,
+    clock_state: bool,
+    roughening: f64,
+    rng: RngStream,
+    terrain: Option<TerrainConstraint>,
+}
+
+impl Default for ParticleFilter {
+    fn default() -> Self {
+        Self {
+            particles: Vec::new(),
+            ess_tau: 0.0,
+            resampling_scheme: ResamplingScheme::default(),
+            kld_sampling: None,
+            state: FilterState::default(),
+            clock_state: false,
+            roughening: DEFAULT_ROUGHENING,
+            rng: RngStream::default(),
+            terrain: None,
+        }
+    }
 }
 
 impl Particle {
     pub fn new(position: Vector3<f64>, log_weight: f64) -> Self {
         Self {
             position,
+            velocity: Vector3::zeros(),
+            clock_bias: 0.0,
+            clock_drift: 0.0,
             log_weight,
         }
     }
+
+    pub fn with_velocity(mut self, velocity: Vector3<f64>) -> Self {
+        self.velocity = velocity;
+        self
+    }
 }
 
 impl ParticleFilter {
     pub fn new<E: Enclosure>(enclosure: &E, num_particles: usize, ess_tau: f64) -> Self {
-        let mut rng = rand::rng();
+        Self::new_with_rng(enclosure, num_particles, ess_tau, &mut rand::rng())
+    }
+
+    // Draw the initial particles from `rng`, and seed the filter's own stream from it,
+    // so a seeded generator makes the whole filter reproducible.
+    pub fn new_with_rng<E: Enclosure, R: Rng + ?Sized>(
+        enclosure: &E,
+        num_particles: usize,
+        ess_tau: f64,
+        rng: &mut R,
+    ) -> Self {
         let ln_uniform = -(num_particles as f64).ln();
         let particles: Vec<Particle> = (0..num_particles)
             .map(|_| {
-                let pos = enclosure.sample(&mut rng);
+                let pos = enclosure.sample(rng);
                 Particle::new(pos, ln_uniform)
             })
             .collect();
 
-        ParticleFilter { particles, ess_tau }
+        ParticleFilter {
+            particles,
+            ess_tau,
+            resampling_scheme: ResamplingScheme::default(),
+            kld_sampling: None,
+            state: FilterState::default(),
+            clock_state: false,
+            roughening: DEFAULT_ROUGHENING,
+            rng: RngStream::new(rng.random()),
+            terrain: None,
+        }
+    }
+
+    // Replace the stream used for prediction, roughening and resampling.
+    pub fn set_rng_stream(&mut self, stream: RngStream) {
+        self.rng = stream;
+    }
+
+    pub fn with_resampling_scheme(mut self, resampling_scheme: ResamplingScheme) -> Self {
+        self.resampling_scheme = resampling_scheme;
+        self
+    }
+
+    pub fn resampling_scheme(&self) -> ResamplingScheme {
+        self.resampling_scheme
+    }
+
+    // Constant c of the post-resampling jitter bandwidth h = c * N^(-1/d); 0 disables it.
+    pub fn with_roughening(mut self, roughening: f64) -> Self {
+        assert!(roughening >= 0.0, "ParticleFilter: roughening must be >= 0");
+        self.roughening = roughening;
+        self
+    }
+
+    pub fn roughening(&self) -> f64 {
+        self.roughening
+    }
+
+    // Let the particle count adapt to the spread of the belief on every resample.
+    pub fn with_kld_sampling(mut self, kld_sampling: KldSampling) -> Self {
+        self.kld_sampling = Some(kld_sampling);
+        self
+    }
+
+    pub fn kld_sampling(&self) -> Option<&KldSampling> {
+        self.kld_sampling.as_ref()
+    }
+
+    // Switch to a 6-D state, drawing each particle's initial velocity from `velocity_prior`.
+    pub fn with_velocity_state<E: Enclosure>(mut self, velocity_prior: &E) -> Self {
+        let mut rng = self.rng.next_rng();
+        for p in &mut self.particles {
+            p.velocity = velocity_prior.sample(&mut rng);
+        }
+        self.state = FilterState::PositionVelocity;
+        self
+    }
+
+    pub fn state(&self) -> FilterState {
+        self.state
+    }
+
+    // Estimate the tag clock jointly with position. Initial offsets and drifts are
+    // drawn uniformly from [-max_bias, max_bias] and [-max_drift, max_drift].
+    pub fn with_clock_state(mut self, max_bias: f64, max_drift: f64) -> Self {
+        assert!(
+            max_bias >= 0.0 && max_drift >= 0.0,
+            "ParticleFilter: clock prior bounds must be >= 0"
+        );
+        let mut rng = self.rng.next_rng();
+        for p in &mut self.particles {
+            p.clock_bias = max_bias * rng.random_range(-1.0..=1.0);
+            p.clock_drift = max_drift * rng.random_range(-1.0..=1.0);
+        }
+        self.clock_state = true;
+        self
+    }
+
+    pub fn has_clock_state(&self) -> bool {
+        self.clock_state
+    }
+
+    // Keep the particles above the ground, now and after every prediction.
+    pub fn with_terrain(mut self, terrain: TerrainConstraint) -> Self {
+        self.terrain = Some(terrain);
+        self.apply_terrain();
+        self
+    }
+
+    pub fn terrain(&self) -> Option<&TerrainConstraint> {
+        self.terrain.as_ref()
+    }
+
+    // The prior on the height above ground is applied as a weight at every step,
+    // like a measurement of it.
+    fn apply_terrain(&mut self) {
+        if self.terrain.is_none() {
+            return;
+        }
+        self.keep_above_ground();
+        let terrain = self.terrain.as_ref().unwrap();
+        if let Some(prior) = terrain.agl_prior {
+            let ground = &terrain.ground;
+            self.particles.par_iter_mut().for_each(|p| {
+                if let Some(h) = ground.height_at(p.position.x, p.position.y) {
+                    let residual = p.position.z - h - prior.mean;
+                    p.log_weight += LikelihoodModel::Gaussian.log_likelihood(residual, prior.std);
+                }
+            });
+        }
+        self.normalize_weights();
     }
 
-    fn linear_weights(&self) -> Vec<f64> {
+    fn keep_above_ground(&mut self) {
+        let Some(terrain) = &self.terrain else {
+            return;
+        };
+        let ground = &terrain.ground;
+        let height = |p: &Particle| ground.height_at(p.position.x, p.position.y);
+        let below = |p: &Particle| height(p).is_some_and(|h| p.position.z < h);
+
+        match terrain.below_ground {
+            BelowGround::Reflect => {
+                let velocity_state = self.state == FilterState::PositionVelocity;
+                self.particles.par_iter_mut().for_each(|p| {
+                    if let Some(h) = height(p).filter(|h| p.position.z < *h) {
+                        p.position.z = 2.0 * h - p.position.z;
+                        if velocity_state {
+                            p.velocity.z = -p.velocity.z;
+                        }
+                    }
+                });
+            }
+            // with every weighted particle underground there is nothing better to keep;
+            // particles zeroed before and not yet resampled do not count
+            BelowGround::ZeroWeight => {
+                let weighted = |p: &&Particle| p.log_weight > f64::NEG_INFINITY;
+                if !self.particles.par_iter().filter(weighted).all(below) {
+                    self.particles.par_iter_mut().for_each(|p| {
+                        if below(p) {
+                            p.log_weight = f64::NEG_INFINITY;
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    // Position, optional velocity and optional clock offset and drift.
+    pub fn dimension(&self) -> usize {
+        self.state.dimension() + if self.clock_state { 2 } else { 0 }
+    }
+
+    pub(crate) fn linear_weights(&self) -> Vec<f64> {
         let m = self
             .particles
             .iter()
@@ -125,7 +581,10 @@ impl ParticleFilter {
         w
     }
 
-    pub fn normalize_weights(&mut self) {
+    // Returns the log of the total weight before normalising. Right after a likelihood
+    // update of normalised weights, that is the log marginal likelihood of the
+    // measurement, up to the constants the likelihood leaves out.
+    pub fn normalize_weights(&mut self) -> f64 {
         let m = self
             .particles
             .iter()
@@ -140,6 +599,7 @@ impl ParticleFilter {
         for p in &mut self.particles {
             p.log_weight -= lse;
         }
+        lse
     }
 
     pub fn posterior_mean(&self) -> Vector3<f64> {
@@ -151,6 +611,42 @@ impl ParticleFilter {
         mu
     }
 
+    pub fn estimate(&self, estimator: &PointEstimator) -> Vector3<f64> {
+        estimator.estimate(&self.particles, &self.linear_weights())
+    }
+
+    pub fn posterior_covariance(&self) -> Matrix3<f64> {
+        let w = self.linear_weights();
+        let mu = self.posterior_mean();
+        let mut cov = Matrix3::zeros();
+        for (p, wi) in self.particles.iter().zip(w.iter()) {
+            let d = p.position - mu;
+            cov += d * d.transpose() * *wi;
+        }
+        cov
+    }
+
+    pub fn posterior_std(&self) -> Vector3<f64> {
+        self.posterior_covariance().diagonal().map(f64::sqrt)
+    }
+
+    pub fn credible_ellipsoid(&self, level: f64) -> CredibleEllipsoid {
+        CredibleEllipsoid::from_covariance(
+            self.posterior_mean(),
+            &self.posterior_covariance(),
+            level,
+        )
+    }
+
+    pub fn posterior_mean_velocity(&self) -> Vector3<f64> {
+        let w = self.linear_weights();
+        let mut mu = Vector3::zeros();
+        for (p, wi) in self.particles.iter().zip(w.iter()) {
+            mu += p.velocity * *wi;
+        }
+        mu
+    }
+
     pub fn predict_with_measured_velocity<M>(
         &mut self,
         dt: f64,
@@ -159,60 +655,184 @@ impl ParticleFilter {
     ) where
         M: DynamicsModel + Sync,
     {
-        let mut rng = rand::rng();
-        self.particles.iter_mut().for_each(|p| {
-            p.position = dynamics_model.predict_next_state(dt, p.position, velocity, &mut rng);
+        self.par_for_each_with_rng(|p, rng| {
+            p.position = dynamics_model.predict_next_state(dt, p.position, velocity, rng);
+        });
+        self.apply_terrain();
+    }
+
+    pub fn predict_clock(&mut self, dt: f64, clock: &ClockModel) {
+        self.par_for_each_with_rng(|p, rng| {
+            (p.clock_bias, p.clock_drift) = clock.propagate(p.clock_bias, p.clock_drift, dt, rng);
         });
     }
 
+    pub fn posterior_mean_clock_bias(&self) -> f64 {
+        let w = self.linear_weights();
+        self.particles
+            .iter()
+            .zip(w.iter())
+            .map(|(p, wi)| p.clock_bias * wi)
+            .sum()
+    }
+
+    pub fn predict_with_particle_velocity<M>(&mut self, dt: f64, dynamics_model: &M)
+    where
+        M: DynamicsModel + Sync,
+    {
+        self.par_for_each_with_rng(|p, rng| {
+            (p.position, p.velocity) =
+                dynamics_model.predict_next_state_with_velocity(dt, p.position, p.velocity, rng);
+        });
+        self.apply_terrain();
+    }
+
+    // Run `f` over fixed-size chunks in parallel, each chunk with its own generator
+    // derived from the filter's stream, so the result does not depend on scheduling.
+    fn par_for_each_with_rng<F>(&mut self, f: F)
+    where
+        F: Fn(&mut Particle, &mut StdRng) + Sync,
+    {
+        let draw = self.rng.next_substream();
+        self.particles
+            .par_chunks_mut(CHUNK_SIZE)
+            .enumerate()
+            .for_each(|(i, chunk)| {
+                let mut rng = draw.substream(i as u64).next_rng();
+                chunk.iter_mut().for_each(|p| f(p, &mut rng));
+            });
+    }
+
     pub fn update_weights(&mut self, ranging: f64, pos: Vector3<f64>, sigma: f64) {
+        self.update_weights_with(ranging, pos, sigma, &LikelihoodModel::Gaussian);
+    }
+
+    pub fn update_weights_with(
+        &mut self,
+        ranging: f64,
+        pos: Vector3<f64>,
+        sigma: f64,
+        likelihood: &LikelihoodModel,
+    ) {
         assert!(
             sigma > 0.0,
             "sigma must be > 0 - Perfect measurements not implmented yet"
         );
-        let inv_var = 1.0 / sigma.powi(2);
         self.particles.par_iter_mut().for_each(|p| {
             let pred = (p.position - pos).norm();
             let e = ranging - pred;
-            p.log_weight += -0.5 * e * e * inv_var;
+            p.log_weight += likelihood.log_likelihood(e, sigma);
+        });
+    }
+
+    // Range to a neighbour whose own position is only known through `neighbour`.
+    pub fn update_weights_cooperative(
+        &mut self,
+        ranging: f64,
+        neighbour: &NeighbourBelief,
+        sigma: f64,
+        likelihood: &LikelihoodModel,
+    ) {
+        assert!(
+            sigma > 0.0,
+            "sigma must be > 0 - Perfect measurements not implmented yet"
+        );
+        self.particles.par_iter_mut().for_each(|p| {
+            p.log_weight += neighbour.log_likelihood(ranging, &p.position, sigma, likelihood);
+        });
+    }
+
+    // Raw time of arrival (s) at an anchor; each particle explains it with its own
+    // range plus clock bias. Without a clock state the bias is zero.
+    pub fn update_weights_toa(
+        &mut self,
+        time_of_arrival: f64,
+        pos: Vector3<f64>,
+        sigma: f64,
+        likelihood: &LikelihoodModel,
+    ) {
+        assert!(
+            sigma > 0.0,
+            "sigma must be > 0 - Perfect measurements not implmented yet"
+        );
+        let measured = time_of_arrival * SPEED_OF_LIGHT;
+        self.particles.par_iter_mut().for_each(|p| {
+            let pred = (p.position - pos).norm() + p.clock_bias * SPEED_OF_LIGHT;
+            p.log_weight += likelihood.log_likelihood(measured - pred, sigma);
+        });
+    }
+
+    pub fn update_weights_tdoa(&mut self, measurement: &TdoaMeasurement) {
+        self.particles.par_iter_mut().for_each(|p| {
+            p.log_weight += measurement.log_likelihood(&p.position);
         });
     }
 
     // Slightly jitter particle positions to break exact clones after resampling.
     pub fn roughen_positions(&mut self, c: f64) {
+        let Some(std) = self.roughening_std(c, |p| p.position) else {
+            return;
+        };
+
+        self.par_for_each_with_rng(|p, rng| p.position += Self::jitter(&std, rng));
+    }
+
+    // Same as `roughen_positions`, for the velocity part of a 6-D state.
+    pub fn roughen_velocities(&mut self, c: f64) {
+        let Some(std) = self.roughening_std(c, |p| p.velocity) else {
+            return;
+        };
+
+        self.par_for_each_with_rng(|p, rng| p.velocity += Self::jitter(&std, rng));
+    }
+
+    // Jitter the clock offset and drift, packed into the first two axes.
+    pub fn roughen_clock(&mut self, c: f64) {
+        let Some(std) = self.roughening_std(c, |p| Vector3::new(p.clock_bias, p.clock_drift, 0.0))
+        else {
+            return;
+        };
+
+        self.par_for_each_with_rng(|p, rng| {
+            let j = Self::jitter(&std, rng);
+            p.clock_bias += j.x;
+            p.clock_drift += j.y;
+        });
+    }
+
+    fn roughening_std(
+        &self,
+        c: f64,
+        component: impl Fn(&Particle) -> Vector3<f64>,
+    ) -> Option<Vector3<f64>> {
         let n = self.particles.len();
         if n == 0 {
-            return;
+            return None;
         }
 
         // compute span in one pass
-        let mut min = self.particles[0].position;
-        let mut max = self.particles[0].position;
+        let mut min = component(&self.particles[0]);
+        let mut max = min;
         for p in &self.particles {
-            min = min.inf(&p.position);
-            max = max.sup(&p.position);
+            min = min.inf(&component(p));
+            max = max.sup(&component(p));
         }
         let span: Vector3<f64> = max - min;
 
-        // bandwidth h = c * N^{-1/d}, d=3
-        let h = c * (n as f64).powf(-1.0 / 3.0);
+        // bandwidth h = c * N^{-1/d}, d = state dimension
+        let d = self.dimension() as f64;
+        let h = c * (n as f64).powf(-1.0 / d);
 
         // per-axis std, with tiny floor to avoid zero
         let eps = 1e-12;
-        let sx = (h * span.x).max(eps);
-        let sy = (h * span.y).max(eps);
-        let sz = (h * span.z).max(eps);
-
-        let mut rng = rand::rng();
-        for p in &mut self.particles {
-            let dx = sx * Distribution::<f64>::sample(&StandardNormal, &mut rng);
-            let dy = sy * Distribution::<f64>::sample(&StandardNormal, &mut rng);
-            let dz = sz * Distribution::<f64>::sample(&StandardNormal, &mut rng);
+        Some((h * span).map(|s| s.max(eps)))
+    }
 
-            p.position.x += dx;
-            p.position.y += dy;
-            p.position.z += dz;
-        }
+    fn jitter<R: Rng + ?Sized>(std: &Vector3<f64>, rng: &mut R) -> Vector3<f64> {
+        let dx = std.x * Distribution::<f64>::sample(&StandardNormal, rng);
+        let dy = std.y * Distribution::<f64>::sample(&StandardNormal, rng);
+        let dz = std.z * Distribution::<f64>::sample(&StandardNormal, rng);
+        Vector3::new(dx, dy, dz)
     }
 
     pub fn ess(&self) -> f64 {
@@ -225,47 +845,57 @@ impl ParticleFilter {
         }
     }
 
-    pub fn resample(&mut self) {
+    // Resample when the ESS has dropped below `ess_tau * n`; returns whether it did.
+    pub fn resample(&mut self) -> bool {
         let n = self.particles.len();
         let ess = self.ess();
         let threshold = self.ess_tau * n as f64;
 
         if n == 0 {
-            return;
+            false
         } else if ess < threshold {
             let w = self.linear_weights();
 
-            let mut cum_weights = Vec::with_capacity(n);
-            let mut csum = 0.0;
-            for wi in &w {
-                csum += wi;
-                cum_weights.push(csum);
-            }
-
-            if let Some(last) = cum_weights.last_mut() {
-                *last = 1.0;
-            }
-
-            let mut rng = rand::rng();
-            let u0 = rng.random::<f64>() / (n as f64);
-
-            let mut out = Vec::with_capacity(n);
-            let mut i = 0usize;
-            for j in 0..n {
-                let u = u0 + (j as f64) / (n as f64);
-                while i < n - 1 && u > cum_weights[i] {
-                    i += 1;
+            let mut rng = self.rng.next_rng();
+            let n_out = match &self.kld_sampling {
+                Some(kld) => {
+                    let positions: Vec<Vector3<f64>> =
+                        self.particles.iter().map(|p| p.position).collect();
+                    kld.sample_size(&positions, &w, &mut rng)
                 }
-                out.push(self.particles[i].clone());
-            }
+                None => n,
+            };
+
+            let out: Vec<Particle> = self
+                .resampling_scheme
+                .indices(&w, n_out, &mut rng)
+                .into_iter()
+                .map(|i| self.particles[i])
+                .collect();
 
             self.particles = out;
-            let logw = -(n as f64).ln();
+            let logw = -(n_out as f64).ln();
             for p in &mut self.particles {
                 p.log_weight = logw
             }
 
-            self.roughen_positions(0.5);
+            if self.roughening > 0.0 {
+                self.roughen_positions(self.roughening);
+                if self.state == FilterState::PositionVelocity {
+                    self.roughen_velocities(self.roughening);
+                }
+                if self.clock_state {
+                    self.roughen_clock(self.roughening);
+                }
+                // the jitter must not push particles into the ground
+                if self.terrain.is_some() {
+                    self.keep_above_ground();
+                    self.normalize_weights();
+                }
+            }
+            true
+        } else {
+            false
         }
     }
 }
@@ -273,6 +903,8 @@ impl ParticleFilter {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::dynamics_model::WhiteNoiseAcceleration;
+    use rand::SeedableRng;
 
     #[test]
     fn test_particle_new() {
@@ -389,6 +1021,88 @@ mod tests {
         assert!((sphere.origo.z - z_empirical_mean).abs() <= tolerance);
     }
 
+    #[test]
+    fn test_cylinder_and_shell_samples_stay_inside() {
+        let mut rng = StdRng::seed_from_u64(7);
+
+        let base = Vector3::new(1.0, 2.0, 3.0);
+        let cylinder = Cylinder::new(2.0, base, 5.0).unwrap();
+        let mut r_sum = 0.0;
+        for _ in 0..10_000 {
+            let p = cylinder.sample(&mut rng);
+            let r = (p.xy() - base.xy()).norm();
+            assert!(r <= 2.0 && (3.0..=8.0).contains(&p.z));
+            r_sum += r;
+        }
+        // uniform over the disc, so the mean radius is 2/3 of the full one
+        assert!((r_sum / 10_000.0 - 4.0 / 3.0).abs() < 0.05);
+        for (radius, height) in [(-2.0, 5.0), (0.0, 5.0), (2.0, -5.0), (f64::NAN, 5.0)] {
+            assert!(matches!(
+                Cylinder::new(radius, base, height),
+                Err(UniformError::EmptyRange)
+            ));
+        }
+
+        let shell = SphericalShell::around_range(base, 10.0, 0.5, 3.0).unwrap();
+        for _ in 0..10_000 {
+            let r = (shell.sample(&mut rng) - base).norm();
+            assert!((8.5..=11.5).contains(&r));
+        }
+        assert!(SphericalShell::new(base, -1.0, 2.0).is_err());
+    }
+
+    #[test]
+    fn test_extruded_polygon_keeps_to_the_footprint() {
+        // L-shaped footprint, its bounding box has an empty corner at x, y > 1
+        let footprint = vec![
+            [0.0, 0.0],
+            [2.0, 0.0],
+            [2.0, 1.0],
+            [1.0, 1.0],
+            [1.0, 2.0],
+            [0.0, 2.0],
+        ];
+        let polygon = ExtrudedPolygon::new(footprint, -1.0, 1.0).unwrap();
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..10_000 {
+            let p = polygon.sample(&mut rng);
+            assert!(polygon.contains(p.x, p.y));
+            assert!(!(p.x > 1.0 && p.y > 1.0));
+            assert!((-1.0..=1.0).contains(&p.z));
+        }
+
+        assert!(ExtrudedPolygon::new(vec![[0.0, 0.0], [1.0, 1.0]], 0.0, 1.0).is_err());
+        assert!(ExtrudedPolygon::new(vec![[0.0, 0.0], [1.0, 1.0], [2.0, 2.0]], 0.0, 1.0).is_err());
+        // collinear up to rounding, and a sliver in a large box
+        let rounded = vec![[0.0, 0.0], [1.0, 1.0 + 1e-12], [2.0, 2.0]];
+        assert!(ExtrudedPolygon::new(rounded, 0.0, 1.0).is_err());
+        let sliver = vec![[0.0, 0.0], [1000.0, 1000.0], [1000.0, 1000.001]];
+        assert!(ExtrudedPolygon::new(sliver, 0.0, 1.0).is_err());
+        let corridor = vec![[0.0, 0.0], [1000.0, 1000.0], [1000.0, 1001.0], [0.0, 1.0]];
+        assert!(ExtrudedPolygon::new(corridor, 0.0, 1.0).is_ok());
+    }
+
+    #[test]
+    fn test_union_draws_parts_by_weight() {
+        let near: Box<dyn DynEnclosure> =
+            Box::new(Gaussian::new(Vector3::zeros(), Vector3::new(1.0, 1.0, 0.1)).unwrap());
+        let far: Box<dyn DynEnclosure> =
+            Box::new(Sphere::new(1.0, Vector3::new(100.0, 0.0, 0.0)).unwrap());
+        let union = Union::new(vec![(3.0, near), (1.0, far)]).unwrap();
+
+        let particle_filter = ParticleFilter::new(&union, 20_000, 0.5);
+        let far_share = particle_filter
+            .particles
+            .iter()
+            .filter(|p| p.position.x > 50.0)
+            .count() as f64
+            / 20_000.0;
+        assert!((far_share - 0.25).abs() < 0.02);
+
+        assert!(Union::new(Vec::new()).is_err());
+        assert!(Gaussian::new(Vector3::zeros(), Vector3::new(-1.0, 1.0, 1.0)).is_err());
+    }
+
     #[test]
     fn test_normalize_weitghts() {
         let x_bounds = (0.0, 1.0);
@@ -414,4 +1128,467 @@ mod tests {
         let sum: f64 = particle_filter.linear_weights().iter().sum();
         assert!(sum == 1.0);
     }
+
+    #[test]
+    fn test_resample_resets_weights_for_every_scheme() {
+        let min = Vector3::new(0.0, 0.0, 0.0);
+        let max = Vector3::new(1.0, 1.0, 1.0);
+        let bounding_box = BoundingBox::new(min, max).unwrap();
+
+        let num_particles = 200;
+        let ess_tau = 1.0;
+
+        for scheme in [
+            ResamplingScheme::Multinomial,
+            ResamplingScheme::Stratified,
+            ResamplingScheme::Systematic,
+            ResamplingScheme::Residual,
+            ResamplingScheme::ResidualSystematic,
+        ] {
+            let mut particle_filter = ParticleFilter::new(&bounding_box, num_particles, ess_tau)
+                .with_resampling_scheme(scheme);
+            assert_eq!(particle_filter.resampling_scheme(), scheme);
+
+            for (i, p) in particle_filter.particles.iter_mut().enumerate() {
+                p.log_weight = if i % 10 == 0 { 0.0 } else { -50.0 };
+            }
+            particle_filter.normalize_weights();
+            particle_filter.resample();
+
+            assert_eq!(particle_filter.particles.len(), num_particles);
+            let logw = -(num_particles as f64).ln();
+            particle_filter
+                .particles
+                .iter()
+                .for_each(|p| assert_eq!(p.log_weight, logw));
+        }
+    }
+
+    #[test]
+    fn test_kld_resample_adapts_particle_count() {
+        let min = Vector3::new(0.0, 0.0, 0.0);
+        let max = Vector3::new(100.0, 100.0, 100.0);
+        let bounding_box = BoundingBox::new(min, max).unwrap();
+
+        let num_particles = 5_000;
+        let ess_tau = 1.0;
+        let kld = KldSampling::new(100, 20_000, 1.0, 0.05, 0.01);
+
+        let mut particle_filter =
+            ParticleFilter::new(&bounding_box, num_particles, ess_tau).with_kld_sampling(kld);
+        assert_eq!(particle_filter.kld_sampling(), Some(&kld));
+
+        // collapse the belief onto a tight blob around (50, 50, 50)
+        particle_filter.update_weights(0.0, Vector3::new(50.0, 50.0, 50.0), 0.5);
+        particle_filter.normalize_weights();
+        particle_filter.resample();
+
+        let n_concentrated = particle_filter.particles.len();
+        assert!(n_concentrated < num_particles);
+        assert!(n_concentrated >= kld.min_particles);
+        let logw = -(n_concentrated as f64).ln();
+        particle_filter
+            .particles
+            .iter()
+            .for_each(|p| assert_eq!(p.log_weight, logw));
+
+        // re-acquisition: a wide belief needs more particles again
+        let mut wide =
+            ParticleFilter::new(&bounding_box, n_concentrated, ess_tau).with_kld_sampling(kld);
+        wide.particles[0].log_weight += 1.0;
+        wide.normalize_weights();
+        wide.resample();
+        assert!(wide.particles.len() > n_concentrated);
+        assert!(wide.particles.len() <= kld.max_particles);
+    }
+
+    #[test]
+    fn test_velocity_state_samples_velocity_prior() {
+        let bounding_box =
+            BoundingBox::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 1.0)).unwrap();
+        let velocity_prior =
+            BoundingBox::new(Vector3::new(-2.0, -2.0, -2.0), Vector3::new(2.0, 2.0, 2.0)).unwrap();
+
+        let particle_filter = ParticleFilter::new(&bounding_box, 1_000, 0.5);
+        assert_eq!(particle_filter.state(), FilterState::Position);
+        assert!(particle_filter
+            .particles
+            .iter()
+            .all(|p| p.velocity == Vector3::zeros()));
+
+        let particle_filter = particle_filter.with_velocity_state(&velocity_prior);
+        assert_eq!(particle_filter.state(), FilterState::PositionVelocity);
+        for p in &particle_filter.particles {
+            assert!(p.velocity.abs().max() <= 2.0);
+        }
+        assert!(particle_filter
+            .particles
+            .iter()
+            .any(|p| p.velocity != Vector3::zeros()));
+    }
+
+    #[test]
+    fn test_predict_with_particle_velocity_uses_own_velocity() {
+        let bounding_box =
+            BoundingBox::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 1.0)).unwrap();
+        let velocity_prior =
+            BoundingBox::new(Vector3::new(-2.0, -2.0, -2.0), Vector3::new(2.0, 2.0, 2.0)).unwrap();
+        let mut particle_filter =
+            ParticleFilter::new(&bounding_box, 100, 0.5).with_velocity_state(&velocity_prior);
+
+        // noiseless, constant acceleration of (0, 0, -1)
+        let dynamics_model = WhiteNoiseAcceleration::new(
+            Vector3::zeros(),
+            Vector3::zeros(),
+            Vector3::new(0.0, 0.0, -1.0),
+            Vector3::zeros(),
+        );
+
+        let before = particle_filter.particles.clone();
+        let dt = 0.5;
+        particle_filter.predict_with_particle_velocity(dt, &dynamics_model);
+
+        let a = Vector3::new(0.0, 0.0, -1.0);
+        let eps = 1e-12;
+        for (old, new) in before.iter().zip(particle_filter.particles.iter()) {
+            let expected_pos = old.position + old.velocity * dt + 0.5 * a * dt * dt;
+            let expected_vel = old.velocity + a * dt;
+            assert!((new.position - expected_pos).abs().max() <= eps);
+            assert!((new.velocity - expected_vel).abs().max() <= eps);
+        }
+
+        let mean_velocity = particle_filter.posterior_mean_velocity();
+        let expected: Vector3<f64> = particle_filter
+            .particles
+            .iter()
+            .map(|p| p.velocity)
+            .sum::<Vector3<f64>>()
+            / 100.0;
+        assert!((mean_velocity - expected).abs().max() <= 1e-9);
+    }
+
+    #[test]
+    fn test_posterior_covariance_of_weighted_particles() {
+        let particle_filter = ParticleFilter {
+            particles: vec![
+                Particle::new(Vector3::new(-1.0, 0.0, 0.0), 0.25_f64.ln()),
+                Particle::new(Vector3::new(1.0, 0.0, 0.0), 0.25_f64.ln()),
+                Particle::new(Vector3::new(0.0, 2.0, 0.0), 0.5_f64.ln()),
+            ],
+            ..Default::default()
+        };
+
+        // mean = (0, 1, 0); var_x = 0.5, var_y = 1.0, var_z = 0
+        let mean = particle_filter.posterior_mean();
+        assert!((mean - Vector3::new(0.0, 1.0, 0.0)).abs().max() <= 1e-12);
+
+        let cov = particle_filter.posterior_covariance();
+        let expected = Matrix3::from_diagonal(&Vector3::new(0.5, 1.0, 0.0));
+        assert!((cov - expected).abs().max() <= 1e-12, "got {cov}");
+
+        let std = particle_filter.posterior_std();
+        assert!((std - Vector3::new(0.5_f64.sqrt(), 1.0, 0.0)).abs().max() <= 1e-12);
+    }
+
+    #[test]
+    fn test_credible_ellipsoid_covers_requested_mass() {
+        let bounding_box =
+            BoundingBox::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(10.0, 10.0, 10.0)).unwrap();
+        let mut particle_filter = ParticleFilter::new(&bounding_box, 50_000, 0.5);
+        particle_filter.update_weights(0.0, Vector3::new(5.0, 5.0, 5.0), 1.0);
+        particle_filter.normalize_weights();
+
+        let level = 0.9;
+        let ellipsoid = particle_filter.credible_ellipsoid(level);
+        let w = particle_filter.linear_weights();
+        let mass: f64 = particle_filter
+            .particles
+            .iter()
+            .zip(w.iter())
+            .filter(|(p, _)| ellipsoid.contains(&p.position))
+            .map(|(_, wi)| wi)
+            .sum();
+
+        assert!((mass - level).abs() <= 0.02, "mass {mass}");
+    }
+
+    #[test]
+    fn test_robust_likelihood_survives_nlos_outlier() {
+        let anchor = Vector3::new(0.0, 0.0, 0.0);
+        let truth = Vector3::new(10.0, 0.0, 0.0);
+        let wrong = Vector3::new(18.0, 0.0, 0.0);
+        let sigma = 0.5;
+
+        let share_of_truth = |likelihood: &LikelihoodModel| {
+            let mut particle_filter = ParticleFilter {
+                particles: vec![Particle::new(truth, 0.0), Particle::new(wrong, 0.0)],
+                ..Default::default()
+            };
+            particle_filter.normalize_weights();
+            // a single multipath range with 8 m of excess delay
+            particle_filter.update_weights_with(18.0, anchor, sigma, likelihood);
+            particle_filter.normalize_weights();
+            particle_filter.particles[0].log_weight.exp()
+        };
+
+        let gaussian = share_of_truth(&LikelihoodModel::Gaussian);
+        let nlos = share_of_truth(&LikelihoodModel::Nlos {
+            nlos_prob: 0.2,
+            mean_excess: 5.0,
+        });
+
+        assert!(gaussian < 1e-50);
+        assert!(nlos > 0.01, "truth share {nlos}");
+    }
+
+    #[test]
+    fn test_tdoa_update_favours_consistent_particle() {
+        use crate::tdoa::{Arrival, TdoaMeasurement};
+
+        let truth = Vector3::new(3.0, 4.0, 1.0);
+        let anchors = [
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(20.0, 0.0, 0.0),
+            Vector3::new(0.0, 20.0, 0.0),
+            Vector3::new(0.0, 0.0, 20.0),
+        ];
+        // every arrival is offset by the same unknown transmit time
+        let arrivals: Vec<Arrival> = anchors
+            .iter()
+            .map(|a| Arrival {
+                position: *a,
+                range: (a - truth).norm() + 123.0,
+                std: 0.2,
+            })
+            .collect();
+        let measurement = TdoaMeasurement::from_arrivals(&arrivals[0], &arrivals[1..]);
+
+        let mut particle_filter = ParticleFilter {
+            particles: vec![
+                Particle::new(truth, 0.0),
+                Particle::new(truth + Vector3::new(2.0, 0.0, 0.0), 0.0),
+            ],
+            ..Default::default()
+        };
+        particle_filter.normalize_weights();
+        particle_filter.update_weights_tdoa(&measurement);
+        particle_filter.normalize_weights();
+
+        assert!(particle_filter.particles[0].log_weight.exp() > 0.999);
+    }
+
+    #[test]
+    fn test_clock_state_separates_bias_from_range() {
+        let anchors = [
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(30.0, 0.0, 0.0),
+            Vector3::new(0.0, 30.0, 0.0),
+            Vector3::new(0.0, 0.0, 30.0),
+        ];
+        let truth = Vector3::new(5.0, 5.0, 5.0);
+        let bias = 20e-9;
+
+        // the same position with and without the right clock bias
+        let mut particle_filter = ParticleFilter {
+            particles: vec![
+                Particle {
+                    clock_bias: bias,
+                    ..Particle::new(truth, 0.0)
+                },
+                Particle::new(truth, 0.0),
+            ],
+            clock_state: true,
+            ..Default::default()
+        };
+        particle_filter.normalize_weights();
+        assert_eq!(particle_filter.dimension(), 5);
+
+        for a in anchors {
+            let toa = (a - truth).norm() / SPEED_OF_LIGHT + bias;
+            particle_filter.update_weights_toa(toa, a, 0.3, &LikelihoodModel::Gaussian);
+            particle_filter.normalize_weights();
+        }
+
+        assert!(particle_filter.particles[0].log_weight.exp() > 0.999);
+        assert!((particle_filter.posterior_mean_clock_bias() - bias).abs() <= 1e-10);
+    }
+
+    #[test]
+    fn test_with_clock_state_draws_within_bounds() {
+        let bounding_box =
+            BoundingBox::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 1.0)).unwrap();
+        let particle_filter =
+            ParticleFilter::new(&bounding_box, 500, 0.5).with_clock_state(1e-6, 1e-8);
+
+        assert!(particle_filter.has_clock_state());
+        assert!(particle_filter
+            .particles
+            .iter()
+            .all(|p| p.clock_bias.abs() <= 1e-6 && p.clock_drift.abs() <= 1e-8));
+        assert!(particle_filter
+            .particles
+            .iter()
+            .any(|p| p.clock_bias != 0.0));
+    }
+
+    #[test]
+    fn test_seeded_filter_is_independent_of_thread_count() {
+        let run = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            pool.install(|| {
+                let bounding_box =
+                    BoundingBox::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(10.0, 10.0, 10.0))
+                        .unwrap();
+                let mut rng = StdRng::seed_from_u64(7);
+                // several chunks, so the parallel paths are exercised
+                let mut particle_filter =
+                    ParticleFilter::new_with_rng(&bounding_box, 3 * CHUNK_SIZE + 5, 1.0, &mut rng);
+                let dynamics_model = WhiteNoiseAcceleration::new(
+                    Vector3::zeros(),
+                    Vector3::zeros(),
+                    Vector3::zeros(),
+                    Vector3::new(1.0, 1.0, 1.0),
+                );
+                for _ in 0..3 {
+                    particle_filter.predict_with_measured_velocity(
+                        0.1,
+                        Vector3::new(1.0, 0.0, 0.0),
+                        &dynamics_model,
+                    );
+                    particle_filter.update_weights(5.0, Vector3::zeros(), 1.0);
+                    particle_filter.normalize_weights();
+                    particle_filter.resample();
+                }
+                particle_filter
+            })
+        };
+
+        let single = run(1);
+        assert_eq!(single, run(4));
+        assert_eq!(single, run(4));
+    }
+
+    #[test]
+    fn test_zero_roughening_keeps_exact_clones() {
+        let mut particle_filter = ParticleFilter {
+            particles: vec![
+                Particle::new(Vector3::new(1.0, 2.0, 3.0), 0.0),
+                Particle::new(Vector3::new(-1.0, 0.0, 0.0), -50.0),
+            ],
+            ess_tau: 1.0,
+            ..Default::default()
+        }
+        .with_roughening(0.0);
+        assert_eq!(ParticleFilter::default().roughening(), DEFAULT_ROUGHENING);
+
+        particle_filter.resample();
+
+        assert!(particle_filter
+            .particles
+            .iter()
+            .all(|p| p.position == Vector3::new(1.0, 2.0, 3.0)));
+    }
+
+    #[test]
+    fn test_normalize_returns_log_total_and_resample_reports() {
+        let mut particle_filter = ParticleFilter {
+            particles: vec![
+                Particle::new(Vector3::zeros(), 0.5_f64.ln()),
+                Particle::new(Vector3::x(), 0.25_f64.ln()),
+            ],
+            ess_tau: 0.75,
+            ..Default::default()
+        };
+
+        let lse = particle_filter.normalize_weights();
+        assert!((lse - 0.75_f64.ln()).abs() < 1e-12);
+        assert!((particle_filter.normalize_weights()).abs() < 1e-12);
+
+        // ESS 1.8 of 2 is above the threshold of 1.5, then drops to 1
+        assert!(!particle_filter.resample());
+        particle_filter.particles[1].log_weight = -50.0;
+        particle_filter.normalize_weights();
+        assert!(particle_filter.resample());
+        assert!(!ParticleFilter {
+            particles: Vec::new(),
+            ..Default::default()
+        }
+        .resample());
+    }
+
+    #[test]
+    fn test_terrain_keeps_particles_above_ground() {
+        use crate::terrain::{AglPrior, HeightGrid};
+        use std::sync::Arc;
+
+        // flat ground 10 m up over [-100, 100]^2
+        let ground = Arc::new(HeightGrid::new([-50.0, -50.0], 100.0, 2, 2, vec![10.0; 4]));
+        let filter = |zs: &[f64], below_ground| {
+            ParticleFilter {
+                particles: zs
+                    .iter()
+                    .map(|&z| Particle::new(Vector3::new(0.0, 0.0, z), 0.0))
+                    .chain([Particle::new(Vector3::new(500.0, 0.0, 0.0), 0.0)])
+                    .collect(),
+                ..Default::default()
+            }
+            .with_terrain(TerrainConstraint::new(ground.clone(), below_ground))
+        };
+
+        let reflected = filter(&[4.0, 20.0], BelowGround::Reflect);
+        let heights: Vec<f64> = reflected.particles.iter().map(|p| p.position.z).collect();
+        assert_eq!(heights, vec![16.0, 20.0, 0.0]);
+
+        let zeroed = filter(&[4.0, 20.0], BelowGround::ZeroWeight);
+        let weights = zeroed.linear_weights();
+        assert_eq!(weights, vec![0.0, 0.5, 0.5]);
+
+        // a particle off the grid is never below ground
+        let weights = filter(&[4.0, 5.0], BelowGround::ZeroWeight).linear_weights();
+        assert_eq!(weights, vec![0.0, 0.0, 1.0]);
+
+        // the only particle above ground was zeroed earlier, so the rest are kept
+        let mut stale = ParticleFilter {
+            particles: vec![
+                Particle::new(Vector3::new(0.0, 0.0, 4.0), 0.0),
+                Particle::new(Vector3::new(0.0, 0.0, 20.0), f64::NEG_INFINITY),
+            ],
+            ..Default::default()
+        };
+        stale.terrain = Some(TerrainConstraint::new(
+            ground.clone(),
+            BelowGround::ZeroWeight,
+        ));
+        stale.apply_terrain();
+        let log_weights: Vec<f64> = stale.particles.iter().map(|p| p.log_weight).collect();
+        assert_eq!(log_weights, vec![0.0, f64::NEG_INFINITY]);
+
+        let mut agl = ParticleFilter {
+            particles: vec![
+                Particle::new(Vector3::new(0.0, 0.0, 20.0), 0.0),
+                Particle::new(Vector3::new(0.0, 0.0, 30.0), 0.0),
+            ],
+            ..Default::default()
+        }
+        .with_terrain(
+            TerrainConstraint::new(ground, BelowGround::ZeroWeight).with_agl_prior(AglPrior {
+                mean: 10.0,
+                std: 2.0,
+            }),
+        );
+        let weights = agl.linear_weights();
+        assert!(weights[0] > 0.99, "{weights:?}");
+
+        // and again after every prediction
+        agl.particles[0].log_weight = agl.particles[1].log_weight;
+        agl.predict_with_measured_velocity(
+            0.0,
+            Vector3::zeros(),
+            &WhiteNoiseAcceleration::default(),
+        );
+        assert!(agl.linear_weights()[0] > 0.99);
+    }
 }
@@ -0,0 +1,349 @@
+use std::collections::HashSet;
+
+use nalgebra::Vector3;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::stats::normal_quantile;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResamplingScheme {
+    Multinomial,
+    Stratified,
+    #[default]
+    Systematic,
+    Residual,
+    ResidualSystematic,
+}
+
+impl ResamplingScheme {
+    // Draw `n` ancestor indices from the normalized weights `w`.
+    pub fn indices<R: Rng + ?Sized>(&self, w: &[f64], n: usize, rng: &mut R) -> Vec<usize> {
+        if w.is_empty() || n == 0 {
+            return Vec::new();
+        }
+
+        match self {
+            ResamplingScheme::Multinomial => multinomial(w, n, rng),
+            ResamplingScheme::Stratified => {
+                let nf = n as f64;
+                let u = (0..n).map(|j| (j as f64 + rng.random::<f64>()) / nf);
+                walk_cumulative(&cumulative(w), u)
+            }
+            ResamplingScheme::Systematic => {
+                let nf = n as f64;
+                let u0 = rng.random::<f64>() / nf;
+                let u = (0..n).map(|j| u0 + j as f64 / nf);
+                walk_cumulative(&cumulative(w), u)
+            }
+            ResamplingScheme::Residual => residual(w, n, rng),
+            ResamplingScheme::ResidualSystematic => residual_systematic(w, n, rng),
+        }
+    }
+}
+
+// KLD-sampling (Fox, 2003): choose the number of particles so that, with probability
+// 1 - delta, the KL divergence between the sample-based and true posterior stays below
+// epsilon. Support is measured by the number of occupied cubic bins of side `bin_size`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KldSampling {
+    pub min_particles: usize,
+    pub max_particles: usize,
+    pub bin_size: f64,
+    pub epsilon: f64,
+    pub delta: f64,
+}
+
+impl KldSampling {
+    pub fn new(
+        min_particles: usize,
+        max_particles: usize,
+        bin_size: f64,
+        epsilon: f64,
+        delta: f64,
+    ) -> Self {
+        assert!(
+            min_particles > 0 && min_particles <= max_particles,
+            "KldSampling: need 0 < min_particles <= max_particles"
+        );
+        assert!(bin_size > 0.0, "KldSampling: bin_size must be > 0");
+        assert!(epsilon > 0.0, "KldSampling: epsilon must be > 0");
+        assert!(
+            delta > 0.0 && delta < 1.0,
+            "KldSampling: delta must be in (0, 1)"
+        );
+        Self {
+            min_particles,
+            max_particles,
+            bin_size,
+            epsilon,
+            delta,
+        }
+    }
+
+    // Wilson-Hilferty approximation of the chi-square quantile used by Fox.
+    pub fn required_particles(&self, occupied_bins: usize) -> usize {
+        if occupied_bins < 2 {
+            return 0;
+        }
+        let k = (occupied_bins - 1) as f64;
+        let z = normal_quantile(1.0 - self.delta);
+        let a = 2.0 / (9.0 * k);
+        let n = k / (2.0 * self.epsilon) * (1.0 - a + a.sqrt() * z).powi(3);
+        n.ceil() as usize
+    }
+
+    // Draw from the weighted particle set until the KLD bound is met and return the sample size.
+    pub fn sample_size<R: Rng + ?Sized>(
+        &self,
+        positions: &[Vector3<f64>],
+        w: &[f64],
+        rng: &mut R,
+    ) -> usize {
+        if positions.is_empty() {
+            return 0;
+        }
+
+        let cum_weights = cumulative(w);
+        let mut bins = HashSet::new();
+        let mut required = 0;
+        let mut n = 0;
+        while n < self.max_particles {
+            let u = rng.random::<f64>();
+            let i = cum_weights
+                .partition_point(|&c| c < u)
+                .min(positions.len() - 1);
+            if bins.insert(self.bin(&positions[i])) {
+                required = self.required_particles(bins.len());
+            }
+            n += 1;
+
+            if n >= self.min_particles && n >= required {
+                break;
+            }
+        }
+        n
+    }
+
+    fn bin(&self, position: &Vector3<f64>) -> (i64, i64, i64) {
+        let b = position / self.bin_size;
+        (b.x.floor() as i64, b.y.floor() as i64, b.z.floor() as i64)
+    }
+}
+
+fn cumulative(w: &[f64]) -> Vec<f64> {
+    let mut cum_weights = Vec::with_capacity(w.len());
+    let mut csum = 0.0;
+    for wi in w {
+        csum += wi;
+        cum_weights.push(csum);
+    }
+
+    if let Some(last) = cum_weights.last_mut() {
+        *last = 1.0;
+    }
+    cum_weights
+}
+
+// `u` must be sorted in ascending order.
+fn walk_cumulative(cum_weights: &[f64], u: impl Iterator<Item = f64>) -> Vec<usize> {
+    let last = cum_weights.len() - 1;
+    let mut out = Vec::new();
+    let mut i = 0usize;
+    for uj in u {
+        while i < last && uj > cum_weights[i] {
+            i += 1;
+        }
+        out.push(i);
+    }
+    out
+}
+
+fn multinomial<R: Rng + ?Sized>(w: &[f64], n: usize, rng: &mut R) -> Vec<usize> {
+    let mut u: Vec<f64> = (0..n).map(|_| rng.random::<f64>()).collect();
+    u.sort_by(f64::total_cmp);
+    walk_cumulative(&cumulative(w), u.into_iter())
+}
+
+fn residual<R: Rng + ?Sized>(w: &[f64], n: usize, rng: &mut R) -> Vec<usize> {
+    let nf = n as f64;
+    let mut out = Vec::with_capacity(n);
+    let mut residuals = Vec::with_capacity(w.len());
+    for (i, wi) in w.iter().enumerate() {
+        let expected = nf * wi;
+        let copies = expected.floor();
+        out.extend(std::iter::repeat_n(i, copies as usize));
+        residuals.push(expected - copies);
+    }
+
+    let remaining = n.saturating_sub(out.len());
+    if remaining > 0 {
+        let total: f64 = residuals.iter().sum();
+        if total > 0.0 {
+            residuals.iter_mut().for_each(|r| *r /= total);
+            out.extend(multinomial(&residuals, remaining, rng));
+        } else {
+            out.extend(multinomial(w, remaining, rng));
+        }
+    }
+    out.truncate(n);
+    out
+}
+
+fn residual_systematic<R: Rng + ?Sized>(w: &[f64], n: usize, rng: &mut R) -> Vec<usize> {
+    let nf = n as f64;
+    let mut out = Vec::with_capacity(n);
+    let mut u = rng.random::<f64>() / nf;
+    for (i, wi) in w.iter().enumerate() {
+        let copies = (((wi - u) * nf).floor() + 1.0).max(0.0);
+        out.extend(std::iter::repeat_n(i, copies as usize));
+        u += copies / nf - wi;
+    }
+
+    // floating point drift can leave us one short or one over
+    if out.len() < n {
+        let heaviest = w
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(i, _)| i)
+            .unwrap_or(0);
+        out.resize(n, heaviest);
+    }
+    out.truncate(n);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    const SCHEMES: [ResamplingScheme; 5] = [
+        ResamplingScheme::Multinomial,
+        ResamplingScheme::Stratified,
+        ResamplingScheme::Systematic,
+        ResamplingScheme::Residual,
+        ResamplingScheme::ResidualSystematic,
+    ];
+
+    fn counts(indices: &[usize], len: usize) -> Vec<usize> {
+        let mut c = vec![0; len];
+        for &i in indices {
+            c[i] += 1;
+        }
+        c
+    }
+
+    #[test]
+    fn every_scheme_returns_requested_number_of_valid_indices() {
+        let w = [0.1, 0.2, 0.3, 0.4];
+        let mut rng = StdRng::seed_from_u64(1);
+
+        for scheme in SCHEMES {
+            for n in [1, 4, 7, 100] {
+                let idx = scheme.indices(&w, n, &mut rng);
+                assert_eq!(idx.len(), n, "{scheme:?} with n={n}");
+                assert!(idx.iter().all(|&i| i < w.len()), "{scheme:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn degenerate_weights_select_single_particle() {
+        let w = [0.0, 0.0, 1.0, 0.0];
+        let mut rng = StdRng::seed_from_u64(2);
+
+        for scheme in SCHEMES {
+            let idx = scheme.indices(&w, 50, &mut rng);
+            assert!(idx.iter().all(|&i| i == 2), "{scheme:?} got {idx:?}");
+        }
+    }
+
+    #[test]
+    fn low_variance_schemes_keep_counts_within_one_of_expectation() {
+        let w = [0.05, 0.15, 0.3, 0.5];
+        let n = 1000;
+        let mut rng = StdRng::seed_from_u64(3);
+
+        for scheme in [
+            ResamplingScheme::Systematic,
+            ResamplingScheme::Residual,
+            ResamplingScheme::ResidualSystematic,
+        ] {
+            let c = counts(&scheme.indices(&w, n, &mut rng), w.len());
+            for (ci, wi) in c.iter().zip(w.iter()) {
+                let expected = wi * n as f64;
+                assert!(
+                    (*ci as f64 - expected).abs() <= 1.0 + 1e-9,
+                    "{scheme:?}: got {ci}, expected {expected}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn residual_keeps_deterministic_copies() {
+        let w = [0.26, 0.74];
+        let n = 10;
+        let mut rng = StdRng::seed_from_u64(4);
+
+        let c = counts(
+            &ResamplingScheme::Residual.indices(&w, n, &mut rng),
+            w.len(),
+        );
+        assert!(c[0] >= 2);
+        assert!(c[1] >= 7);
+    }
+
+    #[test]
+    fn kld_required_particles_grows_with_support() {
+        let kld = KldSampling::new(10, 100_000, 1.0, 0.05, 0.01);
+
+        assert_eq!(kld.required_particles(0), 0);
+        assert_eq!(kld.required_particles(1), 0);
+
+        let few = kld.required_particles(5);
+        let many = kld.required_particles(500);
+        assert!(few > 0);
+        assert!(many > few);
+    }
+
+    #[test]
+    fn kld_sample_size_shrinks_for_concentrated_belief() {
+        let kld = KldSampling::new(50, 10_000, 0.5, 0.05, 0.01);
+        let mut rng = StdRng::seed_from_u64(6);
+
+        let n = 5_000;
+        let w = vec![1.0 / n as f64; n];
+        let concentrated: Vec<Vector3<f64>> = (0..n)
+            .map(|i| Vector3::new(0.01 * (i % 10) as f64, 0.0, 0.0))
+            .collect();
+        let spread: Vec<Vector3<f64>> = (0..n)
+            .map(|i| Vector3::new((i % 20) as f64, (i / 20 % 20) as f64, (i / 400) as f64))
+            .collect();
+
+        let small = kld.sample_size(&concentrated, &w, &mut rng);
+        let large = kld.sample_size(&spread, &w, &mut rng);
+
+        assert_eq!(small, kld.min_particles);
+        assert!(large > small);
+        assert!(large <= kld.max_particles);
+    }
+
+    #[test]
+    fn random_schemes_are_unbiased() {
+        let w = [0.1, 0.6, 0.3];
+        let n = 100_000;
+        let mut rng = StdRng::seed_from_u64(5);
+
+        for scheme in [ResamplingScheme::Multinomial, ResamplingScheme::Stratified] {
+            let c = counts(&scheme.indices(&w, n, &mut rng), w.len());
+            for (ci, wi) in c.iter().zip(w.iter()) {
+                let freq = *ci as f64 / n as f64;
+                assert!((freq - wi).abs() < 0.01, "{scheme:?}: {freq} vs {wi}");
+            }
+        }
+    }
+}
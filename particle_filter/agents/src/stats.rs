@@ -0,0 +1,256 @@
+// Inverse of the standard normal CDF (Acklam's rational approximation, rel. error < 1.2e-9).
+pub fn normal_quantile(p: f64) -> f64 {
+    assert!(p > 0.0 && p < 1.0, "normal_quantile: p must be in (0, 1)");
+
+    const A: [f64; 6] = [
+        -3.969_683_028_665_376e1,
+        2.209_460_984_245_205e2,
+        -2.759_285_104_469_687e2,
+        1.383_577_518_672_69e2,
+        -3.066_479_806_614_716e1,
+        2.506_628_277_459_239,
+    ];
+    const B: [f64; 5] = [
+        -5.447_609_879_822_406e1,
+        1.615_858_368_580_409e2,
+        -1.556_989_798_598_866e2,
+        6.680_131_188_771_972e1,
+        -1.328_068_155_288_572e1,
+    ];
+    const C: [f64; 6] = [
+        -7.784_894_002_430_293e-3,
+        -3.223_964_580_411_365e-1,
+        -2.400_758_277_161_838,
+        -2.549_732_539_343_734,
+        4.374_664_141_464_968,
+        2.938_163_982_698_783,
+    ];
+    const D: [f64; 4] = [
+        7.784_695_709_041_462e-3,
+        3.224_671_290_700_398e-1,
+        2.445_134_137_142_996,
+        3.754_408_661_907_416,
+    ];
+    const P_LOW: f64 = 0.02425;
+
+    if p < P_LOW {
+        let q = (-2.0 * p.ln()).sqrt();
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+    } else if p <= 1.0 - P_LOW {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+    } else {
+        -normal_quantile(1.0 - p)
+    }
+}
+
+// Regularized lower incomplete gamma function P(a, x).
+pub fn regularized_gamma_p(a: f64, x: f64) -> f64 {
+    assert!(a > 0.0, "regularized_gamma_p: a must be > 0");
+    if x <= 0.0 {
+        return 0.0;
+    }
+
+    if x < a + 1.0 {
+        gamma_series(a, x).exp()
+    } else {
+        1.0 - gamma_continued_fraction(a, x).exp()
+    }
+}
+
+// ln Q(a, x) = ln(1 - P(a, x)), accurate far into the upper tail.
+pub fn ln_regularized_gamma_q(a: f64, x: f64) -> f64 {
+    assert!(a > 0.0, "ln_regularized_gamma_q: a must be > 0");
+    if x <= 0.0 {
+        return 0.0;
+    }
+
+    if x < a + 1.0 {
+        (-gamma_series(a, x).exp()).ln_1p()
+    } else {
+        gamma_continued_fraction(a, x)
+    }
+}
+
+const GAMMA_MAX_ITER: usize = 500;
+const GAMMA_EPS: f64 = 1e-15;
+
+// ln P(a, x) by series expansion, converges quickly for x < a + 1.
+fn gamma_series(a: f64, x: f64) -> f64 {
+    let mut term = 1.0 / a;
+    let mut sum = term;
+    let mut ap = a;
+    for _ in 0..GAMMA_MAX_ITER {
+        ap += 1.0;
+        term *= x / ap;
+        sum += term;
+        if term.abs() < sum.abs() * GAMMA_EPS {
+            break;
+        }
+    }
+    sum.ln() + a * x.ln() - x - ln_gamma(a)
+}
+
+// ln Q(a, x) by Lentz's continued fraction, converges quickly for x >= a + 1.
+fn gamma_continued_fraction(a: f64, x: f64) -> f64 {
+    let tiny = 1e-300;
+    let mut b = x + 1.0 - a;
+    let mut c = 1.0 / tiny;
+    let mut d = 1.0 / b;
+    let mut h = d;
+    for i in 1..GAMMA_MAX_ITER {
+        let an = -(i as f64) * (i as f64 - a);
+        b += 2.0;
+        d = an * d + b;
+        if d.abs() < tiny {
+            d = tiny;
+        }
+        c = b + an / c;
+        if c.abs() < tiny {
+            c = tiny;
+        }
+        d = 1.0 / d;
+        let delta = d * c;
+        h *= delta;
+        if (delta - 1.0).abs() < GAMMA_EPS {
+            break;
+        }
+    }
+    h.ln() + a * x.ln() - x - ln_gamma(a)
+}
+
+pub fn erf(x: f64) -> f64 {
+    x.signum() * regularized_gamma_p(0.5, x * x)
+}
+
+// ln erfc(x), finite even where erfc(x) underflows.
+pub fn ln_erfc(x: f64) -> f64 {
+    if x >= 0.0 {
+        ln_regularized_gamma_q(0.5, x * x)
+    } else {
+        (1.0 + regularized_gamma_p(0.5, x * x)).ln()
+    }
+}
+
+// Lanczos approximation (g = 7, n = 9).
+pub fn ln_gamma(x: f64) -> f64 {
+    const G: f64 = 7.0;
+    const COEFFS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+
+    if x < 0.5 {
+        // reflection formula
+        let pi = std::f64::consts::PI;
+        return (pi / (pi * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+
+    let x = x - 1.0;
+    let mut a = COEFFS[0];
+    let t = x + G + 0.5;
+    for (i, c) in COEFFS.iter().enumerate().skip(1) {
+        a += c / (x + i as f64);
+    }
+    0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + a.ln()
+}
+
+pub fn chi_squared_cdf(x: f64, dof: usize) -> f64 {
+    regularized_gamma_p(dof as f64 / 2.0, x / 2.0)
+}
+
+pub fn chi_squared_quantile(p: f64, dof: usize) -> f64 {
+    assert!(
+        p > 0.0 && p < 1.0,
+        "chi_squared_quantile: p must be in (0, 1)"
+    );
+    assert!(dof > 0, "chi_squared_quantile: dof must be > 0");
+
+    // Wilson-Hilferty gives a starting bracket, bisection polishes it
+    let k = dof as f64;
+    let a = 2.0 / (9.0 * k);
+    let guess = (k * (1.0 - a + a.sqrt() * normal_quantile(p)).powi(3)).max(1e-9);
+
+    let mut lo = 0.0;
+    let mut hi = guess.max(1.0);
+    while chi_squared_cdf(hi, dof) < p {
+        lo = hi;
+        hi *= 2.0;
+    }
+    for _ in 0..100 {
+        let mid = 0.5 * (lo + hi);
+        if chi_squared_cdf(mid, dof) < p {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+        if hi - lo <= 1e-12 * hi {
+            break;
+        }
+    }
+    0.5 * (lo + hi)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normal_quantile_matches_tabulated_values() {
+        let eps = 1e-6;
+        assert!(normal_quantile(0.5).abs() <= eps);
+        assert!((normal_quantile(0.975) - 1.959_963_985).abs() <= eps);
+        assert!((normal_quantile(0.99) - 2.326_347_874).abs() <= eps);
+        assert!((normal_quantile(0.01) + 2.326_347_874).abs() <= eps);
+        assert!((normal_quantile(0.001) + 3.090_232_306).abs() <= eps);
+    }
+
+    #[test]
+    fn chi_squared_quantile_matches_tabulated_values() {
+        let eps = 1e-6;
+        assert!((chi_squared_quantile(0.95, 1) - 3.841_458_821).abs() <= eps);
+        assert!((chi_squared_quantile(0.95, 2) - 5.991_464_547).abs() <= eps);
+        assert!((chi_squared_quantile(0.95, 3) - 7.814_727_903).abs() <= eps);
+        assert!((chi_squared_quantile(0.99, 3) - 11.344_866_73).abs() <= eps);
+        assert!((chi_squared_quantile(0.5, 3) - 2.365_973_884).abs() <= eps);
+    }
+
+    #[test]
+    fn chi_squared_cdf_inverts_quantile() {
+        for dof in 1..=6 {
+            for p in [0.05, 0.5, 0.9, 0.999] {
+                let x = chi_squared_quantile(p, dof);
+                assert!((chi_squared_cdf(x, dof) - p).abs() <= 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn erf_matches_tabulated_values() {
+        let eps = 1e-12;
+        assert_eq!(erf(0.0), 0.0);
+        assert!((erf(0.5) - 0.520_499_877_813_046_5).abs() <= eps);
+        assert!((erf(-1.0) + 0.842_700_792_949_714_9).abs() <= eps);
+        assert!((erf(2.0) - 0.995_322_265_018_952_7).abs() <= eps);
+    }
+
+    #[test]
+    fn ln_erfc_is_accurate_in_the_tail() {
+        let eps = 1e-9;
+        assert_eq!(ln_erfc(0.0), 0.0);
+        assert!((ln_erfc(-2.0) - 1.995_322_265_018_952_7_f64.ln()).abs() <= eps);
+        assert!((ln_erfc(3.0) - 2.209_049_699_858_544e-5_f64.ln()).abs() <= eps);
+        // erfc(30) ~ 2.56e-393 underflows f64, its log must not
+        assert!((ln_erfc(30.0) + 903.974_117_110_644).abs() <= 1e-6);
+    }
+}
@@ -1,9 +1,19 @@
-use crate::{dynamics_model::DynamicsModel, particle_filter::ParticleFilter, Measurements};
-
-use nalgebra::Vector3;
-use rand::rng;
+use crate::{
+    clock::ClockModel,
+    dynamics_model::DynamicsModel,
+    estimator::PointEstimator,
+    particle_filter::{FilterState, ParticleFilter},
+    rng::RngStream,
+    uncertainty::CredibleEllipsoid,
+    Measurements,
+};
+
+use nalgebra::{Matrix3, Vector3};
+use rand::Rng;
 use rand_distr::{Distribution, Normal};
 
+pub const DEFAULT_CREDIBLE_LEVEL: f64 = 0.95;
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct PrevPositions {
     pub true_position: Option<Vector3<f64>>,
@@ -25,12 +35,25 @@ pub struct SwarmElement<M: DynamicsModel> {
 
     pub dynamics_model: M,
     pub est_position: Vector3<f64>,
+    pub est_velocity: Option<Vector3<f64>>,
+    // Posterior covariance, about the posterior mean rather than `est_position`.
+    pub est_covariance: Matrix3<f64>,
+    pub est_mean: Vector3<f64>,
+    // Estimated tag clock offset (s), only with a clock state in the filter.
+    pub est_clock_bias: Option<f64>,
+    // Effective sample size behind the estimate, before any resampling.
+    pub est_ess: f64,
+    pub credible_level: f64,
+    pub estimator: PointEstimator,
     pub particle_filter: ParticleFilter,
 
     pub transmission_noise: Normal<f64>,
     pub ranging_noise: Normal<f64>,
+    pub clock: ClockModel,
 
     pub prev_positions: PrevPositions,
+    // Drives the true motion, clock and velocity readings of this element.
+    pub rng: RngStream,
 }
 
 impl<M> SwarmElement<M>
@@ -53,15 +76,49 @@ where
             name,
             dynamics_model,
             est_position: Vector3::zeros(),
+            est_velocity: None,
+            est_covariance: Matrix3::zeros(),
+            est_mean: Vector3::zeros(),
+            est_clock_bias: None,
+            est_ess: 0.0,
+            credible_level: DEFAULT_CREDIBLE_LEVEL,
+            estimator: PointEstimator::default(),
             particle_filter,
             transmission_noise,
             ranging_noise,
+            clock: ClockModel::default(),
             prev_positions,
+            rng: RngStream::from_entropy(),
         }
     }
 
+    // Split `stream` between the element itself and its particle filter.
+    pub fn set_rng_stream(&mut self, stream: RngStream) {
+        self.rng = stream.substream(0);
+        self.particle_filter.set_rng_stream(stream.substream(1));
+    }
+
+    pub fn with_credible_level(mut self, credible_level: f64) -> Self {
+        assert!(
+            credible_level > 0.0 && credible_level < 1.0,
+            "SwarmElement: credible_level must be in (0, 1)"
+        );
+        self.credible_level = credible_level;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: ClockModel) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_estimator(mut self, estimator: PointEstimator) -> Self {
+        self.estimator = estimator;
+        self
+    }
+
     pub fn update_est_position(&mut self) {
-        let new_est = self.particle_filter.posterior_mean();
+        let new_est = self.particle_filter.estimate(&self.estimator);
 
         match self.prev_positions.est_position {
             None => {
@@ -75,17 +132,41 @@ where
                 self.est_position = new_est;
             }
         }
+
+        self.est_covariance = self.particle_filter.posterior_covariance();
+        self.est_mean = self.particle_filter.posterior_mean();
+        self.est_ess = self.particle_filter.ess();
+
+        if self.particle_filter.state() == FilterState::PositionVelocity {
+            self.est_velocity = Some(self.particle_filter.posterior_mean_velocity());
+        }
+
+        if self.particle_filter.has_clock_state() {
+            self.est_clock_bias = Some(self.particle_filter.posterior_mean_clock_bias());
+        }
+    }
+
+    pub fn est_std(&self) -> Vector3<f64> {
+        self.est_covariance.diagonal().map(f64::sqrt)
+    }
+
+    // Centred on the posterior mean, which `est_covariance` is taken about, whatever
+    // the point estimator.
+    pub fn credible_ellipsoid(&self) -> CredibleEllipsoid {
+        CredibleEllipsoid::from_covariance(self.est_mean, &self.est_covariance, self.credible_level)
     }
 
     pub fn estimation_error(&self) -> f64 {
         (self.dynamics_model.position() - self.est_position).norm()
     }
 
-    fn get_ranging_velocity(&self) -> Vector3<f64> {
+    // A noisy reading of the true velocity, as the filter gets it.
+    pub fn measure_velocity(&mut self) -> Vector3<f64> {
+        let mut rng = self.rng.next_rng();
         let noise: Vector3<f64> = Vector3::new(
-            self.transmission_noise.sample(&mut rng()),
-            self.transmission_noise.sample(&mut rng()),
-            self.transmission_noise.sample(&mut rng()),
+            self.transmission_noise.sample(&mut rng),
+            self.transmission_noise.sample(&mut rng),
+            self.transmission_noise.sample(&mut rng),
         );
 
         self.dynamics_model.velocity() + noise
@@ -95,14 +176,50 @@ where
     where
         M: Sync,
     {
-        self.particle_filter.predict_with_measured_velocity(
-            dt,
-            self.get_ranging_velocity(),
-            &self.dynamics_model,
-        );
+        let velocity = self.measure_velocity();
+        self.predict(dt, velocity);
+        self.advance(dt);
+    }
+
+    // Filter prediction; `measured_velocity` is only used by a position-only state.
+    pub fn predict(&mut self, dt: f64, measured_velocity: Vector3<f64>)
+    where
+        M: Sync,
+    {
+        match self.particle_filter.state() {
+            FilterState::Position => self.particle_filter.predict_with_measured_velocity(
+                dt,
+                measured_velocity,
+                &self.dynamics_model,
+            ),
+            FilterState::PositionVelocity => self
+                .particle_filter
+                .predict_with_particle_velocity(dt, &self.dynamics_model),
+        }
+
+        if self.particle_filter.has_clock_state() {
+            self.particle_filter.predict_clock(dt, &self.clock);
+        }
+    }
 
+    // Move the true state and clock on by `dt`.
+    pub fn advance(&mut self, dt: f64) {
         self.prev_positions.true_position = Some(self.dynamics_model.position());
-        self.dynamics_model.step(dt, &mut rng());
+        let mut rng = self.rng.next_rng();
+        self.dynamics_model.step(dt, &mut rng);
+        self.clock.step(dt, &mut rng);
+    }
+
+    // Replace the true state with a recorded one.
+    pub fn set_true_state(
+        &mut self,
+        position: Vector3<f64>,
+        velocity: Vector3<f64>,
+        clock_offset: f64,
+    ) {
+        self.prev_positions.true_position = Some(self.dynamics_model.position());
+        self.dynamics_model.set_state(position, velocity);
+        self.clock.offset = clock_offset;
     }
 
     pub fn debug_print(&self) {
@@ -114,6 +231,16 @@ where
         println!("Dynamics velocity: {:?}", vel);
 
         println!("PF estimated position: {:?}", self.est_position);
+        println!("PF estimated std: {:?}", self.est_std());
+        if let Some(est_vel) = self.est_velocity {
+            println!("PF estimated velocity: {:?}", est_vel);
+        }
+        if let Some(est_bias) = self.est_clock_bias {
+            println!(
+                "Clock offset: {:e} s, estimated: {:e} s",
+                self.clock.offset, est_bias
+            );
+        }
         let pf = &self.particle_filter;
         let n = pf.particles.len();
         println!("Particle filter: {} particles", n);
@@ -142,20 +269,34 @@ where
             name: String::new(),
             dynamics_model: M::default(),
             est_position: Vector3::zeros(),
+            est_velocity: None,
+            est_covariance: Matrix3::zeros(),
+            est_mean: Vector3::zeros(),
+            est_clock_bias: None,
+            est_ess: 0.0,
+            credible_level: DEFAULT_CREDIBLE_LEVEL,
+            estimator: PointEstimator::default(),
             particle_filter: ParticleFilter::default(),
             transmission_noise: noise,
             ranging_noise: noise,
+            clock: ClockModel::default(),
             prev_positions: PrevPositions::default(),
+            rng: RngStream::from_entropy(),
         }
     }
 }
 
 impl<M: DynamicsModel> Measurements<M> for SwarmElement<M> {
-    fn ranging(&self, swarm_element: &SwarmElement<M>, std_raning_noise: f64) -> f64 {
+    fn ranging<R: Rng + ?Sized>(
+        &self,
+        swarm_element: &SwarmElement<M>,
+        std_raning_noise: f64,
+        rng: &mut R,
+    ) -> f64 {
         let noise = Normal::new(0.0, std_raning_noise).unwrap();
         let diff =
             (self.dynamics_model.position() - swarm_element.dynamics_model.position()).norm();
-        diff + noise.sample(&mut rng())
+        diff + noise.sample(rng)
     }
 }
 
@@ -203,6 +344,7 @@ mod tests {
         assert_eq!(swarm_element.name, swarm_name);
         assert_eq!(swarm_element.dynamics_model.position(), position);
         assert_eq!(swarm_element.est_position, Vector3::zeros());
+        assert_eq!(swarm_element.est_velocity, None);
         assert_eq!(swarm_element.prev_positions.est_position, None);
         assert_eq!(swarm_element.prev_positions.true_position, None);
         assert_eq!(swarm_element.dynamics_model.velocity(), velocity);
@@ -264,6 +406,17 @@ mod tests {
             swarm_element.prev_positions.est_position,
             Some(swarm_element.est_position)
         );
+
+        // uniform on [0, L] has std L / sqrt(12)
+        let expected_std = Vector3::new(1.0, 2.0, 3.0) / 12.0_f64.sqrt();
+        assert!((swarm_element.est_std() - expected_std).abs().max() <= tolerance);
+
+        let ellipsoid = swarm_element.credible_ellipsoid();
+        assert_eq!(
+            ellipsoid.center,
+            swarm_element.particle_filter.posterior_mean()
+        );
+        assert_eq!(ellipsoid.level, DEFAULT_CREDIBLE_LEVEL);
     }
 
     #[test]
@@ -318,15 +471,17 @@ mod tests {
         let num_samples = 100_000;
         let empirical_sum: f64 = (0..num_samples)
             .into_par_iter()
-            .map(|_| sw1.ranging(&sw2, measurement_std_deviation))
+            .map_init(rand::rng, |rng, _| {
+                sw1.ranging(&sw2, measurement_std_deviation, rng)
+            })
             .sum();
 
         let empirical_mean = empirical_sum / num_samples as f64;
 
         let empirical_variance: f64 = (0..num_samples)
             .into_par_iter()
-            .map(|_| {
-                let x = sw1.ranging(&sw2, measurement_std_deviation);
+            .map_init(rand::rng, |rng, _| {
+                let x = sw1.ranging(&sw2, measurement_std_deviation, rng);
                 (x - empirical_mean).powi(2)
             })
             .sum::<f64>()
@@ -429,4 +584,92 @@ mod tests {
             "got {err}, expected {expected}"
         );
     }
+
+    #[test]
+    fn test_velocity_state_reports_est_velocity() {
+        let swarm_name = String::from("test");
+
+        let position = Vector3::new(0.5, 0.5, 0.5);
+        let velocity = Vector3::new(1.0, 0.0, 0.0);
+        let mean_a = Vector3::zeros();
+        let sigma_a = Vector3::new(0.1, 0.1, 0.1);
+        let dynamics_model = WhiteNoiseAcceleration::new(position, velocity, mean_a, sigma_a);
+
+        let bounding_box =
+            BoundingBox::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 1.0)).unwrap();
+        let velocity_prior =
+            BoundingBox::new(Vector3::new(0.5, -0.5, -0.5), Vector3::new(1.5, 0.5, 0.5)).unwrap();
+        let num_particles = 10_000;
+        let ess_tau = 0.5;
+        let particle_filter = ParticleFilter::new(&bounding_box, num_particles, ess_tau)
+            .with_velocity_state(&velocity_prior);
+
+        let mut swarm_element =
+            SwarmElement::new(swarm_name, dynamics_model, particle_filter, 0.1, 0.5);
+
+        swarm_element.step(0.1);
+        swarm_element.update_est_position();
+
+        let est_velocity = swarm_element
+            .est_velocity
+            .expect("velocity state should report an estimate");
+        let tolerance = 0.05;
+        assert!((est_velocity - velocity).abs().max() <= tolerance);
+    }
+
+    #[test]
+    fn test_update_est_position_uses_selected_estimator() {
+        let position = Vector3::new(0.5, 0.5, 0.5);
+        let velocity = Vector3::new(0.1, 0.1, 0.1);
+        let mean_a = Vector3::zeros();
+        let sigma_a = Vector3::new(0.1, 0.1, 0.1);
+        let dynamics_model = WhiteNoiseAcceleration::new(position, velocity, mean_a, sigma_a);
+
+        let bounding_box =
+            BoundingBox::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 1.0)).unwrap();
+        let mut particle_filter = ParticleFilter::new(&bounding_box, 100, 0.5);
+        particle_filter.particles[42].log_weight += 10.0;
+        particle_filter.normalize_weights();
+        let heaviest = particle_filter.particles[42].position;
+
+        let mut swarm_element = SwarmElement::new(
+            String::from("test"),
+            dynamics_model,
+            particle_filter,
+            0.1,
+            0.5,
+        )
+        .with_estimator(PointEstimator::MaxWeight);
+        assert_eq!(swarm_element.estimator, PointEstimator::MaxWeight);
+
+        swarm_element.update_est_position();
+        assert_eq!(swarm_element.est_position, heaviest);
+        // the ellipsoid stays on the distribution the covariance describes
+        let mean = swarm_element.particle_filter.posterior_mean();
+        assert_eq!(swarm_element.credible_ellipsoid().center, mean);
+        assert_ne!(mean, heaviest);
+    }
+
+    #[test]
+    fn test_clock_state_reports_est_clock_bias() {
+        let bounding_box =
+            BoundingBox::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 1.0)).unwrap();
+        let particle_filter =
+            ParticleFilter::new(&bounding_box, 1_000, 0.5).with_clock_state(0.0, 0.0);
+        let clock = ClockModel::new(5e-7, 1e-7, 0.0, 0.0);
+
+        let mut swarm_element = SwarmElement::<WhiteNoiseAcceleration> {
+            particle_filter,
+            ..Default::default()
+        }
+        .with_clock(clock);
+        assert_eq!(swarm_element.est_clock_bias, None);
+
+        swarm_element.step(2.0);
+        swarm_element.update_est_position();
+
+        // the true clock drifted, the particles started at zero drift and stayed put
+        assert!((swarm_element.clock.offset - 7e-7).abs() <= 1e-18);
+        assert_eq!(swarm_element.est_clock_bias, Some(0.0));
+    }
 }
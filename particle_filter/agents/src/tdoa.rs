@@ -0,0 +1,146 @@
+use nalgebra::{DMatrix, DVector, Vector3};
+
+const LN_2PI: f64 = 1.837_877_066_409_345_5;
+
+// Non-reference arrival stds are floored here (m), so the covariance stays positive
+// definite even for noiseless arrivals.
+pub const MIN_ARRIVAL_STD: f64 = 1e-3;
+
+// One anchor's time of arrival, expressed in metres, with the std of its error.
+// The tag's unknown transmit time is common to all arrivals and cancels in the differences.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Arrival {
+    pub position: Vector3<f64>,
+    pub range: f64,
+    pub std: f64,
+}
+
+// Range differences d_i = |x - a_i| - |x - a_ref| against a reference anchor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TdoaMeasurement {
+    pub reference: Vector3<f64>,
+    pub anchors: Vec<Vector3<f64>>,
+    pub differences: DVector<f64>,
+    // The reference arrival error enters every difference, so the off-diagonal
+    // terms equal the reference variance.
+    pub covariance: DMatrix<f64>,
+    precision: DMatrix<f64>,
+    log_normalizer: f64,
+}
+
+impl TdoaMeasurement {
+    pub fn from_arrivals(reference: &Arrival, others: &[Arrival]) -> Self {
+        assert!(
+            !others.is_empty(),
+            "TdoaMeasurement: need at least one non-reference arrival"
+        );
+        assert!(
+            reference.std >= 0.0 && others.iter().all(|a| a.std >= 0.0),
+            "TdoaMeasurement: arrival std must be >= 0"
+        );
+
+        let k = others.len();
+        let mut covariance = DMatrix::from_element(k, k, reference.std.powi(2));
+        for (i, a) in others.iter().enumerate() {
+            covariance[(i, i)] += a.std.max(MIN_ARRIVAL_STD).powi(2);
+        }
+        let cholesky = covariance
+            .clone()
+            .cholesky()
+            .expect("TdoaMeasurement: covariance is not positive definite");
+        let log_det = 2.0 * cholesky.l().diagonal().iter().map(|d| d.ln()).sum::<f64>();
+        let precision = cholesky.inverse();
+
+        Self {
+            reference: reference.position,
+            anchors: others.iter().map(|a| a.position).collect(),
+            differences: DVector::from_iterator(
+                k,
+                others.iter().map(|a| a.range - reference.range),
+            ),
+            covariance,
+            precision,
+            log_normalizer: 0.5 * (log_det + k as f64 * LN_2PI),
+        }
+    }
+
+    pub fn predicted(&self, position: &Vector3<f64>) -> DVector<f64> {
+        let d_ref = (position - self.reference).norm();
+        DVector::from_iterator(
+            self.anchors.len(),
+            self.anchors.iter().map(|a| (position - a).norm() - d_ref),
+        )
+    }
+
+    // Full-covariance Gaussian log density of the differences given a tag position.
+    pub fn log_likelihood(&self, position: &Vector3<f64>) -> f64 {
+        let r = &self.differences - self.predicted(position);
+        -0.5 * r.dot(&(&self.precision * &r)) - self.log_normalizer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arrival(position: Vector3<f64>, tag: &Vector3<f64>, std: f64) -> Arrival {
+        Arrival {
+            position,
+            range: (position - tag).norm(),
+            std,
+        }
+    }
+
+    #[test]
+    fn differences_share_the_reference_variance() {
+        let tag = Vector3::new(1.0, 2.0, 3.0);
+        let reference = arrival(Vector3::zeros(), &tag, 0.3);
+        let others = [
+            arrival(Vector3::new(10.0, 0.0, 0.0), &tag, 0.1),
+            arrival(Vector3::new(0.0, 10.0, 0.0), &tag, 0.2),
+        ];
+        let m = TdoaMeasurement::from_arrivals(&reference, &others);
+
+        assert!((m.covariance[(0, 0)] - 0.1).abs() <= 1e-12);
+        assert!((m.covariance[(1, 1)] - 0.13).abs() <= 1e-12);
+        assert!((m.covariance[(0, 1)] - 0.09).abs() <= 1e-12);
+        assert!((m.covariance[(1, 0)] - 0.09).abs() <= 1e-12);
+
+        // noise free differences are matched exactly at the true position
+        assert!((m.differences.clone() - m.predicted(&tag)).norm() <= 1e-12);
+        assert!(m.log_likelihood(&tag) > m.log_likelihood(&(tag + Vector3::x())));
+    }
+
+    #[test]
+    fn exact_reference_reduces_to_independent_gaussians() {
+        let tag = Vector3::new(4.0, -1.0, 2.0);
+        let reference = arrival(Vector3::zeros(), &tag, 0.0);
+        let mut others = [
+            arrival(Vector3::new(10.0, 0.0, 0.0), &tag, 0.5),
+            arrival(Vector3::new(0.0, 10.0, 0.0), &tag, 0.25),
+        ];
+        others[0].range += 0.4;
+        others[1].range -= 0.1;
+        let m = TdoaMeasurement::from_arrivals(&reference, &others);
+
+        let expected: f64 = [(0.4_f64, 0.5_f64), (-0.1, 0.25)]
+            .iter()
+            .map(|(e, s)| -0.5 * (e / s).powi(2) - s.ln() - 0.5 * LN_2PI)
+            .sum();
+        assert!((m.log_likelihood(&tag) - expected).abs() <= 1e-12);
+    }
+
+    #[test]
+    fn noiseless_arrivals_still_give_a_density() {
+        let tag = Vector3::new(4.0, -1.0, 2.0);
+        let reference = arrival(Vector3::zeros(), &tag, 0.0);
+        let others = [
+            arrival(Vector3::new(10.0, 0.0, 0.0), &tag, 0.0),
+            arrival(Vector3::new(0.0, 10.0, 0.0), &tag, 0.0),
+        ];
+        let m = TdoaMeasurement::from_arrivals(&reference, &others);
+
+        assert!(m.log_likelihood(&tag).is_finite());
+        assert!(m.log_likelihood(&tag) > m.log_likelihood(&(tag + Vector3::x())));
+    }
+}
@@ -0,0 +1,397 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use nalgebra::Vector3;
+use serde::{Deserialize, Serialize};
+
+// Ground height on a regular grid, interpolated bilinearly between cell centres.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeightGrid {
+    // Centre of the south-west cell.
+    x0: f64,
+    y0: f64,
+    cell_size: f64,
+    ncols: usize,
+    nrows: usize,
+    // Row by row from the south; NaN where there is no data.
+    heights: Vec<f64>,
+}
+
+// Relaxation of the contour grid stops once no cell moves more than this (m) in a sweep.
+const RELAXATION_TOLERANCE: f64 = 1e-4;
+const MAX_RELAXATION_SWEEPS: usize = 20_000;
+
+impl HeightGrid {
+    pub fn new(
+        south_west: [f64; 2],
+        cell_size: f64,
+        ncols: usize,
+        nrows: usize,
+        heights: Vec<f64>,
+    ) -> Self {
+        assert!(cell_size > 0.0, "HeightGrid: cell_size must be > 0");
+        assert!(
+            ncols > 0 && nrows > 0,
+            "HeightGrid: expected at least one cell"
+        );
+        assert_eq!(
+            heights.len(),
+            ncols * nrows,
+            "HeightGrid: expected ncols * nrows heights"
+        );
+        Self {
+            x0: south_west[0],
+            y0: south_west[1],
+            cell_size,
+            ncols,
+            nrows,
+            heights,
+        }
+    }
+
+    // Interpolate contour lines into a grid covering them. Cells crossed by a contour
+    // take its height; the cells in between are filled by relaxing Laplace's
+    // equation, which bends smoothly from one contour to the next.
+    pub fn from_contours(contours: &[Vec<[f64; 3]>], cell_size: f64) -> Self {
+        assert!(cell_size > 0.0, "HeightGrid: cell_size must be > 0");
+        let points: Vec<[f64; 3]> = contours
+            .iter()
+            .flat_map(|c| densify(c, cell_size))
+            .collect();
+        assert!(!points.is_empty(), "HeightGrid: expected contour points");
+
+        let (mut min_x, mut min_y) = (f64::INFINITY, f64::INFINITY);
+        let (mut max_x, mut max_y) = (f64::NEG_INFINITY, f64::NEG_INFINITY);
+        for &[x, y, _] in &points {
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+        }
+        let ncols = ((max_x - min_x) / cell_size).round() as usize + 1;
+        let nrows = ((max_y - min_y) / cell_size).round() as usize + 1;
+
+        let mut sums = vec![(0.0, 0usize); ncols * nrows];
+        for &[x, y, z] in &points {
+            let col = (((x - min_x) / cell_size).round() as usize).min(ncols - 1);
+            let row = (((y - min_y) / cell_size).round() as usize).min(nrows - 1);
+            let (sum, count) = &mut sums[row * ncols + col];
+            *sum += z;
+            *count += 1;
+        }
+        let fixed: Vec<bool> = sums.iter().map(|&(_, count)| count > 0).collect();
+        let mean = points.iter().map(|p| p[2]).sum::<f64>() / points.len() as f64;
+        let mut heights: Vec<f64> = sums
+            .iter()
+            .map(|&(sum, count)| if count > 0 { sum / count as f64 } else { mean })
+            .collect();
+        relax(&mut heights, &fixed, ncols, nrows);
+
+        Self::new([min_x, min_y], cell_size, ncols, nrows, heights)
+    }
+
+    // An ESRI ASCII grid: a header of `ncols`, `nrows`, `xllcorner` or `xllcenter`,
+    // `yllcorner` or `yllcenter`, `cellsize` and an optional `nodata_value`, then the
+    // heights row by row from the north.
+    pub fn from_ascii_grid(text: &str) -> Result<Self, Box<dyn Error>> {
+        let mut tokens = text.split_whitespace().peekable();
+        let mut header = std::collections::HashMap::new();
+        while let Some(key) = tokens.next_if(|t| t.starts_with(|c: char| c.is_ascii_alphabetic())) {
+            let value: f64 = tokens
+                .next()
+                .ok_or_else(|| format!("HeightGrid: no value for {key}"))?
+                .parse()
+                .map_err(|e| format!("HeightGrid: bad value for {key}: {e}"))?;
+            header.insert(key.to_ascii_lowercase(), value);
+        }
+        let get = |key: &str| {
+            header
+                .get(key)
+                .copied()
+                .ok_or_else(|| format!("HeightGrid: missing {key}"))
+        };
+
+        let ncols = get("ncols")? as usize;
+        let nrows = get("nrows")? as usize;
+        let cell_size = get("cellsize")?;
+        if ncols == 0 || nrows == 0 || cell_size.is_nan() || cell_size <= 0.0 {
+            return Err("HeightGrid: expected cells of a positive size".into());
+        }
+        // corners are moved to the centre of the south-west cell
+        let centre = |axis: &str| match (
+            header.get(&format!("{axis}llcenter")),
+            header.get(&format!("{axis}llcorner")),
+        ) {
+            (Some(&c), _) => Ok(c),
+            (None, Some(&c)) => Ok(c + 0.5 * cell_size),
+            _ => Err(format!("HeightGrid: missing {axis}llcorner")),
+        };
+        let south_west = [centre("x")?, centre("y")?];
+        let nodata = header.get("nodata_value").copied();
+
+        let values = tokens
+            .map(|t| {
+                t.parse::<f64>()
+                    .map_err(|e| format!("HeightGrid: bad height {t:?}: {e}"))
+            })
+            .collect::<Result<Vec<f64>, _>>()?;
+        if values.len() != ncols * nrows {
+            return Err(format!(
+                "HeightGrid: expected {} heights, found {}",
+                ncols * nrows,
+                values.len()
+            )
+            .into());
+        }
+        let heights = values
+            .chunks(ncols)
+            .rev()
+            .flatten()
+            .map(|&h| if Some(h) == nodata { f64::NAN } else { h })
+            .collect();
+        Ok(Self::new(south_west, cell_size, ncols, nrows, heights))
+    }
+
+    pub fn read_ascii_grid(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("HeightGrid: cannot read {}: {e}", path.display()))?;
+        Self::from_ascii_grid(&text)
+    }
+
+    // The grid in a frame whose origin lies at `origin` in the grid's coordinates.
+    pub fn shifted(mut self, origin: Vector3<f64>) -> Self {
+        self.x0 -= origin.x;
+        self.y0 -= origin.y;
+        for h in &mut self.heights {
+            *h -= origin.z;
+        }
+        self
+    }
+
+    pub fn cell_size(&self) -> f64 {
+        self.cell_size
+    }
+
+    // (ncols, nrows)
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.ncols, self.nrows)
+    }
+
+    // `None` off the grid or where there is no data around the point.
+    pub fn height_at(&self, x: f64, y: f64) -> Option<f64> {
+        let u = (x - self.x0) / self.cell_size;
+        let v = (y - self.y0) / self.cell_size;
+        let (w, h) = (self.ncols as f64, self.nrows as f64);
+        if !(u >= -0.5 && u <= w - 0.5 && v >= -0.5 && v <= h - 0.5) {
+            return None;
+        }
+        // the outer half cells take the edge heights
+        let u = u.clamp(0.0, w - 1.0);
+        let v = v.clamp(0.0, h - 1.0);
+        let (c0, r0) = (u.floor() as usize, v.floor() as usize);
+        let (c1, r1) = ((c0 + 1).min(self.ncols - 1), (r0 + 1).min(self.nrows - 1));
+        let (fu, fv) = (u - c0 as f64, v - r0 as f64);
+
+        let corners = [
+            (self.height(c0, r0), (1.0 - fu) * (1.0 - fv)),
+            (self.height(c1, r0), fu * (1.0 - fv)),
+            (self.height(c0, r1), (1.0 - fu) * fv),
+            (self.height(c1, r1), fu * fv),
+        ];
+        // missing corners drop out and the others are reweighted
+        let (sum, weight) = corners
+            .iter()
+            .filter(|(h, _)| !h.is_nan())
+            .fold((0.0, 0.0), |(s, t), (h, w)| (s + h * w, t + w));
+        if weight > 0.0 {
+            Some(sum / weight)
+        } else {
+            corners.iter().map(|(h, _)| *h).find(|h| !h.is_nan())
+        }
+    }
+
+    // West to east profiles along every `stride`-th row, broken at missing data.
+    pub fn rows(&self, stride: usize) -> Vec<Vec<[f64; 3]>> {
+        let mut profiles = Vec::new();
+        for row in (0..self.nrows).step_by(stride.max(1)) {
+            let y = self.y0 + row as f64 * self.cell_size;
+            let mut profile = Vec::new();
+            for col in 0..self.ncols {
+                let h = self.height(col, row);
+                if h.is_nan() {
+                    if profile.len() > 1 {
+                        profiles.push(std::mem::take(&mut profile));
+                    }
+                    profile.clear();
+                } else {
+                    profile.push([self.x0 + col as f64 * self.cell_size, y, h]);
+                }
+            }
+            if profile.len() > 1 {
+                profiles.push(profile);
+            }
+        }
+        profiles
+    }
+
+    fn height(&self, col: usize, row: usize) -> f64 {
+        self.heights[row * self.ncols + col]
+    }
+}
+
+// The vertices of a contour and enough points between them that every cell it
+// crosses gets one.
+fn densify(contour: &[[f64; 3]], cell_size: f64) -> Vec<[f64; 3]> {
+    let mut points: Vec<[f64; 3]> = contour.first().copied().into_iter().collect();
+    for pair in contour.windows(2) {
+        let (a, b) = (Vector3::from(pair[0]), Vector3::from(pair[1]));
+        let steps = ((b - a).xy().norm() / (0.5 * cell_size)).ceil().max(1.0) as usize;
+        points.extend(
+            (1..=steps).map(|i| -> [f64; 3] { (a + (b - a) * (i as f64 / steps as f64)).into() }),
+        );
+    }
+    points
+}
+
+// Successive over-relaxation of the free cells towards the mean of their
+// neighbours; the grid edges are left open.
+fn relax(heights: &mut [f64], fixed: &[bool], ncols: usize, nrows: usize) {
+    const OMEGA: f64 = 1.8;
+    for _ in 0..MAX_RELAXATION_SWEEPS {
+        let mut largest_change: f64 = 0.0;
+        for row in 0..nrows {
+            for col in 0..ncols {
+                let i = row * ncols + col;
+                if fixed[i] {
+                    continue;
+                }
+                let mut sum = 0.0;
+                let mut count = 0.0;
+                if col > 0 {
+                    sum += heights[i - 1];
+                    count += 1.0;
+                }
+                if col + 1 < ncols {
+                    sum += heights[i + 1];
+                    count += 1.0;
+                }
+                if row > 0 {
+                    sum += heights[i - ncols];
+                    count += 1.0;
+                }
+                if row + 1 < nrows {
+                    sum += heights[i + ncols];
+                    count += 1.0;
+                }
+                if count > 0.0 {
+                    let change = OMEGA * (sum / count - heights[i]);
+                    heights[i] += change;
+                    largest_change = largest_change.max(change.abs());
+                }
+            }
+        }
+        if largest_change < RELAXATION_TOLERANCE {
+            break;
+        }
+    }
+}
+
+// What happens to particles that end up below ground.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BelowGround {
+    // Their weight drops to zero, unless all of them are below ground.
+    #[default]
+    ZeroWeight,
+    // They are mirrored in the ground, vertical velocity included.
+    Reflect,
+}
+
+// Gaussian belief about the height above ground level (m).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AglPrior {
+    pub mean: f64,
+    pub std: f64,
+}
+
+// Ground knowledge for a particle filter, applied after every prediction.
+// Particles off the grid are left alone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TerrainConstraint {
+    pub ground: Arc<HeightGrid>,
+    pub below_ground: BelowGround,
+    pub agl_prior: Option<AglPrior>,
+}
+
+impl TerrainConstraint {
+    pub fn new(ground: Arc<HeightGrid>, below_ground: BelowGround) -> Self {
+        Self {
+            ground,
+            below_ground,
+            agl_prior: None,
+        }
+    }
+
+    pub fn with_agl_prior(mut self, prior: AglPrior) -> Self {
+        assert!(prior.std > 0.0, "TerrainConstraint: agl std must be > 0");
+        self.agl_prior = Some(prior);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_height_is_interpolated_between_cell_centres() {
+        // a slope rising 1 m per metre eastwards, one missing cell
+        let grid = HeightGrid::new(
+            [0.0, 0.0],
+            1.0,
+            3,
+            2,
+            vec![0.0, 1.0, 2.0, 0.0, 1.0, f64::NAN],
+        );
+        assert_eq!(grid.height_at(0.5, 0.0), Some(0.5));
+        assert_eq!(grid.height_at(-0.4, 0.5), Some(0.0));
+        assert_eq!(grid.height_at(2.0, 0.5), Some(2.0));
+        assert_eq!(grid.height_at(2.0, 1.0), None);
+        assert_eq!(grid.height_at(3.0, 0.0), None);
+        assert_eq!(grid.rows(1)[1], vec![[0.0, 1.0, 0.0], [1.0, 1.0, 1.0]]);
+    }
+
+    #[test]
+    fn test_contours_fill_the_ground_between_them() {
+        // contour lines at 0 m and 10 m, 10 m apart
+        let low: Vec<[f64; 3]> = vec![[0.0, 0.0, 0.0], [0.0, 20.0, 0.0]];
+        let high: Vec<[f64; 3]> = vec![[10.0, 0.0, 10.0], [10.0, 20.0, 10.0]];
+        let grid = HeightGrid::from_contours(&[low, high], 1.0);
+
+        assert_eq!(grid.dimensions(), (11, 21));
+        assert!((grid.height_at(0.0, 7.0).unwrap()).abs() < 1e-9);
+        let middle = grid.height_at(5.0, 10.0).unwrap();
+        assert!((middle - 5.0).abs() < 0.01, "{middle}");
+    }
+
+    #[test]
+    fn test_ascii_grid_rows_run_from_the_north() {
+        let text = "ncols 3\nNROWS 2\nxllcorner 100.0\nyllcorner 200.0\ncellsize 10\n\
+                    NODATA_value -9999\n1 2 3\n4 5 -9999\n";
+        let grid = HeightGrid::from_ascii_grid(text)
+            .unwrap()
+            .shifted(Vector3::new(100.0, 200.0, 1.0));
+        assert_eq!(grid.height_at(5.0, 5.0), Some(3.0));
+        assert_eq!(grid.height_at(5.0, 15.0), Some(0.0));
+        assert_eq!(grid.height_at(15.0, 5.0), Some(4.0));
+
+        assert!(HeightGrid::from_ascii_grid("ncols 2\nnrows 1\nxllcorner 0\n1 2").is_err());
+        assert!(HeightGrid::from_ascii_grid(
+            "ncols 2\nnrows 1\nxllcenter 0\nyllcenter 0\ncellsize 1\n1"
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,205 @@
+use rand::Rng;
+use rand_distr::{Distribution, Normal};
+use serde::{Deserialize, Serialize};
+
+use crate::clock::SPEED_OF_LIGHT;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TwrProtocol {
+    // poll -> response, the initiator measures the round trip.
+    #[default]
+    SingleSided,
+    // poll -> response -> final, asymmetric double-sided formula.
+    DoubleSided,
+}
+
+// A two-way ranging exchange between a tag (initiator) and an anchor (responder).
+// Times are in seconds, crystal offsets in parts per million. Antenna delays are the
+// uncalibrated part of each device's tx + rx delay and add to every flight.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TwrConfig {
+    pub protocol: TwrProtocol,
+    pub reply_delay: f64,
+    pub initiator_ppm: f64,
+    pub responder_ppm: f64,
+    pub initiator_antenna_delay: f64,
+    pub responder_antenna_delay: f64,
+    pub timestamp_std: f64,
+}
+
+impl Default for TwrConfig {
+    // Typical UWB radio: 1 ms turnaround and ~15 ps timestamp resolution.
+    fn default() -> Self {
+        Self {
+            protocol: TwrProtocol::default(),
+            reply_delay: 1e-3,
+            initiator_ppm: 0.0,
+            responder_ppm: 0.0,
+            initiator_antenna_delay: 0.0,
+            responder_antenna_delay: 0.0,
+            timestamp_std: 15e-12,
+        }
+    }
+}
+
+impl TwrConfig {
+    pub fn new(protocol: TwrProtocol, reply_delay: f64) -> Self {
+        assert!(reply_delay > 0.0, "TwrConfig: reply_delay must be > 0");
+        Self {
+            protocol,
+            reply_delay,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_ppm(mut self, initiator_ppm: f64, responder_ppm: f64) -> Self {
+        self.initiator_ppm = initiator_ppm;
+        self.responder_ppm = responder_ppm;
+        self
+    }
+
+    pub fn with_antenna_delays(mut self, initiator: f64, responder: f64) -> Self {
+        self.initiator_antenna_delay = initiator;
+        self.responder_antenna_delay = responder;
+        self
+    }
+
+    pub fn with_timestamp_std(mut self, timestamp_std: f64) -> Self {
+        assert!(
+            timestamp_std >= 0.0,
+            "TwrConfig: timestamp_std must be >= 0"
+        );
+        self.timestamp_std = timestamp_std;
+        self
+    }
+
+    // The values the builders assert on, for configs that bypass them.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if !(self.reply_delay.is_finite() && self.reply_delay > 0.0) {
+            return Err("TwrConfig: reply_delay must be > 0");
+        }
+        if !(self.timestamp_std.is_finite() && self.timestamp_std >= 0.0) {
+            return Err("TwrConfig: timestamp_std must be >= 0");
+        }
+        Ok(())
+    }
+
+    // Run one exchange over `distance` metres and return the distance the radio reports.
+    pub fn measure_distance<R: Rng + ?Sized>(&self, distance: f64, rng: &mut R) -> f64 {
+        let noise = Normal::new(0.0, self.timestamp_std).expect("TwrConfig: invalid timestamp_std");
+        let mut stamp = |t: f64| t + noise.sample(rng);
+
+        // each device timestamps with its own drifting crystal
+        let k_i = 1.0 + self.initiator_ppm * 1e-6;
+        let k_r = 1.0 + self.responder_ppm * 1e-6;
+        let tof =
+            distance / SPEED_OF_LIGHT + self.initiator_antenna_delay + self.responder_antenna_delay;
+
+        // poll
+        let t1 = 0.0;
+        let t2 = t1 + tof;
+        // response, scheduled reply_delay later on the responder clock
+        let t3 = t2 + self.reply_delay / k_r;
+        let t4 = t3 + tof;
+
+        let (i1, r2) = (stamp(t1 * k_i), stamp(t2 * k_r));
+        let (r3, i4) = (stamp(t3 * k_r), stamp(t4 * k_i));
+        let round1 = i4 - i1;
+        let reply1 = r3 - r2;
+
+        let tof_est = match self.protocol {
+            TwrProtocol::SingleSided => 0.5 * (round1 - reply1),
+            TwrProtocol::DoubleSided => {
+                // final, scheduled reply_delay later on the initiator clock
+                let t5 = t4 + self.reply_delay / k_i;
+                let t6 = t5 + tof;
+                let (i5, r6) = (stamp(t5 * k_i), stamp(t6 * k_r));
+                let round2 = r6 - r3;
+                let reply2 = i5 - i4;
+                (round1 * round2 - reply1 * reply2) / (round1 + round2 + reply1 + reply2)
+            }
+        };
+        tof_est * SPEED_OF_LIGHT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn mean_distance(config: &TwrConfig, distance: f64) -> f64 {
+        let mut rng = StdRng::seed_from_u64(11);
+        let n = 20_000;
+        (0..n)
+            .map(|_| config.measure_distance(distance, &mut rng))
+            .sum::<f64>()
+            / n as f64
+    }
+
+    #[test]
+    fn ideal_radios_report_true_distance() {
+        for protocol in [TwrProtocol::SingleSided, TwrProtocol::DoubleSided] {
+            let config = TwrConfig::new(protocol, 1e-3).with_timestamp_std(0.0);
+            let mut rng = StdRng::seed_from_u64(1);
+            let d = config.measure_distance(42.0, &mut rng);
+            assert!((d - 42.0).abs() <= 1e-6, "{protocol:?}: {d}");
+        }
+    }
+
+    #[test]
+    fn single_sided_bias_grows_with_crystal_offset() {
+        let config = TwrConfig::new(TwrProtocol::SingleSided, 1e-3)
+            .with_ppm(10.0, -10.0)
+            .with_timestamp_std(0.0);
+        let mut rng = StdRng::seed_from_u64(2);
+        let d = config.measure_distance(20.0, &mut rng);
+        // about reply_delay * (e_i - e_r) / 2 of extra flight time
+        let expected_bias = 0.5 * 1e-3 * 20e-6 * SPEED_OF_LIGHT;
+        assert!(((d - 20.0) - expected_bias).abs() <= 0.01, "got {d}");
+    }
+
+    #[test]
+    fn double_sided_cancels_crystal_offset() {
+        let config = TwrConfig::new(TwrProtocol::DoubleSided, 1e-3)
+            .with_ppm(10.0, -10.0)
+            .with_timestamp_std(0.0);
+        let mut rng = StdRng::seed_from_u64(3);
+        let d = config.measure_distance(20.0, &mut rng);
+        assert!((d - 20.0).abs() <= 1e-3, "got {d}");
+    }
+
+    #[test]
+    fn antenna_delays_bias_every_protocol() {
+        let delay = 1e-9;
+        for protocol in [TwrProtocol::SingleSided, TwrProtocol::DoubleSided] {
+            let config = TwrConfig::new(protocol, 1e-3).with_antenna_delays(delay, delay);
+            let bias = mean_distance(&config, 10.0) - 10.0;
+            let expected = 2.0 * delay * SPEED_OF_LIGHT;
+            assert!((bias - expected).abs() <= 0.01, "{protocol:?}: bias {bias}");
+        }
+    }
+
+    #[test]
+    fn validate_rejects_what_the_builders_assert_on() {
+        assert_eq!(TwrConfig::default().validate(), Ok(()));
+        let no_turnaround = TwrConfig {
+            reply_delay: 0.0,
+            ..Default::default()
+        };
+        assert!(no_turnaround.validate().is_err());
+        let negative_noise = TwrConfig {
+            timestamp_std: -1e-12,
+            ..Default::default()
+        };
+        assert!(negative_noise.validate().is_err());
+    }
+
+    #[test]
+    fn timestamp_noise_is_unbiased() {
+        let config = TwrConfig::new(TwrProtocol::DoubleSided, 1e-3).with_timestamp_std(100e-12);
+        assert!((mean_distance(&config, 10.0) - 10.0).abs() <= 0.01);
+    }
+}
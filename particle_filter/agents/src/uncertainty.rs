@@ -0,0 +1,111 @@
+use nalgebra::{Matrix3, Rotation3, UnitQuaternion, Vector3};
+
+use crate::stats::chi_squared_quantile;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CredibleEllipsoid {
+    pub center: Vector3<f64>,
+    // Semi-axis lengths, ordered to match the columns of `rotation`.
+    pub semi_axes: Vector3<f64>,
+    pub rotation: Rotation3<f64>,
+    pub level: f64,
+}
+
+impl CredibleEllipsoid {
+    // Region {x : (x - c)^T C^-1 (x - c) <= chi2_3(level)} of a Gaussian with covariance C.
+    pub fn from_covariance(center: Vector3<f64>, covariance: &Matrix3<f64>, level: f64) -> Self {
+        assert!(
+            level > 0.0 && level < 1.0,
+            "CredibleEllipsoid: level must be in (0, 1)"
+        );
+        let scale = chi_squared_quantile(level, 3);
+
+        let sym = 0.5 * (covariance + covariance.transpose());
+        let eigen = sym.symmetric_eigen();
+        let semi_axes = eigen.eigenvalues.map(|l| (l.max(0.0) * scale).sqrt());
+
+        // keep the axes a right-handed frame so they form a proper rotation
+        let mut axes = eigen.eigenvectors;
+        if axes.determinant() < 0.0 {
+            axes.set_column(2, &(-axes.column(2)));
+        }
+        let rotation = Rotation3::from_matrix_unchecked(axes);
+
+        Self {
+            center,
+            semi_axes,
+            rotation,
+            level,
+        }
+    }
+
+    pub fn orientation(&self) -> UnitQuaternion<f64> {
+        UnitQuaternion::from_rotation_matrix(&self.rotation)
+    }
+
+    pub fn contains(&self, point: &Vector3<f64>) -> bool {
+        let local = self.rotation.inverse() * (point - self.center);
+        let mut d = 0.0;
+        for i in 0..3 {
+            let a = self.semi_axes[i];
+            if a <= 0.0 {
+                if local[i].abs() > 1e-12 {
+                    return false;
+                }
+            } else {
+                d += (local[i] / a).powi(2);
+            }
+        }
+        d <= 1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn axis_aligned_covariance_gives_axis_aligned_ellipsoid() {
+        let center = Vector3::new(1.0, 2.0, 3.0);
+        let covariance = Matrix3::from_diagonal(&Vector3::new(4.0, 1.0, 9.0));
+        let level = 0.95;
+
+        let ellipsoid = CredibleEllipsoid::from_covariance(center, &covariance, level);
+
+        let scale = chi_squared_quantile(level, 3).sqrt();
+        let mut got: Vec<f64> = ellipsoid.semi_axes.iter().copied().collect();
+        got.sort_by(f64::total_cmp);
+        let expected = [1.0 * scale, 2.0 * scale, 3.0 * scale];
+        for (g, e) in got.iter().zip(expected.iter()) {
+            assert!((g - e).abs() <= 1e-9, "got {g}, expected {e}");
+        }
+        assert!((ellipsoid.rotation.matrix().determinant() - 1.0).abs() <= 1e-9);
+
+        assert!(ellipsoid.contains(&center));
+        assert!(ellipsoid.contains(&(center + Vector3::new(0.0, 0.0, 2.9 * scale))));
+        assert!(!ellipsoid.contains(&(center + Vector3::new(0.0, 1.1 * scale, 0.0))));
+    }
+
+    #[test]
+    fn rotated_covariance_recovers_orientation() {
+        let rotation = Rotation3::from_euler_angles(0.3, -0.2, 1.1);
+        let local = Matrix3::from_diagonal(&Vector3::new(1.0, 4.0, 16.0));
+        let covariance = rotation.matrix() * local * rotation.matrix().transpose();
+
+        let ellipsoid = CredibleEllipsoid::from_covariance(Vector3::zeros(), &covariance, 0.9);
+
+        // the longest axis must point along the rotated local z axis
+        let (imax, _) = ellipsoid
+            .semi_axes
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .unwrap();
+        let axis = ellipsoid.rotation.matrix().column(imax).into_owned();
+        let expected = rotation * Vector3::z();
+        assert!((axis.dot(&expected).abs() - 1.0).abs() <= 1e-9);
+
+        let q = ellipsoid.orientation();
+        assert!(((q * Vector3::x()).norm() - 1.0).abs() <= 1e-12);
+    }
+}
@@ -0,0 +1,111 @@
+// Stands in for the UWB radios: simulates a scenario and streams its anchor ranges
+// as range reports to a `particle_filter listen` process.
+//
+//   cargo run -- listen scenarios/default.toml --udp 127.0.0.1:9000
+//   cargo run --example fake_radio -- scenarios/default.toml --to 127.0.0.1:9000
+
+use std::error::Error;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use clap::Parser;
+
+use simulation::live::RangeReport;
+use simulation::recording::Record;
+use simulation::scenario::Scenario;
+
+#[derive(Debug, Parser)]
+#[command(about = "Stream simulated range reports to a listening filter")]
+struct Args {
+    scenario: PathBuf,
+    /// Address of the listening filter
+    #[arg(long, default_value = "127.0.0.1:9000")]
+    to: SocketAddr,
+    /// Connect over TCP instead of sending UDP datagrams
+    #[arg(long)]
+    tcp: bool,
+    /// Override the number of steps in the scenario
+    #[arg(long)]
+    steps: Option<usize>,
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Send as fast as possible instead of in real time
+    #[arg(long)]
+    fast: bool,
+}
+
+enum Radio {
+    Udp(UdpSocket, SocketAddr),
+    Tcp(TcpStream),
+}
+
+impl Radio {
+    fn send(&mut self, line: &str) -> std::io::Result<()> {
+        match self {
+            Radio::Udp(socket, to) => socket.send_to(line.as_bytes(), *to).map(|_| ()),
+            Radio::Tcp(stream) => writeln!(stream, "{line}"),
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    let mut scenario = Scenario::from_path(&args.scenario)?;
+    scenario.visualization.enabled = false;
+    scenario.seed = args.seed.or(scenario.seed);
+    let steps = args.steps.unwrap_or(scenario.steps);
+    let mut sim = scenario.simulation_builder()?.build();
+    let names: Vec<String> = sim
+        .swarm_elements
+        .iter()
+        .map(|se| se.name.clone())
+        .collect();
+
+    let mut radio = if args.tcp {
+        Radio::Tcp(TcpStream::connect(args.to)?)
+    } else {
+        Radio::Udp(UdpSocket::bind("0.0.0.0:0")?, args.to)
+    };
+
+    for frame in 0..steps {
+        let records = sim.step(frame, scenario.step_size);
+        for record in &records {
+            match *record {
+                Record::Range {
+                    agent,
+                    anchor,
+                    range,
+                    ..
+                } => {
+                    let report = RangeReport {
+                        anchor,
+                        tag: names[agent].clone(),
+                        range,
+                        timestamp: sim.time(),
+                        quality: None,
+                    };
+                    radio.send(&report.to_line())?;
+                }
+                // printed so the published estimates can be checked against it
+                Record::Truth {
+                    agent, position, ..
+                } => println!(
+                    "{:.2} {} {:.3} {:.3} {:.3}",
+                    sim.time(),
+                    names[agent],
+                    position.x,
+                    position.y,
+                    position.z
+                ),
+                _ => {}
+            }
+        }
+        if !args.fast {
+            thread::sleep(Duration::from_secs_f64(scenario.step_size));
+        }
+    }
+    Ok(())
+}
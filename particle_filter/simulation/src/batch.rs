@@ -0,0 +1,430 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+use std::time::Instant;
+
+use rayon::prelude::*;
+use serde::Serialize;
+
+use agents::{dynamics_model::DynamicsModel, rng::RngStream};
+
+use crate::scenario::Scenario;
+use crate::simulation::Simulation;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatchConfig {
+    pub runs: usize,
+    pub steps: usize,
+    pub step_size: f64,
+    // Every run is seeded from a stream split off this seed.
+    pub seed: u64,
+    pub parallel: bool,
+    // A track whose final error (m) exceeds this, or is not finite, has diverged.
+    pub divergence_threshold: f64,
+}
+
+impl BatchConfig {
+    pub fn new(runs: usize, steps: usize, step_size: f64, seed: u64) -> Self {
+        assert!(runs > 0, "BatchConfig: runs must be > 0");
+        Self {
+            runs,
+            steps,
+            step_size,
+            seed,
+            parallel: true,
+            divergence_threshold: 10.0,
+        }
+    }
+
+    pub fn with_parallel(mut self, parallel: bool) -> Self {
+        self.parallel = parallel;
+        self
+    }
+
+    pub fn with_divergence_threshold(mut self, divergence_threshold: f64) -> Self {
+        assert!(
+            divergence_threshold > 0.0,
+            "BatchConfig: divergence_threshold must be > 0"
+        );
+        self.divergence_threshold = divergence_threshold;
+        self
+    }
+
+    pub fn run_seed(&self, run: usize) -> u64 {
+        RngStream::new(self.seed).substream(run as u64).seed()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ErrorStats {
+    pub rmse: f64,
+    pub mean: f64,
+    pub median: f64,
+    pub p95: f64,
+    // Median horizontal (xy) error.
+    pub cep50: f64,
+    // Median 3-D error.
+    pub sep50: f64,
+}
+
+impl ErrorStats {
+    pub fn from_samples(errors: &[f64], horizontal_errors: &[f64]) -> Self {
+        let by_size = sorted(errors);
+        Self {
+            rmse: rmse(errors),
+            mean: errors.iter().sum::<f64>() / errors.len().max(1) as f64,
+            median: quantile(&by_size, 0.5),
+            p95: quantile(&by_size, 0.95),
+            cep50: quantile(&sorted(horizontal_errors), 0.5),
+            sep50: quantile(&by_size, 0.5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RunReport {
+    pub run: usize,
+    pub seed: u64,
+    pub rmse: f64,
+    // Final error averaged over the run's swarm elements.
+    pub final_error: f64,
+    pub diverged_tracks: usize,
+    pub runtime_s: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BatchReport {
+    pub runs: Vec<RunReport>,
+    pub steps: usize,
+    pub step_size: f64,
+    pub tracks_per_run: usize,
+    // RMSE over all runs and elements, one entry per time step.
+    pub step_rmse: Vec<f64>,
+    pub overall: ErrorStats,
+    // Share of (run, element) tracks that diverged.
+    pub divergence_rate: f64,
+    pub total_runtime_s: f64,
+}
+
+// Errors of one run, indexed [step][element].
+struct RunErrors {
+    errors: Vec<Vec<f64>>,
+    horizontal_errors: Vec<Vec<f64>>,
+    runtime_s: f64,
+}
+
+// Errors from the workers, which may run on other threads.
+pub type RunError = Box<dyn Error + Send + Sync>;
+
+// Run `config.runs` independently seeded simulations made by `make_simulation`. The
+// first run that cannot be made fails the batch.
+pub fn run_batch<M, F>(config: &BatchConfig, make_simulation: F) -> Result<BatchReport, RunError>
+where
+    M: DynamicsModel + Sync,
+    F: Fn(u64) -> Result<Simulation<M>, RunError> + Sync,
+{
+    let start = Instant::now();
+    let run = |run: usize| run_errors(config, config.run_seed(run), &make_simulation);
+    let results: Vec<RunErrors> = if config.parallel {
+        (0..config.runs)
+            .into_par_iter()
+            .map(run)
+            .collect::<Result<_, _>>()?
+    } else {
+        (0..config.runs).map(run).collect::<Result<_, _>>()?
+    };
+    let total_runtime_s = start.elapsed().as_secs_f64();
+
+    let tracks_per_run = results
+        .first()
+        .and_then(|r| r.errors.first())
+        .map_or(0, Vec::len);
+
+    let step_rmse = (0..config.steps)
+        .map(|k| {
+            let step: Vec<f64> = results.iter().flat_map(|r| r.errors[k].clone()).collect();
+            rmse(&step)
+        })
+        .collect();
+
+    let all_errors: Vec<f64> = results.iter().flat_map(|r| r.errors.concat()).collect();
+    let all_horizontal: Vec<f64> = results
+        .iter()
+        .flat_map(|r| r.horizontal_errors.concat())
+        .collect();
+
+    let runs: Vec<RunReport> = results
+        .iter()
+        .enumerate()
+        .map(|(i, r)| {
+            let errors = r.errors.concat();
+            let final_errors = r.errors.last().cloned().unwrap_or_default();
+            RunReport {
+                run: i,
+                seed: config.run_seed(i),
+                rmse: rmse(&errors),
+                final_error: final_errors.iter().sum::<f64>() / final_errors.len().max(1) as f64,
+                diverged_tracks: final_errors
+                    .iter()
+                    .filter(|e| !(e.is_finite() && **e <= config.divergence_threshold))
+                    .count(),
+                runtime_s: r.runtime_s,
+            }
+        })
+        .collect();
+
+    let diverged: usize = runs.iter().map(|r| r.diverged_tracks).sum();
+    let tracks = (runs.len() * tracks_per_run).max(1);
+
+    Ok(BatchReport {
+        runs,
+        steps: config.steps,
+        step_size: config.step_size,
+        tracks_per_run,
+        step_rmse,
+        overall: ErrorStats::from_samples(&all_errors, &all_horizontal),
+        divergence_rate: diverged as f64 / tracks as f64,
+        total_runtime_s,
+    })
+}
+
+// A batch of headless runs of `scenario`, each with the scenario's seed replaced by
+// the run seed. A requested `.rrd` recording or trace is written for every run.
+pub fn run_scenario_batch(
+    scenario: &Scenario,
+    config: &BatchConfig,
+) -> Result<BatchReport, Box<dyn Error>> {
+    // fail here rather than inside a worker, e.g. on a missing terrain file
+    scenario.simulation_builder()?;
+    let mut visualization = scenario.visualization.clone();
+    visualization.enabled = false;
+    let report = run_batch(config, |seed| {
+        let mut scenario = scenario.clone();
+        scenario.seed = Some(seed);
+        let run_error = |e: Box<dyn Error>| RunError::from(format!("run {seed}: {e}"));
+        let mut builder = scenario.simulation_builder().map_err(run_error)?;
+        for visualizer in visualization.visualizers(Some(seed)).map_err(run_error)? {
+            builder = builder.visualizer(visualizer);
+        }
+        Ok(builder.build())
+    });
+    report.map_err(|e| e as Box<dyn Error>)
+}
+
+fn run_errors<M, F>(
+    config: &BatchConfig,
+    seed: u64,
+    make_simulation: &F,
+) -> Result<RunErrors, RunError>
+where
+    M: DynamicsModel + Sync,
+    F: Fn(u64) -> Result<Simulation<M>, RunError>,
+{
+    let start = Instant::now();
+    let mut sim = make_simulation(seed)?;
+    let mut errors = Vec::with_capacity(config.steps);
+    let mut horizontal_errors = Vec::with_capacity(config.steps);
+    for frame in 0..config.steps {
+        sim.step(frame, config.step_size);
+        errors.push(
+            sim.swarm_elements
+                .iter()
+                .map(|se| se.estimation_error())
+                .collect(),
+        );
+        horizontal_errors.push(
+            sim.swarm_elements
+                .iter()
+                .map(|se| (se.dynamics_model.position() - se.est_position).xy().norm())
+                .collect(),
+        );
+    }
+    sim.finish()
+        .map_err(|e| format!("run {seed}: cannot finish the visualizers: {e}"))?;
+    Ok(RunErrors {
+        errors,
+        horizontal_errors,
+        runtime_s: start.elapsed().as_secs_f64(),
+    })
+}
+
+fn rmse(samples: &[f64]) -> f64 {
+    (samples.iter().map(|e| e * e).sum::<f64>() / samples.len().max(1) as f64).sqrt()
+}
+
+fn sorted(samples: &[f64]) -> Vec<f64> {
+    let mut sorted = samples.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    sorted
+}
+
+// Linearly interpolated quantile of already sorted samples.
+fn quantile(sorted: &[f64], q: f64) -> f64 {
+    match sorted.len() {
+        0 => f64::NAN,
+        n => {
+            let pos = q * (n - 1) as f64;
+            let lo = pos.floor() as usize;
+            let hi = pos.ceil() as usize;
+            sorted[lo] + (sorted[hi] - sorted[lo]) * (pos - lo as f64)
+        }
+    }
+}
+
+impl BatchReport {
+    pub fn mean_runtime_s(&self) -> f64 {
+        self.runs.iter().map(|r| r.runtime_s).sum::<f64>() / self.runs.len().max(1) as f64
+    }
+
+    pub fn write_runs_csv<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "run,seed,rmse,final_error,diverged_tracks,runtime_s")?;
+        for r in &self.runs {
+            writeln!(
+                out,
+                "{},{},{},{},{},{}",
+                r.run, r.seed, r.rmse, r.final_error, r.diverged_tracks, r.runtime_s
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn write_steps_csv<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "step,time,rmse")?;
+        for (k, rmse) in self.step_rmse.iter().enumerate() {
+            writeln!(out, "{k},{},{rmse}", (k + 1) as f64 * self.step_size)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_json<W: Write>(&self, out: W) -> serde_json::Result<()> {
+        serde_json::to_writer_pretty(out, self)
+    }
+}
+
+impl fmt::Display for BatchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = &self.overall;
+        writeln!(
+            f,
+            "{} runs x {} steps, {} tracks per run",
+            self.runs.len(),
+            self.steps,
+            self.tracks_per_run
+        )?;
+        let rows = [
+            ("RMSE", s.rmse),
+            ("mean error", s.mean),
+            ("median error", s.median),
+            ("p95 error", s.p95),
+            ("CEP50", s.cep50),
+            ("SEP50", s.sep50),
+        ];
+        for (name, value) in rows {
+            writeln!(f, "  {name:<16}{value:>10.3} m")?;
+        }
+        writeln!(
+            f,
+            "  {:<16}{:>10.1} %",
+            "divergence",
+            100.0 * self.divergence_rate
+        )?;
+        write!(
+            f,
+            "  {:<16}{:>10.3} s ({:.3} s per run)",
+            "runtime",
+            self.total_runtime_s,
+            self.mean_runtime_s()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::initial_particle_rng;
+    use agents::{
+        anchor::Anchor,
+        dynamics_model::WhiteNoiseAcceleration,
+        particle_filter::{BoundingBox, ParticleFilter},
+        swarm_element::SwarmElement,
+    };
+    use nalgebra::Vector3;
+
+    #[test]
+    fn error_stats_of_known_samples() {
+        let errors = [1.0, 2.0, 3.0, 4.0, 10.0];
+        let horizontal = [0.5, 1.0, 1.5, 2.0, 2.5];
+        let stats = ErrorStats::from_samples(&errors, &horizontal);
+
+        assert_eq!(stats.mean, 4.0);
+        assert_eq!(stats.median, 3.0);
+        assert_eq!(stats.sep50, 3.0);
+        assert_eq!(stats.cep50, 1.5);
+        assert!((stats.rmse - 26.0_f64.sqrt()).abs() <= 1e-12);
+        // 95 % of the way from 4 to 10
+        assert!((stats.p95 - 8.8).abs() <= 1e-12);
+    }
+
+    fn small_simulation(seed: u64) -> Simulation<WhiteNoiseAcceleration> {
+        let enclosure = BoundingBox::new(
+            Vector3::new(-10.0, -10.0, -10.0),
+            Vector3::new(10.0, 10.0, 10.0),
+        )
+        .unwrap();
+        let particle_filter =
+            ParticleFilter::new_with_rng(&enclosure, 300, 0.5, &mut initial_particle_rng(seed, 0));
+        let dynamics_model = WhiteNoiseAcceleration::new(
+            Vector3::zeros(),
+            Vector3::new(1.0, 0.0, 0.0),
+            Vector3::zeros(),
+            Vector3::new(0.1, 0.1, 0.1),
+        );
+        let element =
+            SwarmElement::new(String::from("a"), dynamics_model, particle_filter, 0.1, 0.2);
+        let anchors = vec![
+            Anchor::new(Vector3::new(20.0, 0.0, 0.0), 0.2),
+            Anchor::new(Vector3::new(0.0, 20.0, 0.0), 0.2),
+            Anchor::new(Vector3::new(0.0, 0.0, 20.0), 0.2),
+        ];
+        Simulation::builder()
+            .swarm_elements(vec![element])
+            .anchors(anchors)
+            .seed(seed)
+            .build()
+    }
+
+    #[test]
+    fn batches_are_reproducible_in_parallel() {
+        let config = BatchConfig::new(4, 10, 0.1, 3).with_divergence_threshold(1e-6);
+        let make = |seed| Ok(small_simulation(seed));
+        let parallel = run_batch(&config, make).unwrap();
+        let sequential = run_batch(&config.with_parallel(false), make).unwrap();
+
+        assert_eq!(parallel.runs.len(), 4);
+        assert_eq!(parallel.step_rmse.len(), 10);
+        assert_eq!(parallel.tracks_per_run, 1);
+        assert_eq!(parallel.step_rmse, sequential.step_rmse);
+        assert_eq!(parallel.overall, sequential.overall);
+        // runs get distinct seeds
+        assert_ne!(parallel.runs[0].seed, parallel.runs[1].seed);
+        assert_ne!(parallel.runs[0].rmse, parallel.runs[1].rmse);
+        // nothing localizes to a micrometre
+        assert_eq!(parallel.divergence_rate, 1.0);
+
+        let mut csv = Vec::new();
+        parallel.write_runs_csv(&mut csv).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap().lines().count(), 5);
+    }
+
+    #[test]
+    fn unwritable_run_outputs_fail_the_batch() {
+        let mut scenario =
+            Scenario::from_toml_str(include_str!("../../scenarios/default.toml")).unwrap();
+        scenario.visualization.trace = Some("missing/dir/trace.csv".into());
+        scenario.swarm_elements[0].filter.num_particles = 50;
+        let config = BatchConfig::new(2, 1, 0.1, 3);
+
+        let err = run_scenario_batch(&scenario, &config).err().unwrap();
+        assert!(err.to_string().contains("missing/dir/trace_"), "{err}");
+    }
+}
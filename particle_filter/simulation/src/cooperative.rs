@@ -0,0 +1,143 @@
+use nalgebra::Vector3;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use agents::cooperative::NeighbourBelief;
+use agents::likelihood::LikelihoodModel;
+use agents::particle_filter::ParticleFilter;
+
+// Which other swarm elements an element ranges against, decided on true positions
+// since radio connectivity depends on where the agents actually are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NeighbourPolicy {
+    #[default]
+    All,
+    // The k closest other elements.
+    Nearest(usize),
+    // Every other element closer than the given distance (metres).
+    WithinRange(f64),
+}
+
+impl NeighbourPolicy {
+    pub fn neighbours(&self, index: usize, positions: &[Vector3<f64>]) -> Vec<usize> {
+        let me = positions[index];
+        let mut others: Vec<(usize, f64)> = positions
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| *j != index)
+            .map(|(j, p)| (j, (p - me).norm()))
+            .collect();
+
+        match *self {
+            NeighbourPolicy::All => {}
+            NeighbourPolicy::Nearest(k) => {
+                others.sort_by(|a, b| a.1.total_cmp(&b.1));
+                others.truncate(k);
+            }
+            NeighbourPolicy::WithinRange(max_range) => {
+                others.retain(|(_, d)| *d <= max_range);
+            }
+        }
+        others.into_iter().map(|(j, _)| j).collect()
+    }
+}
+
+// How a neighbour's belief enters the likelihood of an inter-agent range.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CooperativeMode {
+    // Marginalize over `samples` particles drawn from the neighbour's cloud.
+    ParticleCloud { samples: usize },
+    // Use the neighbour's estimate and inflate the range noise by its covariance.
+    EstimateWithCovariance,
+}
+
+impl Default for CooperativeMode {
+    fn default() -> Self {
+        CooperativeMode::ParticleCloud { samples: 64 }
+    }
+}
+
+impl CooperativeMode {
+    pub fn belief<R: Rng + ?Sized>(
+        &self,
+        particle_filter: &ParticleFilter,
+        rng: &mut R,
+    ) -> NeighbourBelief {
+        match *self {
+            CooperativeMode::ParticleCloud { samples } => {
+                NeighbourBelief::from_particles(particle_filter, samples, rng)
+            }
+            CooperativeMode::EstimateWithCovariance => {
+                NeighbourBelief::from_estimate(particle_filter)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Cooperation {
+    pub policy: NeighbourPolicy,
+    pub mode: CooperativeMode,
+    // Range error model of the inter-agent links, for the filter.
+    pub likelihood: LikelihoodModel,
+}
+
+impl Cooperation {
+    pub fn new(policy: NeighbourPolicy, mode: CooperativeMode) -> Self {
+        if let CooperativeMode::ParticleCloud { samples } = mode {
+            assert!(samples > 0, "Cooperation: samples must be > 0");
+        }
+        Self {
+            policy,
+            mode,
+            likelihood: LikelihoodModel::default(),
+        }
+    }
+
+    pub fn with_likelihood(mut self, likelihood: LikelihoodModel) -> Self {
+        self.likelihood = likelihood;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line() -> Vec<Vector3<f64>> {
+        [0.0, 1.0, 3.0, 7.0]
+            .iter()
+            .map(|x| Vector3::new(*x, 0.0, 0.0))
+            .collect()
+    }
+
+    #[test]
+    fn all_excludes_self() {
+        assert_eq!(NeighbourPolicy::All.neighbours(1, &line()), vec![0, 2, 3]);
+    }
+
+    #[test]
+    fn nearest_orders_by_true_distance() {
+        assert_eq!(
+            NeighbourPolicy::Nearest(2).neighbours(3, &line()),
+            vec![2, 1]
+        );
+        assert_eq!(NeighbourPolicy::Nearest(10).neighbours(0, &line()).len(), 3);
+    }
+
+    #[test]
+    fn within_range_drops_far_elements() {
+        assert_eq!(
+            NeighbourPolicy::WithinRange(3.0).neighbours(0, &line()),
+            vec![1, 2]
+        );
+        assert!(
+            NeighbourPolicy::WithinRange(0.5)
+                .neighbours(0, &line())
+                .is_empty()
+        );
+    }
+}
@@ -1 +1,9 @@
+pub mod batch;
+pub mod cooperative;
+pub mod live;
+pub mod publisher;
+pub mod recording;
+pub mod scenario;
+pub mod scene;
 pub mod simulation;
+pub mod sweep;
@@ -0,0 +1,349 @@
+use std::error::Error;
+use std::io::{self, BufRead, BufReader};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use nalgebra::Vector3;
+use serde::{Deserialize, Serialize};
+
+use agents::{anchor::Anchor, dynamics_model::DynamicsModel, swarm_element::SwarmElement};
+
+use crate::publisher::{Estimate, Publisher};
+
+// One line of the input stream, e.g.
+// {"anchor": 0, "tag": "1000", "range": 12.31, "timestamp": 1718000000.25, "quality": 0.8}
+// `anchor` indexes the scenario's anchors, `tag` is a swarm element name, the range
+// is in metres and the timestamp in seconds on the radios' clock.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RangeReport {
+    pub anchor: usize,
+    pub tag: String,
+    pub range: f64,
+    pub timestamp: f64,
+    // Confidence in (0, 1]; the range std is divided by it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<f64>,
+}
+
+impl RangeReport {
+    pub fn parse(line: &str) -> Result<Self, Box<dyn Error>> {
+        let report: RangeReport = serde_json::from_str(line)?;
+        if !report.range.is_finite() || report.range < 0.0 {
+            return Err(format!("RangeReport: invalid range {}", report.range).into());
+        }
+        if !report.timestamp.is_finite() {
+            return Err("RangeReport: timestamp must be finite".into());
+        }
+        if let Some(quality) = report.quality
+            && !(quality > 0.0 && quality <= 1.0)
+        {
+            return Err(format!("RangeReport: quality {quality} not in (0, 1]").into());
+        }
+        Ok(report)
+    }
+
+    // The report as one line of input, without the newline.
+    pub fn to_line(&self) -> String {
+        serde_json::to_string(self).expect("RangeReport: serializes to JSON")
+    }
+}
+
+// Per-tag filters driven by range reports as they arrive, with no simulator. Each
+// tag is predicted over the time since its previous report, without a velocity
+// reading, and then updated with the one range. An estimate follows every report.
+pub struct Tracker<M: DynamicsModel> {
+    pub swarm_elements: Vec<SwarmElement<M>>,
+    pub anchors: Vec<Anchor>,
+    last_timestamps: Vec<Option<f64>>,
+}
+
+impl<M: DynamicsModel> Tracker<M> {
+    pub fn new(swarm_elements: Vec<SwarmElement<M>>, anchors: Vec<Anchor>) -> Self {
+        assert!(
+            !swarm_elements.is_empty(),
+            "Tracker: expected at least one swarm element"
+        );
+        assert!(!anchors.is_empty(), "Tracker: expected at least one anchor");
+        let last_timestamps = vec![None; swarm_elements.len()];
+        Self {
+            swarm_elements,
+            anchors,
+            last_timestamps,
+        }
+    }
+
+    // Late reports are applied without a prediction step.
+    pub fn ingest(&mut self, report: &RangeReport) -> Result<Estimate, Box<dyn Error>>
+    where
+        M: Sync,
+    {
+        let index = self
+            .swarm_elements
+            .iter()
+            .position(|se| se.name == report.tag)
+            .ok_or_else(|| format!("Tracker: unknown tag {:?}", report.tag))?;
+        let anchor = self
+            .anchors
+            .get(report.anchor)
+            .ok_or_else(|| format!("Tracker: unknown anchor {}", report.anchor))?;
+        let se = &mut self.swarm_elements[index];
+
+        let last = &mut self.last_timestamps[index];
+        let dt = last.map_or(0.0, |t| report.timestamp - t);
+        if dt > 0.0 {
+            se.predict(dt, Vector3::zeros());
+        }
+        if dt >= 0.0 {
+            *last = Some(report.timestamp);
+        }
+
+        let var_rx = se.ranging_noise.std_dev().powi(2);
+        let var_tx = anchor.ranging_noise.std_dev().powi(2);
+        let std = (var_rx + var_tx).sqrt() / report.quality.unwrap_or(1.0);
+        if std.is_nan() || std <= 0.0 {
+            return Err(format!("Tracker: zero range std for anchor {}", report.anchor).into());
+        }
+        se.particle_filter.update_weights_with(
+            report.range,
+            anchor.position,
+            std,
+            &anchor.likelihood,
+        );
+        se.particle_filter.normalize_weights();
+        se.update_est_position();
+        se.particle_filter.resample();
+
+        // the truth of a live tag is unknown
+        Ok(Estimate::from_element(se, report.timestamp, false))
+    }
+
+    // Feed one line of input, publishing the estimate. Bad reports are logged and
+    // dropped so one faulty radio cannot stop the others.
+    fn handle_line(
+        &mut self,
+        line: &str,
+        publish: &mut impl FnMut(&Estimate) -> io::Result<()>,
+    ) -> io::Result<()>
+    where
+        M: Sync,
+    {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+        match RangeReport::parse(line).and_then(|report| self.ingest(&report)) {
+            Ok(estimate) => publish(&estimate),
+            Err(e) => {
+                log::warn!("dropping report {line:?}: {e}");
+                Ok(())
+            }
+        }
+    }
+
+    // Run every line of `input` through the filters until it ends.
+    pub fn serve_lines<R: BufRead>(
+        &mut self,
+        input: R,
+        mut publish: impl FnMut(&Estimate) -> io::Result<()>,
+    ) -> io::Result<()>
+    where
+        M: Sync,
+    {
+        for line in input.lines() {
+            self.handle_line(&line?, &mut publish)?;
+        }
+        Ok(())
+    }
+
+    // Serve reports from the socket forever. A datagram may carry several lines;
+    // TCP connections are read side by side and their lines filtered as they come.
+    pub fn listen(&mut self, input: Input, publisher: &mut Publisher) -> io::Result<()>
+    where
+        M: Sync,
+    {
+        let mut publish = |estimate: &Estimate| publisher.publish(estimate);
+        match input {
+            Input::Udp(addr) => {
+                let socket = UdpSocket::bind(addr)?;
+                let mut buf = vec![0u8; 65536];
+                loop {
+                    let (len, _) = socket.recv_from(&mut buf)?;
+                    let text = String::from_utf8_lossy(&buf[..len]);
+                    for line in text.lines() {
+                        self.handle_line(line, &mut publish)?;
+                    }
+                }
+            }
+            Input::Tcp(addr) => {
+                let listener = TcpListener::bind(addr)?;
+                let (sender, lines) = mpsc::channel();
+                thread::spawn(move || {
+                    for stream in listener.incoming() {
+                        match stream {
+                            Ok(stream) => {
+                                let sender = sender.clone();
+                                thread::spawn(move || forward_lines(stream, sender));
+                            }
+                            Err(e) => log::warn!("cannot accept connection: {e}"),
+                        }
+                    }
+                });
+                for line in lines {
+                    self.handle_line(&line, &mut publish)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Pass the lines of one connection on to the tracker; a dropped connection only
+// ends that connection.
+fn forward_lines(stream: TcpStream, sender: Sender<String>) {
+    if let Ok(peer) = stream.peer_addr() {
+        log::info!("connection from {peer}");
+    }
+    for line in BufReader::new(stream).lines() {
+        match line {
+            Ok(line) => {
+                if sender.send(line).is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                log::warn!("connection closed: {e}");
+                return;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scenario::Scenario;
+    use agents::dynamics_model::WhiteNoiseAcceleration;
+    use std::fs;
+
+    const DEFAULT: &str = include_str!("../../scenarios/default.toml");
+
+    fn tracker() -> Tracker<WhiteNoiseAcceleration> {
+        let mut scenario = Scenario::from_toml_str(DEFAULT).unwrap();
+        scenario.visualization.enabled = false;
+        scenario.seed = Some(1);
+        let sim = scenario.simulation_builder().unwrap().build();
+        Tracker::new(sim.swarm_elements, sim.anchors)
+    }
+
+    #[test]
+    fn reports_follow_the_schema() {
+        let report =
+            RangeReport::parse(r#"{"anchor": 2, "tag": "a", "range": 4.5, "timestamp": 1.0}"#)
+                .unwrap();
+        assert_eq!(report.anchor, 2);
+        assert_eq!(report.quality, None);
+        assert_eq!(RangeReport::parse(&report.to_line()).unwrap(), report);
+
+        for bad in [
+            r#"{"anchor": 0, "tag": "a", "range": -1.0, "timestamp": 1.0}"#,
+            r#"{"anchor": 0, "tag": "a", "range": 1.0, "timestamp": 1.0, "quality": 0.0}"#,
+            r#"{"anchor": 0, "tag": "a", "range": 1.0}"#,
+            "not json",
+        ] {
+            assert!(RangeReport::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn static_tag_is_located_from_a_stream() {
+        let mut tracker = tracker();
+        let truth = Vector3::new(20.0, 15.0, 0.0);
+        let mut input = String::from("\n{\"anchor\": 9, \"tag\": \"1000\"}\n");
+        for step in 0..30 {
+            for (j, anchor) in tracker.anchors.iter().enumerate() {
+                let report = RangeReport {
+                    anchor: j,
+                    tag: String::from("1000"),
+                    range: (truth - anchor.position).norm(),
+                    timestamp: step as f64 / 10.0,
+                    quality: None,
+                };
+                input.push_str(&report.to_line());
+                input.push('\n');
+            }
+        }
+        // the bad lines are dropped
+        input.push_str(r#"{"anchor": 0, "tag": "unknown", "range": 1.0, "timestamp": 5.0}"#);
+
+        let mut estimates = Vec::new();
+        tracker
+            .serve_lines(input.as_bytes(), |e| {
+                estimates.push(e.clone());
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(estimates.len(), 90);
+        let last = estimates.last().unwrap();
+        assert_eq!(last.timestamp, 2.9);
+        assert_eq!(last.error, None);
+        // three anchors in a plane leave the sign of z open
+        let horizontal = (Vector3::from(last.mean) - truth).xy().norm();
+        assert!(horizontal < 2.0, "horizontal error {horizontal}");
+    }
+
+    #[test]
+    fn tcp_connections_are_served_side_by_side() {
+        use crate::publisher::Destination;
+        use std::io::Write;
+        use std::net::TcpStream;
+        use std::time::{Duration, Instant};
+
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("live_{}.ndjson", std::process::id()));
+        let mut publisher = Publisher::open(&Destination::File(path.clone())).unwrap();
+        let mut tracker = tracker();
+        thread::spawn(move || tracker.listen(Input::Tcp(addr), &mut publisher));
+
+        let connect = || {
+            let start = Instant::now();
+            loop {
+                match TcpStream::connect(addr) {
+                    Ok(stream) => return stream,
+                    Err(e) if start.elapsed() > Duration::from_secs(5) => panic!("{e}"),
+                    Err(_) => thread::sleep(Duration::from_millis(10)),
+                }
+            }
+        };
+        // the first radio connects and stays silent, the second must still get through
+        let _idle = connect();
+        let mut radio = connect();
+        writeln!(
+            radio,
+            r#"{{"anchor": 0, "tag": "1000", "range": 10.0, "timestamp": 0.5}}"#
+        )
+        .unwrap();
+
+        let start = Instant::now();
+        let published = loop {
+            let text = fs::read_to_string(&path).unwrap_or_default();
+            if !text.is_empty() || start.elapsed() > Duration::from_secs(5) {
+                break text;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        fs::remove_file(&path).ok();
+        assert_eq!(published.lines().count(), 1, "{published:?}");
+        assert!(published.contains(r#""timestamp":0.5"#), "{published:?}");
+    }
+}
@@ -0,0 +1,224 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use agents::{dynamics_model::DynamicsModel, swarm_element::SwarmElement};
+
+// One element's estimate, published as one JSON line.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Estimate {
+    pub name: String,
+    // Simulated time, or the report time for live data (s).
+    pub timestamp: f64,
+    // The element's point estimate, from its configured estimator.
+    pub position: [f64; 3],
+    // Posterior mean and covariance of the position.
+    pub mean: [f64; 3],
+    pub covariance: [[f64; 3]; 3],
+    // Effective sample size before resampling.
+    pub ess: f64,
+    // Distance of `position` from the truth, when the truth is known (m).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<f64>,
+}
+
+impl Estimate {
+    // Take the estimate after `update_est_position`.
+    pub fn from_element<M: DynamicsModel>(
+        se: &SwarmElement<M>,
+        timestamp: f64,
+        truth_known: bool,
+    ) -> Self {
+        Self {
+            name: se.name.clone(),
+            timestamp,
+            position: se.est_position.into(),
+            mean: se.est_mean.into(),
+            covariance: se.est_covariance.transpose().into(),
+            ess: se.est_ess,
+            error: truth_known.then(|| se.estimation_error()),
+        }
+    }
+}
+
+// Where estimates go: `-` for stdout, `udp://host:port`, `tcp://host:port`,
+// `unix:///path/to/socket`, and anything else is a file path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    Stdout,
+    File(PathBuf),
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let addr = |a: &str| {
+            a.parse::<SocketAddr>()
+                .map_err(|e| format!("Destination: invalid address {a:?}: {e}"))
+        };
+        if s == "-" {
+            Ok(Destination::Stdout)
+        } else if let Some(a) = s.strip_prefix("udp://") {
+            Ok(Destination::Udp(addr(a)?))
+        } else if let Some(a) = s.strip_prefix("tcp://") {
+            Ok(Destination::Tcp(addr(a)?))
+        } else if let Some(path) = s.strip_prefix("unix://") {
+            #[cfg(unix)]
+            return Ok(Destination::Unix(PathBuf::from(path)));
+            #[cfg(not(unix))]
+            return Err(format!("Destination: no unix sockets here for {path:?}"));
+        } else if s.is_empty() {
+            Err(String::from("Destination: empty"))
+        } else {
+            Ok(Destination::File(PathBuf::from(s)))
+        }
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Destination::Stdout => write!(f, "-"),
+            Destination::File(path) => write!(f, "{}", path.display()),
+            Destination::Udp(addr) => write!(f, "udp://{addr}"),
+            Destination::Tcp(addr) => write!(f, "tcp://{addr}"),
+            #[cfg(unix)]
+            Destination::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+enum Output {
+    Stream(Box<dyn Write + Send>),
+    // One datagram per line.
+    Udp(UdpSocket, SocketAddr),
+}
+
+// Newline-delimited JSON estimates for other processes. Every line is flushed as
+// it is written, so readers see each step as it happens.
+pub struct Publisher {
+    out: Output,
+}
+
+impl Publisher {
+    // Sockets are connected to a listening consumer; files are truncated.
+    pub fn open(destination: &Destination) -> io::Result<Self> {
+        let out = match destination {
+            Destination::Stdout => Output::Stream(Box::new(io::stdout())),
+            Destination::File(path) => {
+                Output::Stream(Box::new(BufWriter::new(File::create(path)?)))
+            }
+            Destination::Udp(target) => {
+                let local: SocketAddr = if target.is_ipv4() {
+                    ([0, 0, 0, 0], 0).into()
+                } else {
+                    ([0u16; 8], 0).into()
+                };
+                Output::Udp(UdpSocket::bind(local)?, *target)
+            }
+            Destination::Tcp(addr) => Output::Stream(Box::new(TcpStream::connect(addr)?)),
+            #[cfg(unix)]
+            Destination::Unix(path) => {
+                Output::Stream(Box::new(std::os::unix::net::UnixStream::connect(path)?))
+            }
+        };
+        Ok(Self { out })
+    }
+
+    pub fn publish(&mut self, estimate: &Estimate) -> io::Result<()> {
+        let mut line = serde_json::to_string(estimate)?;
+        line.push('\n');
+        match &mut self.out {
+            Output::Stream(out) => {
+                out.write_all(line.as_bytes())?;
+                out.flush()
+            }
+            Output::Udp(socket, target) => socket.send_to(line.as_bytes(), *target).map(|_| ()),
+        }
+    }
+
+    // Publish every element's current estimate.
+    pub fn publish_step<M: DynamicsModel>(
+        &mut self,
+        swarm_elements: &[SwarmElement<M>],
+        timestamp: f64,
+        truth_known: bool,
+    ) -> io::Result<()> {
+        swarm_elements
+            .iter()
+            .try_for_each(|se| self.publish(&Estimate::from_element(se, timestamp, truth_known)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use agents::dynamics_model::WhiteNoiseAcceleration;
+    use agents::estimator::PointEstimator;
+    use agents::particle_filter::{ParticleFilter, Sphere};
+    use nalgebra::Vector3;
+
+    #[test]
+    fn destinations_parse_and_print() {
+        for text in [
+            "-",
+            "out/estimates.ndjson",
+            "udp://127.0.0.1:9001",
+            "tcp://[::1]:9002",
+        ] {
+            let destination: Destination = text.parse().unwrap();
+            assert_eq!(destination.to_string(), text);
+        }
+        assert!("udp://localhost".parse::<Destination>().is_err());
+        assert!("".parse::<Destination>().is_err());
+    }
+
+    #[test]
+    fn estimates_arrive_as_json_lines() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = Destination::Udp(receiver.local_addr().unwrap());
+        let mut publisher = Publisher::open(&destination).unwrap();
+
+        let enclosure = Sphere::new(10.0, Vector3::zeros()).unwrap();
+        let mut se = SwarmElement::new(
+            String::from("tag"),
+            WhiteNoiseAcceleration::default(),
+            ParticleFilter::new(&enclosure, 100, 0.5),
+            0.1,
+            0.1,
+        )
+        .with_estimator(PointEstimator::MaxWeight);
+        se.update_est_position();
+        let (se_position, se_error) = (se.est_position, se.estimation_error());
+        publisher.publish_step(&[se.clone()], 0.5, true).unwrap();
+        publisher.publish_step(&[se], 1.0, false).unwrap();
+
+        let mut buf = [0u8; 4096];
+        let mut lines = Vec::new();
+        for _ in 0..2 {
+            let len = receiver.recv(&mut buf).unwrap();
+            let line = std::str::from_utf8(&buf[..len]).unwrap();
+            assert!(line.ends_with('\n'));
+            lines.push(serde_json::from_str::<Estimate>(line).unwrap());
+        }
+        assert_eq!(lines[0].name, "tag");
+        assert_eq!(lines[0].timestamp, 0.5);
+        // the error is that of the configured estimate, as in estimates.csv
+        let position = Vector3::from(lines[0].position);
+        assert!((position - se_position).norm() < 1e-9);
+        assert!((lines[0].error.unwrap() - se_error).abs() < 1e-9);
+        assert!((position - Vector3::from(lines[0].mean)).norm() > 1e-6);
+        assert!(lines[0].ess > 0.0);
+        assert_eq!(lines[1].error, None);
+    }
+}
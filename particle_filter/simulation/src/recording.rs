@@ -0,0 +1,534 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use nalgebra::Vector3;
+
+// Everything the filters consume in a step, plus the ground truth to score them.
+// A log is a sequence of steps, each opened by a `Step` record.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Record {
+    // Start of a step ending at `time` (s), `dt` after the previous one.
+    Step {
+        time: f64,
+        dt: f64,
+    },
+    // True state of `agent` at the end of the step; clock offset in s.
+    Truth {
+        agent: usize,
+        position: Vector3<f64>,
+        velocity: Vector3<f64>,
+        clock_offset: f64,
+    },
+    // Velocity reading used to propagate the particles over the step.
+    Velocity {
+        agent: usize,
+        velocity: Vector3<f64>,
+        std: f64,
+    },
+    // Range (m) from `anchor`.
+    Range {
+        agent: usize,
+        anchor: usize,
+        range: f64,
+        std: f64,
+    },
+    // Raw arrival time (s) at `anchor`; the std is in metres.
+    Toa {
+        agent: usize,
+        anchor: usize,
+        toa: f64,
+        std: f64,
+    },
+    // Range (m) to another agent.
+    PeerRange {
+        agent: usize,
+        neighbour: usize,
+        range: f64,
+        std: f64,
+    },
+}
+
+impl Record {
+    // The agent the record is about; `None` for step markers.
+    pub fn agent(&self) -> Option<usize> {
+        match *self {
+            Record::Step { .. } => None,
+            Record::Truth { agent, .. }
+            | Record::Velocity { agent, .. }
+            | Record::Range { agent, .. }
+            | Record::Toa { agent, .. }
+            | Record::PeerRange { agent, .. } => Some(agent),
+        }
+    }
+
+    // A zero std is left to the filters, e.g. the TDOA differences floor it.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        match *self {
+            Record::Range { std, .. } | Record::Toa { std, .. } | Record::PeerRange { std, .. }
+                if !(std.is_finite() && std >= 0.0) =>
+            {
+                Err("std must be finite and >= 0")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// Split a log into steps, each starting with its `Step` record.
+pub fn steps(records: &[Record]) -> impl Iterator<Item = &[Record]> {
+    records.chunk_by(|_, next| !matches!(next, Record::Step { .. }))
+}
+
+const CSV_HEADER: &str = "time,kind,agent,other,value,std,x,y,z,vx,vy,vz";
+const MAGIC: &[u8; 8] = b"PFLOG\0\0\x01";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Csv,
+    // Little-endian, tagged records behind an 8-byte magic.
+    Binary,
+}
+
+impl LogFormat {
+    // `.csv` is CSV, anything else binary.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => LogFormat::Csv,
+            _ => LogFormat::Binary,
+        }
+    }
+}
+
+pub struct RecordWriter<W: Write> {
+    out: W,
+    format: LogFormat,
+    time: f64,
+}
+
+impl RecordWriter<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        Self::new(
+            BufWriter::new(File::create(path)?),
+            LogFormat::from_path(path),
+        )
+    }
+}
+
+impl<W: Write> RecordWriter<W> {
+    pub fn new(mut out: W, format: LogFormat) -> io::Result<Self> {
+        match format {
+            LogFormat::Csv => writeln!(out, "{CSV_HEADER}")?,
+            LogFormat::Binary => out.write_all(MAGIC)?,
+        }
+        Ok(Self {
+            out,
+            format,
+            time: 0.0,
+        })
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        if let Record::Step { time, .. } = *record {
+            self.time = time;
+        }
+        match self.format {
+            LogFormat::Csv => write_csv(&mut self.out, self.time, record),
+            LogFormat::Binary => write_binary(&mut self.out, record),
+        }
+    }
+
+    pub fn write_all(&mut self, records: &[Record]) -> io::Result<()> {
+        records.iter().try_for_each(|r| self.write(r))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+fn write_csv<W: Write>(out: &mut W, time: f64, record: &Record) -> io::Result<()> {
+    let v = |v: &Vector3<f64>| format!("{},{},{}", v.x, v.y, v.z);
+    match record {
+        Record::Step { time, dt } => writeln!(out, "{time},step,,,{dt},,,,,,,"),
+        Record::Truth {
+            agent,
+            position,
+            velocity,
+            clock_offset,
+        } => writeln!(
+            out,
+            "{time},truth,{agent},,{clock_offset},,{},{}",
+            v(position),
+            v(velocity)
+        ),
+        Record::Velocity {
+            agent,
+            velocity,
+            std,
+        } => writeln!(out, "{time},velocity,{agent},,,{std},,,,{}", v(velocity)),
+        Record::Range {
+            agent,
+            anchor,
+            range,
+            std,
+        } => writeln!(out, "{time},range,{agent},{anchor},{range},{std},,,,,,"),
+        Record::Toa {
+            agent,
+            anchor,
+            toa,
+            std,
+        } => writeln!(out, "{time},toa,{agent},{anchor},{toa},{std},,,,,,"),
+        Record::PeerRange {
+            agent,
+            neighbour,
+            range,
+            std,
+        } => writeln!(
+            out,
+            "{time},peer_range,{agent},{neighbour},{range},{std},,,,,,"
+        ),
+    }
+}
+
+fn write_binary<W: Write>(out: &mut W, record: &Record) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(64);
+    let mut f = |x: f64| buf.extend_from_slice(&x.to_le_bytes());
+    let tag: u8;
+    let mut ids: Vec<u32> = Vec::new();
+    match *record {
+        Record::Step { time, dt } => {
+            tag = 0;
+            f(time);
+            f(dt);
+        }
+        Record::Truth {
+            agent,
+            position,
+            velocity,
+            clock_offset,
+        } => {
+            tag = 1;
+            ids.push(agent as u32);
+            position.iter().chain(velocity.iter()).for_each(|x| f(*x));
+            f(clock_offset);
+        }
+        Record::Velocity {
+            agent,
+            velocity,
+            std,
+        } => {
+            tag = 2;
+            ids.push(agent as u32);
+            velocity.iter().for_each(|x| f(*x));
+            f(std);
+        }
+        Record::Range {
+            agent,
+            anchor,
+            range,
+            std,
+        } => {
+            tag = 3;
+            ids.extend([agent as u32, anchor as u32]);
+            f(range);
+            f(std);
+        }
+        Record::Toa {
+            agent,
+            anchor,
+            toa,
+            std,
+        } => {
+            tag = 4;
+            ids.extend([agent as u32, anchor as u32]);
+            f(toa);
+            f(std);
+        }
+        Record::PeerRange {
+            agent,
+            neighbour,
+            range,
+            std,
+        } => {
+            tag = 5;
+            ids.extend([agent as u32, neighbour as u32]);
+            f(range);
+            f(std);
+        }
+    }
+    out.write_all(&[tag])?;
+    for id in ids {
+        out.write_all(&id.to_le_bytes())?;
+    }
+    out.write_all(&buf)
+}
+
+// Read a whole log, in the format given by the file extension.
+pub fn read_records(path: impl AsRef<Path>) -> Result<Vec<Record>, Box<dyn Error>> {
+    let path = path.as_ref();
+    let file =
+        File::open(path).map_err(|e| format!("Recording: cannot read {}: {e}", path.display()))?;
+    let records = match LogFormat::from_path(path) {
+        LogFormat::Csv => read_csv(BufReader::new(file))?,
+        LogFormat::Binary => read_binary(BufReader::new(file))?,
+    };
+    if !matches!(records.first(), None | Some(Record::Step { .. })) {
+        return Err(format!("Recording: {} does not start with a step", path.display()).into());
+    }
+    Ok(records)
+}
+
+pub fn read_csv<R: BufRead>(input: R) -> Result<Vec<Record>, Box<dyn Error>> {
+    let mut records = Vec::new();
+    for (i, line) in input.lines().enumerate().skip(1) {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = parse_csv_line(&line)
+            .and_then(|r| r.validate().map(|()| r).map_err(Into::into))
+            .map_err(|e| format!("Recording: line {}: {e}", i + 1))?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn parse_csv_line(line: &str) -> Result<Record, Box<dyn Error>> {
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    if fields.len() != 12 {
+        return Err(format!("expected 12 fields, got {}", fields.len()).into());
+    }
+    let num = |i: usize| -> Result<f64, Box<dyn Error>> { Ok(fields[i].parse::<f64>()?) };
+    let id = |i: usize| -> Result<usize, Box<dyn Error>> { Ok(fields[i].parse::<usize>()?) };
+    let vector = |i: usize| -> Result<Vector3<f64>, Box<dyn Error>> {
+        Ok(Vector3::new(num(i)?, num(i + 1)?, num(i + 2)?))
+    };
+
+    Ok(match fields[1] {
+        "step" => Record::Step {
+            time: num(0)?,
+            dt: num(4)?,
+        },
+        "truth" => Record::Truth {
+            agent: id(2)?,
+            position: vector(6)?,
+            velocity: vector(9)?,
+            clock_offset: num(4)?,
+        },
+        "velocity" => Record::Velocity {
+            agent: id(2)?,
+            velocity: vector(9)?,
+            std: num(5)?,
+        },
+        "range" => Record::Range {
+            agent: id(2)?,
+            anchor: id(3)?,
+            range: num(4)?,
+            std: num(5)?,
+        },
+        "toa" => Record::Toa {
+            agent: id(2)?,
+            anchor: id(3)?,
+            toa: num(4)?,
+            std: num(5)?,
+        },
+        "peer_range" => Record::PeerRange {
+            agent: id(2)?,
+            neighbour: id(3)?,
+            range: num(4)?,
+            std: num(5)?,
+        },
+        kind => return Err(format!("unknown record kind {kind:?}").into()),
+    })
+}
+
+pub fn read_binary<R: Read>(mut input: R) -> Result<Vec<Record>, Box<dyn Error>> {
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err("Recording: not a binary measurement log".into());
+    }
+
+    let mut records = Vec::new();
+    let mut tag = [0u8; 1];
+    loop {
+        match input.read_exact(&mut tag) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let mut id = || -> io::Result<usize> {
+            let mut b = [0u8; 4];
+            input.read_exact(&mut b)?;
+            Ok(u32::from_le_bytes(b) as usize)
+        };
+        let record = match tag[0] {
+            0 => {
+                let [time, dt] = read_f64s(&mut input)?;
+                Record::Step { time, dt }
+            }
+            1 => {
+                let agent = id()?;
+                let [x, y, z, vx, vy, vz, clock_offset] = read_f64s(&mut input)?;
+                Record::Truth {
+                    agent,
+                    position: Vector3::new(x, y, z),
+                    velocity: Vector3::new(vx, vy, vz),
+                    clock_offset,
+                }
+            }
+            2 => {
+                let agent = id()?;
+                let [vx, vy, vz, std] = read_f64s(&mut input)?;
+                Record::Velocity {
+                    agent,
+                    velocity: Vector3::new(vx, vy, vz),
+                    std,
+                }
+            }
+            3..=5 => {
+                let (agent, other) = (id()?, id()?);
+                let [value, std] = read_f64s(&mut input)?;
+                match tag[0] {
+                    3 => Record::Range {
+                        agent,
+                        anchor: other,
+                        range: value,
+                        std,
+                    },
+                    4 => Record::Toa {
+                        agent,
+                        anchor: other,
+                        toa: value,
+                        std,
+                    },
+                    _ => Record::PeerRange {
+                        agent,
+                        neighbour: other,
+                        range: value,
+                        std,
+                    },
+                }
+            }
+            t => return Err(format!("Recording: unknown record tag {t}").into()),
+        };
+        record
+            .validate()
+            .map_err(|e| format!("Recording: record {}: {e}", records.len()))?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn read_f64s<R: Read, const N: usize>(input: &mut R) -> io::Result<[f64; N]> {
+    let mut values = [0.0; N];
+    let mut b = [0u8; 8];
+    for v in &mut values {
+        input.read_exact(&mut b)?;
+        *v = f64::from_le_bytes(b);
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_log() -> Vec<Record> {
+        vec![
+            Record::Step { time: 0.1, dt: 0.1 },
+            Record::Velocity {
+                agent: 0,
+                velocity: Vector3::new(1.0, -0.5, 0.25),
+                std: 0.1,
+            },
+            Record::Truth {
+                agent: 0,
+                position: Vector3::new(1.5, 2.0, -3.0),
+                velocity: Vector3::new(1.0, 0.0, 0.0),
+                clock_offset: 1e-9,
+            },
+            Record::Range {
+                agent: 0,
+                anchor: 2,
+                range: 12.345678901234567,
+                std: 0.3,
+            },
+            Record::Step { time: 0.2, dt: 0.1 },
+            Record::Toa {
+                agent: 0,
+                anchor: 1,
+                toa: 4.2e-8,
+                std: 0.3,
+            },
+            Record::PeerRange {
+                agent: 0,
+                neighbour: 1,
+                range: 3.0,
+                std: 0.2,
+            },
+        ]
+    }
+
+    #[test]
+    fn csv_and_binary_round_trip_exactly() {
+        let log = sample_log();
+        for format in [LogFormat::Csv, LogFormat::Binary] {
+            let mut writer = RecordWriter::new(Vec::new(), format).unwrap();
+            writer.write_all(&log).unwrap();
+            let bytes = writer.out;
+
+            let read = match format {
+                LogFormat::Csv => read_csv(bytes.as_slice()).unwrap(),
+                LogFormat::Binary => read_binary(bytes.as_slice()).unwrap(),
+            };
+            assert_eq!(read, log, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn logs_split_into_steps() {
+        let log = sample_log();
+        let steps: Vec<&[Record]> = steps(&log).collect();
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[0].len(), 4);
+        assert_eq!(steps[1][0], Record::Step { time: 0.2, dt: 0.1 });
+
+        assert!(read_binary(&b"not a log"[..]).is_err());
+        assert!(read_csv(format!("{CSV_HEADER}\n0.1,jump,,,,,,,,,,").as_bytes()).is_err());
+    }
+
+    #[test]
+    fn unusable_stds_are_rejected_on_reading() {
+        for std in [-0.3, f64::NAN, f64::INFINITY] {
+            let mut log = sample_log();
+            log[3] = Record::Range {
+                agent: 0,
+                anchor: 2,
+                range: 12.0,
+                std,
+            };
+            for format in [LogFormat::Csv, LogFormat::Binary] {
+                let mut writer = RecordWriter::new(Vec::new(), format).unwrap();
+                writer.write_all(&log).unwrap();
+                let bytes = writer.out;
+                let err = match format {
+                    LogFormat::Csv => read_csv(bytes.as_slice()).unwrap_err(),
+                    LogFormat::Binary => read_binary(bytes.as_slice()).unwrap_err(),
+                };
+                let context = match format {
+                    LogFormat::Csv => "line 5",
+                    LogFormat::Binary => "record 3",
+                };
+                assert!(err.to_string().contains(context), "{err}");
+            }
+        }
+    }
+}
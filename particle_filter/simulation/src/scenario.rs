@@ -0,0 +1,1255 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use nalgebra::Vector3;
+use rand::Rng;
+use rand_distr::StandardNormal;
+use serde::{Deserialize, Serialize};
+
+use agents::{
+    anchor::Anchor,
+    clock::ClockModel,
+    dynamics_model::{DynamicsModel, WhiteNoiseAcceleration},
+    estimator::PointEstimator,
+    likelihood::LikelihoodModel,
+    particle_filter::{
+        BoundingBox, Cylinder, DEFAULT_ROUGHENING, DynEnclosure, ExtrudedPolygon, Gaussian,
+        ParticleFilter, Sphere, SphericalShell, Union,
+    },
+    resampling::{KldSampling, ResamplingScheme},
+    swarm_element::{DEFAULT_CREDIBLE_LEVEL, SwarmElement},
+    terrain::{AglPrior, BelowGround, HeightGrid, TerrainConstraint},
+    twr::TwrConfig,
+};
+
+use visualization::{
+    terrain_shape,
+    trace::TraceWriter,
+    visualization::{RerunVisualization, Visualizer},
+};
+
+use crate::cooperative::{Cooperation, CooperativeMode};
+use crate::scene::{BlockedLink, Obstacle, Scene, Terrain};
+use crate::simulation::{
+    MeasurementMode, Simulation, SimulationBuilder, VisualizationOptions, initial_particle_rng,
+};
+
+// Everything needed to set up and run one simulation, loaded from TOML or JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Scenario {
+    pub steps: usize,
+    pub step_size: f64,
+    // Unseeded scenarios draw a different run every time.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub measurement_mode: MeasurementMode,
+    #[serde(default)]
+    pub visualization: VisualizationConfig,
+    pub anchors: Vec<AnchorConfig>,
+    pub swarm_elements: Vec<SwarmElementConfig>,
+    #[serde(default)]
+    pub cooperation: Option<Cooperation>,
+    #[serde(default)]
+    pub scene: Option<SceneConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VisualizationConfig {
+    // Spawn the live viewer.
+    pub enabled: bool,
+    pub application_id: String,
+    // Rerun recording to write, with or without the viewer.
+    pub rrd: Option<PathBuf>,
+    // CSV or NDJSON trace of everything drawn, picked by the extension.
+    pub trace: Option<PathBuf>,
+    #[serde(flatten)]
+    pub options: VisualizationOptions,
+}
+
+impl Default for VisualizationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            application_id: String::from("ToA-Particle-Filter"),
+            rrd: None,
+            trace: None,
+            options: VisualizationOptions::default(),
+        }
+    }
+}
+
+impl VisualizationConfig {
+    // The visualizers asked for. Batch runs pass their seed, which goes into the file
+    // names so the runs do not overwrite each other.
+    pub fn visualizers(
+        &self,
+        run_seed: Option<u64>,
+    ) -> Result<Vec<Box<dyn Visualizer>>, Box<dyn Error>> {
+        let mut visualizers: Vec<Box<dyn Visualizer>> = Vec::new();
+        if self.enabled {
+            let viewer = RerunVisualization::new(self.application_id.clone())?;
+            visualizers.push(Box::new(viewer));
+        }
+        if let Some(path) = &self.rrd {
+            let path = with_run_seed(path, run_seed);
+            let recording = RerunVisualization::save(self.application_id.clone(), &path)
+                .map_err(|e| format!("Scenario: cannot record {}: {e}", path.display()))?;
+            visualizers.push(Box::new(recording));
+        }
+        if let Some(path) = &self.trace {
+            let path = with_run_seed(path, run_seed);
+            let trace = TraceWriter::create(&path)
+                .map_err(|e| format!("Scenario: cannot write {}: {e}", path.display()))?;
+            visualizers.push(Box::new(trace));
+        }
+        Ok(visualizers)
+    }
+}
+
+// `out/trace.csv` becomes `out/trace_<seed>.csv`.
+fn with_run_seed(path: &Path, run_seed: Option<u64>) -> PathBuf {
+    let Some(seed) = run_seed else {
+        return path.to_path_buf();
+    };
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!("_{seed}"));
+    if let Some(ext) = path.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    path.with_file_name(name)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnchorConfig {
+    pub position: [f64; 3],
+    pub sd_ranging_noise: f64,
+    #[serde(default)]
+    pub likelihood: LikelihoodModel,
+    #[serde(default)]
+    pub range_errors: LikelihoodModel,
+    #[serde(default)]
+    pub twr: Option<TwrConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SwarmElementConfig {
+    pub name: String,
+    pub dynamics: DynamicsConfig,
+    pub filter: FilterConfig,
+    pub sd_transmission_noise: f64,
+    pub sd_ranging_noise: f64,
+    #[serde(default)]
+    pub estimator: PointEstimator,
+    #[serde(default = "default_credible_level")]
+    pub credible_level: f64,
+    #[serde(default)]
+    pub clock: Option<ClockModel>,
+}
+
+fn default_credible_level() -> f64 {
+    DEFAULT_CREDIBLE_LEVEL
+}
+
+fn default_roughening() -> f64 {
+    DEFAULT_ROUGHENING
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DynamicsConfig {
+    WhiteNoiseAcceleration {
+        position: [f64; 3],
+        velocity: [f64; 3],
+        mean_acceleration: [f64; 3],
+        sigma_acceleration: [f64; 3],
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FilterConfig {
+    pub num_particles: usize,
+    pub ess_tau: f64,
+    pub enclosure: EnclosureConfig,
+    #[serde(default)]
+    pub resampling: ResamplingScheme,
+    #[serde(default = "default_roughening")]
+    pub roughening: f64,
+    #[serde(default)]
+    pub kld_sampling: Option<KldConfig>,
+    // Switches the filter to a position-velocity state.
+    #[serde(default)]
+    pub velocity_prior: Option<EnclosureConfig>,
+    #[serde(default)]
+    pub clock_state: Option<ClockStateConfig>,
+    // Keeps the particles above the ground of the scene's terrain.
+    #[serde(default)]
+    pub terrain: Option<FilterTerrainConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FilterTerrainConfig {
+    #[serde(default)]
+    pub below_ground: BelowGround,
+    #[serde(default)]
+    pub agl_prior: Option<AglPrior>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EnclosureConfig {
+    BoundingBox {
+        min: [f64; 3],
+        max: [f64; 3],
+    },
+    Sphere {
+        radius: f64,
+        origin: [f64; 3],
+    },
+    Cylinder {
+        radius: f64,
+        base: [f64; 3],
+        height: f64,
+    },
+    // A footprint given by its vertices, or by the first polygon of a shapefile in
+    // the map coordinates of the scene terrain, between two heights.
+    Polygon {
+        #[serde(default)]
+        vertices: Vec<[f64; 2]>,
+        #[serde(default)]
+        shapefile: Option<PathBuf>,
+        min_z: f64,
+        max_z: f64,
+    },
+    Gaussian {
+        mean: [f64; 3],
+        std: [f64; 3],
+    },
+    // Shell around an anchor at the element's first range to it, `sigmas` ranging
+    // standard deviations thick either side. Without a `range`, one is drawn from the
+    // element's true start.
+    RangeShell {
+        anchor: usize,
+        #[serde(default)]
+        range: Option<f64>,
+        #[serde(default = "default_sigmas")]
+        sigmas: f64,
+    },
+    Union {
+        parts: Vec<WeightedEnclosure>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WeightedEnclosure {
+    pub weight: f64,
+    #[serde(flatten)]
+    pub enclosure: EnclosureConfig,
+}
+
+fn default_sigmas() -> f64 {
+    3.0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct KldConfig {
+    pub min_particles: usize,
+    pub max_particles: usize,
+    pub bin_size: f64,
+    pub epsilon: f64,
+    pub delta: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ClockStateConfig {
+    pub max_bias: f64,
+    pub max_drift: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SceneConfig {
+    #[serde(default)]
+    pub blocked_link: BlockedLink,
+    #[serde(default)]
+    pub obstacles: Vec<ObstacleConfig>,
+    #[serde(default)]
+    pub terrain: Option<TerrainConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ObstacleConfig {
+    AxisAlignedBox { min: [f64; 3], max: [f64; 3] },
+    Plane { point: [f64; 3], normal: [f64; 3] },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TerrainConfig {
+    // PolylineZ contour lines.
+    #[serde(default)]
+    pub shapefile: Option<PathBuf>,
+    // ESRI ASCII grid of ground heights, in the same map coordinates.
+    #[serde(default)]
+    pub dem: Option<PathBuf>,
+    // Without a DEM, the contours are interpolated into a height grid of cells of
+    // this size (m) for the filters.
+    #[serde(default)]
+    pub grid_cell_size: Option<f64>,
+    // Map coordinates of the simulation origin, e.g. easting, northing and altitude.
+    pub origin: [f64; 3],
+    #[serde(default = "default_vertical_exaggeration")]
+    pub vertical_exaggeration: f64,
+    // When set, the terrain is rasterized into cells of this size (m) and blocks
+    // the links through it.
+    #[serde(default)]
+    pub cell_size: Option<f64>,
+}
+
+fn default_vertical_exaggeration() -> f64 {
+    1.0
+}
+
+impl Scenario {
+    // The format follows the file extension, `.json` for JSON and TOML otherwise.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Scenario: cannot read {}: {e}", path.display()))?;
+        let is_json = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+        if is_json {
+            Self::from_json_str(&text)
+        } else {
+            Self::from_toml_str(&text)
+        }
+    }
+
+    pub fn from_toml_str(text: &str) -> Result<Self, Box<dyn Error>> {
+        let scenario: Scenario = toml::from_str(text)?;
+        scenario.validate()?;
+        Ok(scenario)
+    }
+
+    pub fn from_json_str(text: &str) -> Result<Self, Box<dyn Error>> {
+        let scenario: Scenario = serde_json::from_str(text)?;
+        scenario.validate()?;
+        Ok(scenario)
+    }
+
+    // Catch the values the constructors would otherwise panic on.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.step_size.is_nan() || self.step_size <= 0.0 {
+            return Err("Scenario: step_size must be > 0".into());
+        }
+        if self.anchors.is_empty() {
+            return Err("Scenario: expected at least one anchor".into());
+        }
+        if self.swarm_elements.is_empty() {
+            return Err("Scenario: expected at least one swarm element".into());
+        }
+        if let MeasurementMode::Tdoa { reference } = self.measurement_mode
+            && (self.anchors.len() < 2 || reference >= self.anchors.len())
+        {
+            return Err("Scenario: TDOA needs two anchors and a valid reference".into());
+        }
+        if let Some(Cooperation {
+            mode: CooperativeMode::ParticleCloud { samples: 0 },
+            ..
+        }) = self.cooperation
+        {
+            return Err("Scenario: cooperation samples must be > 0".into());
+        }
+        if let Some(cooperation) = self.cooperation {
+            cooperation
+                .likelihood
+                .validate()
+                .map_err(|e| format!("Scenario: cooperation: {e}"))?;
+        }
+
+        for (j, anchor) in self.anchors.iter().enumerate() {
+            check_std("anchor sd_ranging_noise", anchor.sd_ranging_noise)?;
+            let invalid = |e| format!("Scenario: anchor {j}: {e}");
+            anchor.likelihood.validate().map_err(invalid)?;
+            anchor.range_errors.validate().map_err(invalid)?;
+            if let Some(twr) = anchor.twr {
+                twr.validate().map_err(invalid)?;
+            }
+        }
+
+        for se in &self.swarm_elements {
+            check_std("sd_transmission_noise", se.sd_transmission_noise)?;
+            check_std("sd_ranging_noise", se.sd_ranging_noise)?;
+            se.estimator
+                .validate()
+                .map_err(|e| format!("Scenario: {}: estimator: {e}", se.name))?;
+            if !(se.credible_level > 0.0 && se.credible_level < 1.0) {
+                return Err(
+                    format!("Scenario: {}: credible_level must be in (0, 1)", se.name).into(),
+                );
+            }
+
+            let filter = &se.filter;
+            if filter.num_particles == 0 {
+                return Err(format!("Scenario: {}: num_particles must be > 0", se.name).into());
+            }
+            if !(0.0..=1.0).contains(&filter.ess_tau) {
+                return Err(format!("Scenario: {}: ess_tau must be in [0, 1]", se.name).into());
+            }
+            if !(filter.roughening.is_finite() && filter.roughening >= 0.0) {
+                return Err(format!("Scenario: {}: roughening must be >= 0", se.name).into());
+            }
+            if let Some(k) = filter.kld_sampling {
+                let valid = k.min_particles > 0
+                    && k.min_particles <= k.max_particles
+                    && k.bin_size > 0.0
+                    && k.epsilon > 0.0
+                    && k.delta > 0.0
+                    && k.delta < 1.0;
+                if !valid {
+                    return Err(format!("Scenario: {}: invalid kld_sampling", se.name).into());
+                }
+            }
+            if let Some(c) = filter.clock_state
+                && (c.max_bias < 0.0 || c.max_drift < 0.0)
+            {
+                return Err(format!("Scenario: {}: clock bounds must be >= 0", se.name).into());
+            }
+            filter
+                .enclosure
+                .validate(self.anchors.len())
+                .map_err(|e| format!("Scenario: {}: enclosure: {e}", se.name))?;
+            if let Some(prior) = &filter.velocity_prior {
+                if prior.refers_to_anchors() {
+                    return Err(format!(
+                        "Scenario: {}: velocity_prior cannot be a range_shell",
+                        se.name
+                    )
+                    .into());
+                }
+                prior
+                    .validate(self.anchors.len())
+                    .map_err(|e| format!("Scenario: {}: velocity_prior: {e}", se.name))?;
+            }
+        }
+
+        // the filters weight ranges with the stds of both ends combined, and need it > 0
+        let ranges_to_anchors = matches!(
+            self.measurement_mode,
+            MeasurementMode::Toa | MeasurementMode::RawToa
+        );
+        for (i, se) in self.swarm_elements.iter().enumerate() {
+            if ranges_to_anchors
+                && let Some(j) = self
+                    .anchors
+                    .iter()
+                    .position(|a| se.sd_ranging_noise == 0.0 && a.sd_ranging_noise == 0.0)
+            {
+                return Err(format!(
+                    "Scenario: {}: anchor {j}: the combined sd_ranging_noise must be > 0",
+                    se.name
+                )
+                .into());
+            }
+            if self.cooperation.is_some()
+                && let Some(other) = self.swarm_elements[i + 1..]
+                    .iter()
+                    .find(|o| se.sd_ranging_noise == 0.0 && o.sd_ranging_noise == 0.0)
+            {
+                return Err(format!(
+                    "Scenario: {} and {}: the combined sd_ranging_noise must be > 0",
+                    se.name, other.name
+                )
+                .into());
+            }
+        }
+
+        let terrain = self.scene.as_ref().and_then(|s| s.terrain.as_ref());
+        if let Some(terrain) = terrain {
+            if terrain.shapefile.is_none() && terrain.dem.is_none() {
+                return Err("Scenario: terrain needs a shapefile or a dem".into());
+            }
+            if !(terrain.vertical_exaggeration.is_finite() && terrain.vertical_exaggeration > 0.0) {
+                return Err("Scenario: terrain vertical_exaggeration must be > 0".into());
+            }
+            for (name, size) in [
+                ("cell_size", terrain.cell_size),
+                ("grid_cell_size", terrain.grid_cell_size),
+            ] {
+                if let Some(size) = size
+                    && !(size.is_finite() && size > 0.0)
+                {
+                    return Err(format!("Scenario: terrain {name} must be > 0").into());
+                }
+            }
+        }
+        let has_ground = terrain.is_some_and(|t| t.dem.is_some() || t.grid_cell_size.is_some());
+        for se in &self.swarm_elements {
+            let Some(filter_terrain) = se.filter.terrain else {
+                continue;
+            };
+            if !has_ground {
+                return Err(format!(
+                    "Scenario: {}: filter terrain needs a scene terrain with a dem or grid_cell_size",
+                    se.name
+                )
+                .into());
+            }
+            if let Some(prior) = filter_terrain.agl_prior
+                && !(prior.std.is_finite() && prior.std > 0.0)
+            {
+                return Err(format!("Scenario: {}: agl_prior std must be > 0", se.name).into());
+            }
+        }
+        Ok(())
+    }
+
+    // Everything but the visualizer, which the caller attaches if it wants one.
+    pub fn simulation_builder(
+        &self,
+    ) -> Result<SimulationBuilder<WhiteNoiseAcceleration>, Box<dyn Error>> {
+        self.validate()?;
+
+        let scene = self.scene.as_ref().map(SceneConfig::build).transpose()?;
+        let surroundings = Surroundings {
+            anchors: &self.anchors,
+            map_origin: self
+                .scene
+                .as_ref()
+                .and_then(|s| s.terrain.as_ref())
+                .map(|t| t.origin),
+            ground: scene
+                .as_ref()
+                .and_then(|s| s.terrain.as_ref())
+                .and_then(|t| t.ground.as_ref()),
+        };
+        let anchors = self.anchors.iter().map(AnchorConfig::build).collect();
+        let swarm_elements = self
+            .swarm_elements
+            .iter()
+            .enumerate()
+            .map(|(i, se)| match self.seed {
+                Some(seed) => se.build(&mut initial_particle_rng(seed, i), &surroundings),
+                None => se.build(&mut rand::rng(), &surroundings),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut builder = Simulation::builder()
+            .swarm_elements(swarm_elements)
+            .anchors(anchors)
+            .measurement_mode(self.measurement_mode)
+            .visualization_options(self.visualization.options);
+        if let Some(seed) = self.seed {
+            builder = builder.seed(seed);
+        }
+        if let Some(cooperation) = self.cooperation {
+            builder = builder.cooperation(cooperation);
+        }
+        if let Some(scene) = scene {
+            builder = builder.scene(scene);
+        }
+        Ok(builder)
+    }
+}
+
+fn check_std(name: &str, std: f64) -> Result<(), Box<dyn Error>> {
+    if std.is_finite() && std >= 0.0 {
+        Ok(())
+    } else {
+        Err(format!("Scenario: {name} must be finite and >= 0").into())
+    }
+}
+
+fn vector(v: [f64; 3]) -> Vector3<f64> {
+    Vector3::from(v)
+}
+
+// What the filters are set up against besides their own config.
+#[derive(Clone, Copy, Default)]
+pub struct Surroundings<'a> {
+    pub anchors: &'a [AnchorConfig],
+    // Map coordinates of the simulation origin, for footprints read from shapefiles.
+    pub map_origin: Option<[f64; 3]>,
+    // The scene's ground height grid.
+    pub ground: Option<&'a Arc<HeightGrid>>,
+}
+
+// Where an element really starts and how noisy its own ranging is, for enclosures
+// placed around its first range.
+#[derive(Debug, Clone, Copy)]
+pub struct ElementStart {
+    pub position: Vector3<f64>,
+    pub sd_ranging_noise: f64,
+}
+
+impl AnchorConfig {
+    pub fn build(&self) -> Anchor {
+        let mut anchor = Anchor::new(vector(self.position), self.sd_ranging_noise)
+            .with_likelihood(self.likelihood)
+            .with_range_errors(self.range_errors);
+        if let Some(twr) = self.twr {
+            anchor = anchor.with_twr(twr);
+        }
+        anchor
+    }
+}
+
+impl SwarmElementConfig {
+    // `rng` draws the initial particles.
+    pub fn build<R: Rng + ?Sized>(
+        &self,
+        rng: &mut R,
+        surroundings: &Surroundings,
+    ) -> Result<SwarmElement<WhiteNoiseAcceleration>, Box<dyn Error>> {
+        let dynamics_model = match self.dynamics {
+            DynamicsConfig::WhiteNoiseAcceleration {
+                position,
+                velocity,
+                mean_acceleration,
+                sigma_acceleration,
+            } => WhiteNoiseAcceleration::new(
+                vector(position),
+                vector(velocity),
+                vector(mean_acceleration),
+                vector(sigma_acceleration),
+            ),
+        };
+
+        let start = ElementStart {
+            position: dynamics_model.position(),
+            sd_ranging_noise: self.sd_ranging_noise,
+        };
+        let mut swarm_element = SwarmElement::new(
+            self.name.clone(),
+            dynamics_model,
+            self.filter.build(rng, surroundings, start)?,
+            self.sd_transmission_noise,
+            self.sd_ranging_noise,
+        )
+        .with_estimator(self.estimator)
+        .with_credible_level(self.credible_level);
+        if let Some(clock) = self.clock {
+            swarm_element = swarm_element.with_clock(clock);
+        }
+        Ok(swarm_element)
+    }
+}
+
+impl FilterConfig {
+    pub fn build<R: Rng + ?Sized>(
+        &self,
+        rng: &mut R,
+        surroundings: &Surroundings,
+        start: ElementStart,
+    ) -> Result<ParticleFilter, Box<dyn Error>> {
+        let enclosure = self.enclosure.build(rng, surroundings, start)?;
+        let mut particle_filter =
+            ParticleFilter::new_with_rng(&enclosure, self.num_particles, self.ess_tau, rng)
+                .with_resampling_scheme(self.resampling)
+                .with_roughening(self.roughening);
+
+        if let Some(k) = self.kld_sampling {
+            particle_filter = particle_filter.with_kld_sampling(KldSampling::new(
+                k.min_particles,
+                k.max_particles,
+                k.bin_size,
+                k.epsilon,
+                k.delta,
+            ));
+        }
+        if let Some(prior) = &self.velocity_prior {
+            let prior = prior.build(rng, surroundings, start)?;
+            particle_filter = particle_filter.with_velocity_state(&prior);
+        }
+        if let Some(c) = self.clock_state {
+            particle_filter = particle_filter.with_clock_state(c.max_bias, c.max_drift);
+        }
+        if let Some(t) = self.terrain {
+            let ground = surroundings
+                .ground
+                .ok_or("Scenario: filter terrain needs a ground height grid")?;
+            let mut constraint = TerrainConstraint::new(ground.clone(), t.below_ground);
+            if let Some(prior) = t.agl_prior {
+                constraint = constraint.with_agl_prior(prior);
+            }
+            particle_filter = particle_filter.with_terrain(constraint);
+        }
+        Ok(particle_filter)
+    }
+}
+
+impl EnclosureConfig {
+    pub fn build<R: Rng + ?Sized>(
+        &self,
+        rng: &mut R,
+        surroundings: &Surroundings,
+        start: ElementStart,
+    ) -> Result<Box<dyn DynEnclosure>, Box<dyn Error>> {
+        Ok(match self {
+            EnclosureConfig::BoundingBox { min, max } => {
+                Box::new(BoundingBox::new(vector(*min), vector(*max))?)
+            }
+            EnclosureConfig::Sphere { radius, origin } => {
+                Box::new(Sphere::new(*radius, vector(*origin))?)
+            }
+            EnclosureConfig::Cylinder {
+                radius,
+                base,
+                height,
+            } => Box::new(Cylinder::new(*radius, vector(*base), *height)?),
+            EnclosureConfig::Polygon {
+                vertices,
+                shapefile,
+                min_z,
+                max_z,
+            } => {
+                let vertices = match shapefile {
+                    Some(path) => {
+                        let [east, north, _] = surroundings.map_origin.unwrap_or_default();
+                        terrain_shape::load_footprint(path)
+                            .map_err(|e| format!("Scenario: cannot read {}: {e}", path.display()))?
+                            .into_iter()
+                            .map(|[x, y]| [x - east, y - north])
+                            .collect()
+                    }
+                    None => vertices.clone(),
+                };
+                Box::new(ExtrudedPolygon::new(vertices, *min_z, *max_z).map_err(|_| {
+                    "Scenario: polygon needs 3 vertices enclosing more than a sliver \
+                             of their bounding box"
+                })?)
+            }
+            EnclosureConfig::Gaussian { mean, std } => {
+                Box::new(Gaussian::new(vector(*mean), vector(*std))?)
+            }
+            EnclosureConfig::RangeShell {
+                anchor,
+                range,
+                sigmas,
+            } => {
+                let anchor = surroundings
+                    .anchors
+                    .get(*anchor)
+                    .ok_or("Scenario: range_shell anchor out of range")?;
+                let center = vector(anchor.position);
+                let std = start.sd_ranging_noise.hypot(anchor.sd_ranging_noise);
+                let range = match range {
+                    Some(range) => *range,
+                    None => {
+                        let noise: f64 = rng.sample(StandardNormal);
+                        ((start.position - center).norm() + std * noise).max(0.0)
+                    }
+                };
+                Box::new(SphericalShell::around_range(center, range, std, *sigmas)?)
+            }
+            EnclosureConfig::Union { parts } => {
+                let parts = parts
+                    .iter()
+                    .map(|part| Ok((part.weight, part.enclosure.build(rng, surroundings, start)?)))
+                    .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+                Box::new(Union::new(parts)?)
+            }
+        })
+    }
+
+    fn refers_to_anchors(&self) -> bool {
+        match self {
+            EnclosureConfig::RangeShell { .. } => true,
+            EnclosureConfig::Union { parts } => {
+                parts.iter().any(|part| part.enclosure.refers_to_anchors())
+            }
+            _ => false,
+        }
+    }
+
+    fn validate(&self, num_anchors: usize) -> Result<(), Box<dyn Error>> {
+        match self {
+            EnclosureConfig::Polygon {
+                vertices,
+                shapefile,
+                min_z,
+                max_z,
+            } => {
+                if vertices.is_empty() == shapefile.is_none() {
+                    return Err("polygon needs either vertices or a shapefile".into());
+                }
+                if !(min_z.is_finite() && max_z.is_finite() && min_z < max_z) {
+                    return Err("polygon min_z must be below max_z, both finite".into());
+                }
+            }
+            EnclosureConfig::Gaussian { std, .. } => {
+                for &std in std {
+                    check_std("gaussian std", std)?;
+                }
+            }
+            EnclosureConfig::RangeShell {
+                anchor,
+                range,
+                sigmas,
+            } => {
+                if *anchor >= num_anchors {
+                    return Err(format!("range_shell anchor {anchor} does not exist").into());
+                }
+                if range.is_some_and(|r| !(r.is_finite() && r >= 0.0)) {
+                    return Err("range_shell range must be >= 0".into());
+                }
+                if !(sigmas.is_finite() && *sigmas > 0.0) {
+                    return Err("range_shell sigmas must be > 0".into());
+                }
+            }
+            EnclosureConfig::Union { parts } => {
+                if parts.is_empty() {
+                    return Err("union needs at least one part".into());
+                }
+                for part in parts {
+                    if !(part.weight.is_finite() && part.weight > 0.0) {
+                        return Err("union weights must be > 0".into());
+                    }
+                    part.enclosure.validate(num_anchors)?;
+                }
+            }
+            EnclosureConfig::BoundingBox { min, max } => {
+                if !min.iter().zip(max).all(|(lo, hi)| lo < hi) {
+                    return Err("bounding_box min must be below max on every axis".into());
+                }
+            }
+            EnclosureConfig::Sphere { radius, .. } => {
+                if !(radius.is_finite() && *radius > 0.0) {
+                    return Err("sphere radius must be > 0".into());
+                }
+            }
+            EnclosureConfig::Cylinder { radius, height, .. } => {
+                if !(radius.is_finite() && *radius > 0.0 && height.is_finite() && *height > 0.0) {
+                    return Err("cylinder radius and height must be > 0".into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SceneConfig {
+    pub fn build(&self) -> Result<Scene, Box<dyn Error>> {
+        let mut scene = Scene::new(self.blocked_link);
+        for obstacle in &self.obstacles {
+            let obstacle = match obstacle {
+                ObstacleConfig::AxisAlignedBox { min, max } => Obstacle::AxisAlignedBox {
+                    min: vector(*min),
+                    max: vector(*max),
+                },
+                ObstacleConfig::Plane { point, normal } => Obstacle::Plane {
+                    point: vector(*point),
+                    normal: vector(*normal),
+                },
+            };
+            scene = scene.with_obstacle(obstacle);
+        }
+        if let Some(t) = &self.terrain {
+            let origin = vector(t.origin);
+            let mut terrain = match &t.shapefile {
+                Some(path) => Terrain::from_shapefile(path, origin, t.vertical_exaggeration)?,
+                None => Terrain::new(Vec::new(), t.vertical_exaggeration),
+            };
+            let ground = match (&t.dem, t.grid_cell_size) {
+                (Some(dem), _) => Some(HeightGrid::read_ascii_grid(dem)?.shifted(origin)),
+                (None, Some(_)) if terrain.contours.iter().all(|c| c.is_empty()) => {
+                    return Err("Scenario: terrain shapefile has no contours".into());
+                }
+                (None, Some(cell_size)) => {
+                    Some(HeightGrid::from_contours(&terrain.contours, cell_size))
+                }
+                (None, None) => None,
+            };
+            if let Some(ground) = ground {
+                terrain = terrain.with_ground(Arc::new(ground));
+            }
+            if let Some(cell_size) = t.cell_size {
+                scene = scene.with_obstacle(Obstacle::Terrain(terrain.heights(cell_size)));
+            }
+            scene = scene.with_terrain(terrain);
+        }
+        Ok(scene)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULT: &str = include_str!("../../scenarios/default.toml");
+    const COOPERATIVE: &str = include_str!("../../scenarios/cooperative.json");
+
+    #[test]
+    fn bundled_scenarios_load_and_build() {
+        let default = Scenario::from_toml_str(DEFAULT).unwrap();
+        assert_eq!(default.steps, 1000);
+        assert_eq!(default.anchors.len(), 3);
+        let sim = default.simulation_builder().unwrap().build();
+        assert_eq!(sim.swarm_elements[0].particle_filter.particles.len(), 5_000);
+        assert_eq!(sim.anchors[1].position, Vector3::new(0.0, 50.0, 0.0));
+
+        let cooperative = Scenario::from_json_str(COOPERATIVE).unwrap();
+        assert!(cooperative.cooperation.is_some());
+        let sim = cooperative.simulation_builder().unwrap().build();
+        assert_eq!(sim.swarm_elements.len(), 3);
+    }
+
+    #[test]
+    fn toml_and_json_describe_the_same_scenario() {
+        let scenario = Scenario::from_toml_str(DEFAULT).unwrap();
+        let json = serde_json::to_string(&scenario).unwrap();
+        assert_eq!(Scenario::from_json_str(&json).unwrap(), scenario);
+        let toml = toml::to_string(&scenario).unwrap();
+        assert_eq!(Scenario::from_toml_str(&toml).unwrap(), scenario);
+    }
+
+    #[test]
+    fn optional_sections_take_defaults() {
+        let text = r#"
+            steps = 10
+            step_size = 0.1
+
+            [[anchors]]
+            position = [0.0, 0.0, 0.0]
+            sd_ranging_noise = 0.5
+
+            [[swarm_elements]]
+            name = "a"
+            sd_transmission_noise = 0.1
+            sd_ranging_noise = 0.1
+            dynamics.white_noise_acceleration = { position = [0.0, 0.0, 0.0], velocity = [0.0, 0.0, 0.0], mean_acceleration = [0.0, 0.0, 0.0], sigma_acceleration = [0.1, 0.1, 0.1] }
+            filter = { num_particles = 100, ess_tau = 0.5, enclosure.sphere = { radius = 10.0, origin = [0.0, 0.0, 0.0] } }
+        "#;
+        let scenario = Scenario::from_toml_str(text).unwrap();
+        assert_eq!(scenario.measurement_mode, MeasurementMode::Toa);
+        assert_eq!(scenario.visualization, VisualizationConfig::default());
+        assert_eq!(
+            scenario.swarm_elements[0].estimator,
+            PointEstimator::WeightedMean
+        );
+        assert_eq!(
+            scenario.swarm_elements[0].credible_level,
+            DEFAULT_CREDIBLE_LEVEL
+        );
+        assert!(scenario.cooperation.is_none() && scenario.scene.is_none());
+        assert_eq!(scenario.seed, None);
+        assert_eq!(
+            scenario.swarm_elements[0].filter.roughening,
+            DEFAULT_ROUGHENING
+        );
+    }
+
+    #[test]
+    fn seeded_scenarios_replay_exactly() {
+        let mut scenario = Scenario::from_json_str(COOPERATIVE).unwrap();
+        scenario.visualization.enabled = false;
+
+        let run = |seed: u64| {
+            let mut scenario = scenario.clone();
+            scenario.seed = Some(seed);
+            let mut sim = scenario.simulation_builder().unwrap().build();
+            assert_eq!(sim.seed(), Some(seed));
+            sim.run(5, scenario.step_size);
+            sim.swarm_elements
+        };
+
+        let first = run(11);
+        assert_eq!(first, run(11));
+        assert_ne!(
+            first.iter().map(|se| se.est_position).collect::<Vec<_>>(),
+            run(12).iter().map(|se| se.est_position).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn batch_runs_get_their_own_files() {
+        assert_eq!(
+            with_run_seed(Path::new("out/trace.csv"), Some(7)),
+            PathBuf::from("out/trace_7.csv")
+        );
+        assert_eq!(
+            with_run_seed(Path::new("trace"), Some(7)),
+            PathBuf::from("trace_7")
+        );
+        assert_eq!(
+            with_run_seed(Path::new("out/a.rrd"), None),
+            PathBuf::from("out/a.rrd")
+        );
+    }
+
+    #[test]
+    fn terrain_is_configured_under_the_scene() {
+        let mut scenario = Scenario::from_toml_str(&format!(
+            r#"{DEFAULT}
+            [scene.terrain]
+            shapefile = "missing/contours.shp"
+            origin = [512000.0, 6650000.0, 120.0]
+            cell_size = 2.0
+            "#
+        ))
+        .unwrap();
+        let scene = scenario.scene.as_ref().unwrap();
+        assert_eq!(scene.blocked_link, BlockedLink::Dropped);
+        let terrain = scene.terrain.as_ref().unwrap();
+        assert_eq!(terrain.vertical_exaggeration, 1.0);
+        assert_eq!(terrain.cell_size, Some(2.0));
+
+        let err = scenario.simulation_builder().err().unwrap().to_string();
+        assert!(err.contains("missing/contours.shp"), "{err}");
+
+        let scene = scenario.scene.as_mut().unwrap();
+        scene.terrain.as_mut().unwrap().vertical_exaggeration = 0.0;
+        assert!(scenario.validate().is_err());
+    }
+
+    #[test]
+    fn enclosures_combine_in_a_union() {
+        use agents::particle_filter::Particle;
+
+        let text = DEFAULT.replace(
+            "[swarm_elements.filter.enclosure.sphere]\nradius = 200.0\norigin = [10.0, 10.0, 5.0]",
+            r#"[[swarm_elements.filter.enclosure.union.parts]]
+            weight = 3.0
+            range_shell = { anchor = 2 }
+
+            [[swarm_elements.filter.enclosure.union.parts]]
+            weight = 1.0
+            polygon = { vertices = [[-100.0, -100.0], [-100.0, -90.0], [-90.0, -100.0]], min_z = 0.0, max_z = 2.0 }"#,
+        );
+        let mut scenario = Scenario::from_toml_str(&text).unwrap();
+        scenario.visualization.enabled = false;
+        scenario.seed = Some(3);
+        let sim = scenario.simulation_builder().unwrap().build();
+
+        // true start [100, 20, 5] is about 54 m from the anchor at [50, 0, 0]
+        let anchor = Vector3::new(50.0, 0.0, 0.0);
+        let particles = &sim.swarm_elements[0].particle_filter.particles;
+        let (footprint, shell): (Vec<&Particle>, Vec<&Particle>) =
+            particles.iter().partition(|p| p.position.x < -50.0);
+        assert!((footprint.len() as f64 / particles.len() as f64 - 0.25).abs() < 0.03);
+        assert!(
+            footprint
+                .iter()
+                .all(|p| (0.0..=2.0).contains(&p.position.z))
+        );
+        assert!(
+            shell
+                .iter()
+                .all(|p| ((p.position - anchor).norm() - 54.1).abs() < 6.0)
+        );
+
+        let filter = &mut scenario.swarm_elements[0].filter;
+        filter.velocity_prior = Some(EnclosureConfig::RangeShell {
+            anchor: 0,
+            range: None,
+            sigmas: 3.0,
+        });
+        assert!(scenario.validate().is_err());
+
+        let filter = &mut scenario.swarm_elements[0].filter;
+        filter.velocity_prior = None;
+        filter.enclosure = EnclosureConfig::RangeShell {
+            anchor: 3,
+            range: Some(10.0),
+            sigmas: 3.0,
+        };
+        assert!(scenario.validate().is_err());
+    }
+
+    #[test]
+    fn filters_stay_above_a_dem() {
+        // flat ground at 120 m over 600 m by 600 m around the origin
+        let mut dem =
+            String::from("ncols 6\nnrows 6\nxllcorner 511700\nyllcorner 6649700\ncellsize 100\n");
+        dem.push_str(&"120 120 120 120 120 120\n".repeat(6));
+        let path = std::env::temp_dir().join(format!("dem_{}.asc", std::process::id()));
+        fs::write(&path, dem).unwrap();
+
+        let text = format!(
+            r#"{DEFAULT}
+            [swarm_elements.filter.terrain]
+            below_ground = "reflect"
+            agl_prior = {{ mean = 5.0, std = 50.0 }}
+
+            [scene.terrain]
+            dem = {path:?}
+            origin = [512000.0, 6650000.0, 120.0]
+            "#
+        );
+        let mut scenario = Scenario::from_toml_str(&text).unwrap();
+        scenario.visualization.enabled = false;
+        scenario.seed = Some(5);
+        let mut sim = scenario.simulation_builder().unwrap().build();
+        fs::remove_file(&path).unwrap();
+
+        let terrain = sim.scene.as_ref().unwrap().terrain.as_ref().unwrap();
+        assert_eq!(
+            terrain.ground.as_ref().unwrap().height_at(0.0, 0.0),
+            Some(0.0)
+        );
+        assert_eq!(terrain.contours.len(), 6);
+        sim.run(5, scenario.step_size);
+        for se in &sim.swarm_elements {
+            assert!(se.dynamics_model.position().z > 0.0);
+            let pf = &se.particle_filter;
+            assert_eq!(pf.terrain().unwrap().below_ground, BelowGround::Reflect);
+            assert!(pf.particles.iter().all(|p| p.position.z >= 0.0));
+        }
+
+        // without a height grid the filters have no ground to keep to
+        scenario
+            .scene
+            .as_mut()
+            .unwrap()
+            .terrain
+            .as_mut()
+            .unwrap()
+            .dem = None;
+        scenario
+            .scene
+            .as_mut()
+            .unwrap()
+            .terrain
+            .as_mut()
+            .unwrap()
+            .shapefile = Some(PathBuf::from("contours.shp"));
+        let err = scenario.validate().unwrap_err().to_string();
+        assert!(err.contains("dem or grid_cell_size"), "{err}");
+    }
+
+    #[test]
+    fn replaying_a_recording_reproduces_the_run() {
+        use crate::recording::{LogFormat, Record, RecordWriter, read_csv, steps};
+
+        let mut scenario = Scenario::from_json_str(COOPERATIVE).unwrap();
+        scenario.visualization.enabled = false;
+        scenario.seed = Some(3);
+
+        let mut live = scenario.simulation_builder().unwrap().build();
+        let mut writer = RecordWriter::new(Vec::new(), LogFormat::Csv).unwrap();
+        for frame in 0..5 {
+            let records = live.step(frame, scenario.step_size);
+            writer.write_all(&records).unwrap();
+        }
+        writer.flush().unwrap();
+        let log = read_csv(writer.into_inner().as_slice()).unwrap();
+
+        let mut replayed = scenario.simulation_builder().unwrap().build();
+        for (frame, step) in steps(&log).enumerate() {
+            replayed.replay_step(frame, step);
+        }
+        assert_eq!(replayed.time(), live.time());
+        for (a, b) in live.swarm_elements.iter().zip(&replayed.swarm_elements) {
+            assert_eq!(a.est_position, b.est_position);
+            assert_eq!(a.dynamics_model.position(), b.dynamics_model.position());
+        }
+
+        // exact ranges from a real log are skipped rather than panicking the filters
+        let exact = [
+            Record::Step {
+                time: live.time() + 0.1,
+                dt: 0.1,
+            },
+            Record::Range {
+                agent: 0,
+                anchor: 0,
+                range: 5.0,
+                std: 0.0,
+            },
+            Record::PeerRange {
+                agent: 0,
+                neighbour: 1,
+                range: 3.0,
+                std: 0.0,
+            },
+        ];
+        replayed.replay_step(5, &exact);
+        assert!(
+            replayed.swarm_elements[0]
+                .est_position
+                .iter()
+                .all(|x| x.is_finite())
+        );
+    }
+
+    #[test]
+    fn invalid_values_are_reported_not_panicked() {
+        let mut scenario = Scenario::from_toml_str(DEFAULT).unwrap();
+        scenario.measurement_mode = MeasurementMode::Tdoa { reference: 7 };
+        assert!(scenario.validate().is_err());
+
+        let mut scenario = Scenario::from_toml_str(DEFAULT).unwrap();
+        scenario.swarm_elements[0].filter.ess_tau = 2.0;
+        assert!(scenario.simulation_builder().is_err());
+
+        assert!(Scenario::from_toml_str("steps = 1").is_err());
+
+        // models the simulation would only trip over mid-run
+        let text = DEFAULT.replacen(
+            "sd_ranging_noise = 0.4\n",
+            "sd_ranging_noise = 0.4\nrange_errors = { student_t = { nu = -1.0 } }\n",
+            1,
+        );
+        let err = Scenario::from_toml_str(&text).err().unwrap().to_string();
+        assert!(err.contains("anchor 0") && err.contains("nu"), "{err}");
+
+        let mut scenario = Scenario::from_toml_str(DEFAULT).unwrap();
+        scenario.anchors[1].twr = Some(TwrConfig {
+            reply_delay: 0.0,
+            ..Default::default()
+        });
+        assert!(scenario.validate().is_err());
+
+        let mut scenario = Scenario::from_toml_str(DEFAULT).unwrap();
+        scenario.swarm_elements[0].estimator = PointEstimator::MeanShift {
+            bandwidth: 0.0,
+            max_iterations: 50,
+            tolerance: 1e-6,
+        };
+        let err = scenario.validate().unwrap_err().to_string();
+        assert!(err.contains("bandwidth"), "{err}");
+
+        // exact ranges are only a problem when neither end adds noise
+        let mut scenario = Scenario::from_toml_str(DEFAULT).unwrap();
+        scenario.anchors[1].sd_ranging_noise = 0.0;
+        assert!(scenario.validate().is_ok());
+        for se in &mut scenario.swarm_elements {
+            se.sd_ranging_noise = 0.0;
+        }
+        let err = scenario.validate().unwrap_err().to_string();
+        assert!(err.contains("anchor 1"), "{err}");
+        scenario.measurement_mode = MeasurementMode::Tdoa { reference: 0 };
+        assert!(scenario.validate().is_ok());
+
+        let enclosures = [
+            EnclosureConfig::BoundingBox {
+                min: [0.0, 0.0, 1.0],
+                max: [1.0, 1.0, 0.0],
+            },
+            EnclosureConfig::Sphere {
+                radius: 0.0,
+                origin: [0.0; 3],
+            },
+            EnclosureConfig::Cylinder {
+                radius: 1.0,
+                base: [0.0; 3],
+                height: -1.0,
+            },
+            EnclosureConfig::Polygon {
+                vertices: vec![[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]],
+                shapefile: None,
+                min_z: 1.0,
+                max_z: 1.0,
+            },
+        ];
+        for enclosure in enclosures {
+            let mut scenario = Scenario::from_toml_str(DEFAULT).unwrap();
+            scenario.swarm_elements[0].filter.enclosure = enclosure;
+            assert!(scenario.validate().is_err());
+        }
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use nalgebra::Vector3;
+use rand::Rng;
+use rand_distr::{Distribution, Normal};
+use serde::{Deserialize, Serialize};
+
+use agents::terrain::HeightGrid;
+use visualization::terrain_shape;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Obstacle {
+    AxisAlignedBox {
+        min: Vector3<f64>,
+        max: Vector3<f64>,
+    },
+    // Infinite plane through `point`; a link is blocked when its ends lie on opposite sides.
+    Plane {
+        point: Vector3<f64>,
+        normal: Vector3<f64>,
+    },
+    Terrain(TerrainHeights),
+}
+
+// Conservative height field: highest contour vertex per horizontal cell.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TerrainHeights {
+    cell_size: f64,
+    heights: HashMap<(i64, i64), f64>,
+}
+
+impl TerrainHeights {
+    pub fn from_contours(contours: &[Vec<[f64; 3]>], cell_size: f64) -> Self {
+        assert!(cell_size > 0.0, "TerrainHeights: cell_size must be > 0");
+        let mut heights: HashMap<(i64, i64), f64> = HashMap::new();
+        for &[x, y, z] in contours.iter().flatten() {
+            let cell = Self::cell(x, y, cell_size);
+            let h = heights.entry(cell).or_insert(f64::NEG_INFINITY);
+            *h = h.max(z);
+        }
+        Self { cell_size, heights }
+    }
+
+    pub fn height_at(&self, x: f64, y: f64) -> Option<f64> {
+        self.heights.get(&Self::cell(x, y, self.cell_size)).copied()
+    }
+
+    fn cell(x: f64, y: f64, cell_size: f64) -> (i64, i64) {
+        (
+            (x / cell_size).floor() as i64,
+            (y / cell_size).floor() as i64,
+        )
+    }
+
+    fn blocks(&self, a: &Vector3<f64>, b: &Vector3<f64>) -> bool {
+        // sample the interior of the link at half-cell spacing, endpoints may rest on the ground
+        let steps = (((b - a).norm() / (0.5 * self.cell_size)).ceil() as usize).max(2);
+        (1..steps).any(|i| {
+            let p = a + (b - a) * (i as f64 / steps as f64);
+            self.height_at(p.x, p.y).is_some_and(|h| p.z < h)
+        })
+    }
+}
+
+impl Obstacle {
+    pub fn blocks(&self, a: &Vector3<f64>, b: &Vector3<f64>) -> bool {
+        match self {
+            Obstacle::AxisAlignedBox { min, max } => segment_hits_box(a, b, min, max),
+            Obstacle::Plane { point, normal } => {
+                let da = (a - point).dot(normal);
+                let db = (b - point).dot(normal);
+                da * db < 0.0
+            }
+            Obstacle::Terrain(terrain) => terrain.blocks(a, b),
+        }
+    }
+}
+
+// Slab test restricted to the segment parameter range [0, 1].
+fn segment_hits_box(
+    a: &Vector3<f64>,
+    b: &Vector3<f64>,
+    min: &Vector3<f64>,
+    max: &Vector3<f64>,
+) -> bool {
+    let d = b - a;
+    let mut t0: f64 = 0.0;
+    let mut t1: f64 = 1.0;
+    for i in 0..3 {
+        if d[i].abs() < 1e-12 {
+            if a[i] < min[i] || a[i] > max[i] {
+                return false;
+            }
+            continue;
+        }
+        let inv = 1.0 / d[i];
+        let mut near = (min[i] - a[i]) * inv;
+        let mut far = (max[i] - a[i]) * inv;
+        if near > far {
+            std::mem::swap(&mut near, &mut far);
+        }
+        t0 = t0.max(near);
+        t1 = t1.min(far);
+        if t0 > t1 {
+            return false;
+        }
+    }
+    true
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockedLink {
+    // Add a positive excess delay (metres) and extra zero-mean noise to the range.
+    Biased {
+        excess_delay: f64,
+        extra_std: f64,
+    },
+    // No range is produced for a blocked link.
+    #[default]
+    Dropped,
+}
+
+// Contour lines around the flight area, in the simulation frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Terrain {
+    pub contours: Vec<Vec<[f64; 3]>>,
+    // Applied when drawing only; links are blocked by the true heights.
+    pub vertical_exaggeration: f64,
+    // Ground height for the filters, from a DEM or interpolated from the contours.
+    pub ground: Option<Arc<HeightGrid>>,
+}
+
+// Profiles drawn for a DEM without contours, at most.
+const MAX_DRAWN_PROFILES: usize = 100;
+
+impl Terrain {
+    pub fn new(contours: Vec<Vec<[f64; 3]>>, vertical_exaggeration: f64) -> Self {
+        assert!(
+            vertical_exaggeration > 0.0,
+            "Terrain: vertical_exaggeration must be > 0"
+        );
+        Self {
+            contours,
+            vertical_exaggeration,
+            ground: None,
+        }
+    }
+
+    // Without contours, the ground is drawn as west to east profiles.
+    pub fn with_ground(mut self, ground: Arc<HeightGrid>) -> Self {
+        if self.contours.is_empty() {
+            let (_, nrows) = ground.dimensions();
+            self.contours = ground.rows(nrows.div_ceil(MAX_DRAWN_PROFILES));
+        }
+        self.ground = Some(ground);
+        self
+    }
+
+    // `origin` is where the simulation origin lies in the map coordinates of the
+    // file, so anchors, agents and terrain share one frame.
+    pub fn from_shapefile(
+        path: impl AsRef<Path>,
+        origin: Vector3<f64>,
+        vertical_exaggeration: f64,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let contours = terrain_shape::load_contours(path)
+            .map_err(|e| format!("Terrain: cannot read {}: {e}", path.display()))?;
+        let contours = terrain_shape::to_origin_and_scale(contours, origin.into(), 1.0);
+        Ok(Self::new(contours, vertical_exaggeration))
+    }
+
+    // The contours as drawn, heights stretched by the exaggeration.
+    pub fn drawn_contours(&self) -> Vec<Vec<[f64; 3]>> {
+        terrain_shape::to_origin_and_scale(
+            self.contours.clone(),
+            [0.0; 3],
+            self.vertical_exaggeration,
+        )
+    }
+
+    // Taken from the height grid, when there is one.
+    pub fn heights(&self, cell_size: f64) -> TerrainHeights {
+        match &self.ground {
+            Some(ground) => TerrainHeights::from_contours(&ground.rows(1), cell_size),
+            None => TerrainHeights::from_contours(&self.contours, cell_size),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scene {
+    pub obstacles: Vec<Obstacle>,
+    pub blocked_link: BlockedLink,
+    // Drawn as static geometry when the visualizer starts.
+    pub terrain: Option<Terrain>,
+}
+
+impl Scene {
+    pub fn new(blocked_link: BlockedLink) -> Self {
+        Self {
+            obstacles: Vec::new(),
+            blocked_link,
+            terrain: None,
+        }
+    }
+
+    pub fn with_obstacle(mut self, obstacle: Obstacle) -> Self {
+        self.obstacles.push(obstacle);
+        self
+    }
+
+    pub fn with_terrain(mut self, terrain: Terrain) -> Self {
+        self.terrain = Some(terrain);
+        self
+    }
+
+    pub fn is_blocked(&self, a: &Vector3<f64>, b: &Vector3<f64>) -> bool {
+        self.obstacles.iter().any(|o| o.blocks(a, b))
+    }
+
+    // Apply the blocked-link policy to a line-of-sight range, `None` if the link is dropped.
+    pub fn corrupt_range<R: Rng + ?Sized>(&self, range: f64, rng: &mut R) -> Option<f64> {
+        match self.blocked_link {
+            BlockedLink::Biased {
+                excess_delay,
+                extra_std,
+            } => {
+                let noise = Normal::new(0.0, extra_std).expect("Scene: invalid extra_std");
+                Some(range + excess_delay + noise.sample(rng))
+            }
+            BlockedLink::Dropped => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{SeedableRng, rngs::StdRng};
+
+    fn wall() -> Obstacle {
+        Obstacle::AxisAlignedBox {
+            min: Vector3::new(4.0, -1.0, 0.0),
+            max: Vector3::new(5.0, 1.0, 3.0),
+        }
+    }
+
+    #[test]
+    fn box_blocks_only_crossing_links() {
+        let anchor = Vector3::new(0.0, 0.0, 1.0);
+        assert!(wall().blocks(&anchor, &Vector3::new(10.0, 0.0, 1.0)));
+        // passes above the wall
+        assert!(!wall().blocks(&anchor, &Vector3::new(10.0, 0.0, 10.0)));
+        // stops short of the wall
+        assert!(!wall().blocks(&anchor, &Vector3::new(3.0, 0.0, 1.0)));
+        // parallel to the wall, outside of it
+        assert!(!wall().blocks(&Vector3::new(0.0, 2.0, 1.0), &Vector3::new(10.0, 2.0, 1.0)));
+    }
+
+    #[test]
+    fn plane_blocks_links_between_half_spaces() {
+        let floor = Obstacle::Plane {
+            point: Vector3::new(0.0, 0.0, 2.0),
+            normal: Vector3::z(),
+        };
+        assert!(floor.blocks(&Vector3::new(0.0, 0.0, 0.0), &Vector3::new(5.0, 5.0, 5.0)));
+        assert!(!floor.blocks(&Vector3::new(0.0, 0.0, 3.0), &Vector3::new(5.0, 5.0, 5.0)));
+    }
+
+    #[test]
+    fn terrain_blocks_links_through_a_ridge() {
+        // a ridge line at x = 5 rising to 10 m, flat ground elsewhere
+        let ridge: Vec<[f64; 3]> = (0..=20).map(|y| [5.0, y as f64, 10.0]).collect();
+        let ground: Vec<[f64; 3]> = (0..=20)
+            .flat_map(|x| (0..=20).map(move |y| [x as f64, y as f64, 0.0]))
+            .collect();
+        let terrain = Obstacle::Terrain(TerrainHeights::from_contours(&[ridge, ground], 1.0));
+
+        let a = Vector3::new(0.5, 10.5, 1.0);
+        assert!(terrain.blocks(&a, &Vector3::new(9.5, 10.5, 1.0)));
+        assert!(!terrain.blocks(&a, &Vector3::new(9.5, 10.5, 30.0)));
+        assert!(!terrain.blocks(&a, &Vector3::new(3.5, 10.5, 1.0)));
+    }
+
+    #[test]
+    fn blocked_link_policies() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let biased = Scene::new(BlockedLink::Biased {
+            excess_delay: 2.0,
+            extra_std: 0.5,
+        })
+        .with_obstacle(wall());
+
+        let a = Vector3::new(0.0, 0.0, 1.0);
+        let b = Vector3::new(10.0, 0.0, 1.0);
+        assert!(biased.is_blocked(&a, &b));
+
+        let n = 50_000;
+        let ranges: Vec<f64> = (0..n)
+            .map(|_| biased.corrupt_range(10.0, &mut rng).unwrap())
+            .collect();
+        let mean = ranges.iter().sum::<f64>() / n as f64;
+        let var = ranges.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / n as f64;
+        assert!((mean - 12.0).abs() <= 0.02, "mean {mean}");
+        assert!((var - 0.25).abs() <= 0.01, "var {var}");
+
+        let dropped = Scene::new(BlockedLink::Dropped).with_obstacle(wall());
+        assert_eq!(dropped.corrupt_range(10.0, &mut rng), None);
+    }
+}
@@ -1,22 +1,136 @@
-use colorous::INFERNO;
+use std::io;
+use std::time::Instant;
+
+use colorous::{INFERNO, RED_BLUE};
 use nalgebra::Vector3;
+use rand::rngs::StdRng;
+use rand_distr::Distribution;
+use serde::{Deserialize, Serialize};
 
 use agents::{
-    Measurements, anchor, dynamics_model::DynamicsModel, particle_filter::Particle, swarm_element,
+    Measurements, anchor,
+    clock::SPEED_OF_LIGHT,
+    cooperative::NeighbourBelief,
+    dynamics_model::DynamicsModel,
+    particle_filter::Particle,
+    rng::RngStream,
+    swarm_element,
+    tdoa::{Arrival, TdoaMeasurement},
 };
-use visualization::visualization::{Command, RerunVisualization};
+use visualization::visualization::{Command, FanoutVisualizer, Visualizer};
+
+use crate::cooperative::Cooperation;
+use crate::recording::Record;
+use crate::scene::Scene;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MeasurementMode {
+    // Absolute ranges to every anchor.
+    #[default]
+    Toa,
+    // Range differences against `anchors[reference]`.
+    Tdoa {
+        reference: usize,
+    },
+    // Arrival timestamps (s) biased by the tag clock.
+    RawToa,
+}
+
+// Marker sizes used when logging a frame, and the optional layers to draw.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VisualizationOptions {
+    pub particle_size: f64,
+    pub swarm_size: f64,
+    pub anchors_size: f64,
+    // Credible ellipsoid of each element's position.
+    pub ellipsoids: bool,
+    // Sphere of the measured range around each anchor, per element.
+    pub range_spheres: bool,
+    // Anchor to estimate lines, coloured by range residual.
+    pub residual_links: bool,
+    // Time series of each filter's health, see `FilterHealth`.
+    pub health_plots: bool,
+}
+
+impl Default for VisualizationOptions {
+    fn default() -> Self {
+        Self {
+            particle_size: 1.0,
+            swarm_size: 6.0,
+            anchors_size: 6.0,
+            ellipsoids: true,
+            range_spheres: false,
+            residual_links: true,
+            health_plots: true,
+        }
+    }
+}
+
+// How each filter fared in the last step, for the health plots.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FilterHealth {
+    // Effective sample size before resampling.
+    pub ess: f64,
+    pub resampled: bool,
+    // Log marginal likelihood of the step's measurements.
+    pub log_likelihood: f64,
+    // (anchor, range residual against the prediction over its std, which includes
+    // the predicted spread towards the anchor)
+    pub innovations: Vec<(usize, f64)>,
+    // Square root of the trace of the position covariance (m).
+    pub spread: f64,
+    // Wall time spent filtering (s).
+    pub runtime_s: f64,
+}
+
+// Ids of the streams split off a run's seed.
+const INITIAL_STREAMS: u64 = 0;
+const AGENT_STREAMS: u64 = 1;
+const ANCHOR_STREAMS: u64 = 2;
+const LINK_STREAM: u64 = 3;
+const BELIEF_STREAM: u64 = 4;
+const TRANSMIT_STREAM: u64 = 5;
+
+// Generator for the initial particles of element `index` in a run seeded with `seed`.
+pub fn initial_particle_rng(seed: u64, index: usize) -> StdRng {
+    RngStream::new(seed)
+        .substream(INITIAL_STREAMS)
+        .substream(index as u64)
+        .next_rng()
+}
 
 pub struct Simulation<M: DynamicsModel> {
     pub swarm_elements: Vec<swarm_element::SwarmElement<M>>,
     pub anchors: Vec<anchor::Anchor>,
-    visualizer: Option<RerunVisualization>,
+    pub scene: Option<Scene>,
+    pub cooperation: Option<Cooperation>,
+    pub measurement_mode: MeasurementMode,
+    pub visualization_options: VisualizationOptions,
+    visualizer: Option<Box<dyn Visualizer>>,
+    seed: Option<u64>,
+    // One stream per anchor, in the order of `anchors`, and one for inter-agent links.
+    anchor_rngs: Vec<RngStream>,
+    link_rng: RngStream,
+    // Filter side of cooperation, apart from the links so a replay draws the same.
+    belief_rng: RngStream,
+    // Tag transmit errors, common to all anchors in TDOA.
+    transmit_rng: RngStream,
+    time: f64,
+    health: Vec<FilterHealth>,
 }
 
 pub struct SimulationBuilder<M: DynamicsModel> {
     swarm_elements: Option<Vec<swarm_element::SwarmElement<M>>>,
     anchors: Option<Vec<anchor::Anchor>>,
+    scene: Option<Scene>,
+    cooperation: Option<Cooperation>,
+    measurement_mode: MeasurementMode,
 
-    visualizer: Option<RerunVisualization>,
+    visualization_options: VisualizationOptions,
+    visualizers: Vec<Box<dyn Visualizer>>,
+    seed: Option<u64>,
 }
 
 impl<M: DynamicsModel> Simulation<M> {
@@ -24,54 +138,484 @@ impl<M: DynamicsModel> Simulation<M> {
         SimulationBuilder {
             swarm_elements: None,
             anchors: None,
-            visualizer: None,
+            scene: None,
+            cooperation: None,
+            measurement_mode: MeasurementMode::default(),
+            visualization_options: VisualizationOptions::default(),
+            visualizers: Vec::new(),
+            seed: None,
         }
     }
 
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
     pub fn run(&mut self, steps: usize, step_size: f64)
     where
         M: Sync,
     {
         for frame in 0..steps {
-            for se in &mut self.swarm_elements {
-                se.step(step_size);
+            self.step(frame, step_size);
+        }
+    }
+
+    // Flush the visualizers after the last step, e.g. to see that a trace was written.
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self.visualizer.as_mut() {
+            Some(viz) => viz.finish(),
+            None => Ok(()),
+        }
+    }
+
+    // Advance every element by one time step: move the truth, measure, predict, update,
+    // estimate, resample. Returns what was measured, e.g. for a recording.
+    pub fn step(&mut self, frame: usize, step_size: f64) -> Vec<Record>
+    where
+        M: Sync,
+    {
+        let records = self.measure(step_size);
+        self.filter(&records);
+        if self.visualizer.is_some() {
+            self.capture_frame(frame, &records);
+        }
+        records
+    }
+
+    // Run the filters on one recorded step instead of simulated measurements. The
+    // truth, if recorded, replaces the simulated state.
+    pub fn replay_step(&mut self, frame: usize, records: &[Record])
+    where
+        M: Sync,
+    {
+        for record in records {
+            match *record {
+                Record::Step { time, .. } => self.time = time,
+                Record::Truth {
+                    agent,
+                    position,
+                    velocity,
+                    clock_offset,
+                } => {
+                    if let Some(se) = self.swarm_elements.get_mut(agent) {
+                        se.set_true_state(position, velocity, clock_offset);
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.filter(records);
+        if self.visualizer.is_some() {
+            self.capture_frame(frame, records);
+        }
+    }
+
+    // Simulated time (s) at the end of the last step.
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    // One entry per swarm element, from the last step.
+    pub fn health(&self) -> &[FilterHealth] {
+        &self.health
+    }
+
+    fn measure(&mut self, step_size: f64) -> Vec<Record> {
+        self.time += step_size;
+        let mut records = vec![Record::Step {
+            time: self.time,
+            dt: step_size,
+        }];
+
+        for (agent, se) in self.swarm_elements.iter_mut().enumerate() {
+            records.push(Record::Velocity {
+                agent,
+                velocity: se.measure_velocity(),
+                std: se.transmission_noise.std_dev(),
+            });
+            se.advance(step_size);
+            records.push(Record::Truth {
+                agent,
+                position: se.dynamics_model.position(),
+                velocity: se.dynamics_model.velocity(),
+                clock_offset: se.clock.offset,
+            });
+        }
+
+        for (agent, se) in self.swarm_elements.iter().enumerate() {
+            match self.measurement_mode {
+                MeasurementMode::Toa => Self::measure_ranges(
+                    agent,
+                    se,
+                    &self.anchors,
+                    &mut self.anchor_rngs,
+                    self.scene.as_ref(),
+                    None,
+                    &mut records,
+                ),
+                MeasurementMode::Tdoa { .. } => {
+                    let tag_error = se.ranging_noise.sample(&mut self.transmit_rng.next_rng());
+                    Self::measure_ranges(
+                        agent,
+                        se,
+                        &self.anchors,
+                        &mut self.anchor_rngs,
+                        self.scene.as_ref(),
+                        Some(tag_error),
+                        &mut records,
+                    )
+                }
+                MeasurementMode::RawToa => Self::measure_toa(
+                    agent,
+                    se,
+                    &self.anchors,
+                    &mut self.anchor_rngs,
+                    self.scene.as_ref(),
+                    &mut records,
+                ),
+            }
+        }
+
+        if let Some(cooperation) = self.cooperation {
+            self.measure_peer_ranges(&cooperation, &mut records);
+        }
+        records
+    }
+
+    // Without a `tag_error` every range draws the tag's and the anchor's noise on its
+    // own. With one, as for TDOA arrivals, the tag's transmit error is that single
+    // draw shared by all anchors, and each adds only its receive noise; the shared
+    // part cancels in the differences, so the recorded std is the receive noise.
+    fn measure_ranges(
+        agent: usize,
+        se: &swarm_element::SwarmElement<M>,
+        anchors: &[anchor::Anchor],
+        anchor_rngs: &mut [RngStream],
+        scene: Option<&Scene>,
+        tag_error: Option<f64>,
+        records: &mut Vec<Record>,
+    ) {
+        let var_rx = match tag_error {
+            Some(_) => 0.0,
+            None => se.ranging_noise.std_dev().powi(2),
+        };
+        let true_position = se.dynamics_model.position();
+        for (j, (anchor, anchor_rng)) in anchors.iter().zip(anchor_rngs.iter_mut()).enumerate() {
+            let mut rng = anchor_rng.next_rng();
+            let var_tx = anchor.ranging_noise.std_dev().powi(2);
+            let combined_std = (var_rx + var_tx).sqrt();
+            let mut range = anchor.ranging(se, combined_std, &mut rng) + tag_error.unwrap_or(0.0);
+
+            if let Some(scene) = scene
+                && scene.is_blocked(&anchor.position, &true_position)
+            {
+                match scene.corrupt_range(range, &mut rng) {
+                    Some(r) => range = r,
+                    None => continue,
+                }
+            }
+
+            records.push(Record::Range {
+                agent,
+                anchor: j,
+                range,
+                std: combined_std,
+            });
+        }
+    }
+
+    fn measure_toa(
+        agent: usize,
+        se: &swarm_element::SwarmElement<M>,
+        anchors: &[anchor::Anchor],
+        anchor_rngs: &mut [RngStream],
+        scene: Option<&Scene>,
+        records: &mut Vec<Record>,
+    ) {
+        let var_rx = se.ranging_noise.std_dev().powi(2);
+        let true_position = se.dynamics_model.position();
+        for (j, (anchor, anchor_rng)) in anchors.iter().zip(anchor_rngs.iter_mut()).enumerate() {
+            let mut rng = anchor_rng.next_rng();
+            let var_tx = anchor.ranging_noise.std_dev().powi(2);
+            let combined_std = (var_rx + var_tx).sqrt();
+            let mut toa = anchor.time_of_arrival(se, combined_std, &mut rng);
+
+            if let Some(scene) = scene
+                && scene.is_blocked(&anchor.position, &true_position)
+            {
+                // the blocked-link policy applied to a zero range gives the excess alone
+                match scene.corrupt_range(0.0, &mut rng) {
+                    Some(excess) => toa += excess / SPEED_OF_LIGHT,
+                    None => continue,
+                }
+            }
+
+            records.push(Record::Toa {
+                agent,
+                anchor: j,
+                toa,
+                std: combined_std,
+            });
+        }
+    }
+
+    fn measure_peer_ranges(&mut self, cooperation: &Cooperation, records: &mut Vec<Record>) {
+        let mut rng = self.link_rng.next_rng();
+        let positions: Vec<Vector3<f64>> = self
+            .swarm_elements
+            .iter()
+            .map(|se| se.dynamics_model.position())
+            .collect();
+
+        for (i, se) in self.swarm_elements.iter().enumerate() {
+            let var_rx = se.ranging_noise.std_dev().powi(2);
+            for j in cooperation.policy.neighbours(i, &positions) {
+                let neighbour = &self.swarm_elements[j];
+                let var_tx = neighbour.ranging_noise.std_dev().powi(2);
+                let combined_std = (var_rx + var_tx).sqrt();
+                let mut range = neighbour.ranging(se, combined_std, &mut rng);
+
+                if let Some(scene) = &self.scene
+                    && scene.is_blocked(&positions[i], &positions[j])
+                {
+                    match scene.corrupt_range(range, &mut rng) {
+                        Some(r) => range = r,
+                        None => continue,
+                    }
+                }
+                records.push(Record::PeerRange {
+                    agent: i,
+                    neighbour: j,
+                    range,
+                    std: combined_std,
+                });
             }
+        }
+    }
+
+    // Predict with the step's velocity readings, then weight with its measurements.
+    // Records that do not fit the measurement mode, name an unknown anchor or agent,
+    // or give a range std of 0 where the update needs one > 0, are ignored.
+    fn filter(&mut self, records: &[Record])
+    where
+        M: Sync,
+    {
+        let dt = records
+            .iter()
+            .find_map(|r| match *r {
+                Record::Step { dt, .. } => Some(dt),
+                _ => None,
+            })
+            .unwrap_or(0.0);
 
-            for se in &mut self.swarm_elements {
-                let var_rx = se.ranging_noise.std_dev().powi(2);
-                for anchor in self.anchors.iter() {
-                    let var_tx = anchor.ranging_noise.std_dev().powi(2);
-                    let combined_std = (var_rx + var_tx).sqrt();
-                    let anchor_ranging = anchor.ranging(&se, combined_std);
-
-                    se.particle_filter.update_weights(
-                        anchor_ranging,
-                        anchor.position,
-                        combined_std,
-                    );
-                    se.particle_filter.normalize_weights();
+        let mut health = vec![FilterHealth::default(); self.swarm_elements.len()];
+        for (agent, se) in self.swarm_elements.iter_mut().enumerate() {
+            let start = Instant::now();
+            // without a reading the particles only diffuse
+            let velocity = records
+                .iter()
+                .find_map(|r| match *r {
+                    Record::Velocity {
+                        agent: a, velocity, ..
+                    } if a == agent => Some(velocity),
+                    _ => None,
+                })
+                .unwrap_or_else(Vector3::zeros);
+            se.predict(dt, velocity);
+
+            // the residual is scaled by the measurement noise and the predicted spread
+            // along the line to the anchor together
+            let predicted = se.particle_filter.posterior_mean();
+            let covariance = se.particle_filter.posterior_covariance();
+            health[agent].innovations = Self::anchor_ranges(agent, se, records)
+                .into_iter()
+                .filter(|&(j, _, _)| j < self.anchors.len())
+                .filter_map(|(j, range, std)| {
+                    let offset = predicted - self.anchors[j].position;
+                    let spread = offset
+                        .try_normalize(0.0)
+                        .map_or(0.0, |u| u.dot(&(covariance * u)));
+                    let scale = (std * std + spread).sqrt();
+                    (scale > 0.0).then(|| (j, (range - offset.norm()) / scale))
+                })
+                .collect();
+
+            let own = records.iter().filter(|r| r.agent() == Some(agent));
+            health[agent].log_likelihood = match self.measurement_mode {
+                MeasurementMode::Toa => Self::toa_update(se, &self.anchors, own),
+                MeasurementMode::Tdoa { reference } => {
+                    Self::tdoa_update(se, &self.anchors, reference, own)
                 }
-                se.update_est_position();
-                se.particle_filter.resample();
+                MeasurementMode::RawToa => Self::raw_toa_update(se, &self.anchors, own),
+            };
+            health[agent].runtime_s = start.elapsed().as_secs_f64();
+        }
+
+        if let Some(cooperation) = self.cooperation {
+            let start = Instant::now();
+            let log_likelihoods = self.cooperative_update(&cooperation, records);
+            // shared out evenly, the links are not timed one by one
+            let share = start.elapsed().as_secs_f64() / self.swarm_elements.len() as f64;
+            for (h, l) in health.iter_mut().zip(log_likelihoods) {
+                h.log_likelihood += l;
+                h.runtime_s += share;
+            }
+        }
+
+        for (se, h) in self.swarm_elements.iter_mut().zip(&mut health) {
+            let start = Instant::now();
+            se.update_est_position();
+            h.resampled = se.particle_filter.resample();
+            h.runtime_s += start.elapsed().as_secs_f64();
+            h.ess = se.est_ess;
+            h.spread = se.est_covariance.trace().sqrt();
+        }
+        self.health = health;
+    }
+
+    // The update functions return the log marginal likelihood of what they used.
+    fn toa_update<'a>(
+        se: &mut swarm_element::SwarmElement<M>,
+        anchors: &[anchor::Anchor],
+        records: impl Iterator<Item = &'a Record>,
+    ) -> f64 {
+        let mut log_likelihood = 0.0;
+        for record in records {
+            if let Record::Range {
+                anchor, range, std, ..
+            } = *record
+                && std > 0.0
+                && let Some(anchor) = anchors.get(anchor)
+            {
+                se.particle_filter.update_weights_with(
+                    range,
+                    anchor.position,
+                    std,
+                    &anchor.likelihood,
+                );
+                log_likelihood += se.particle_filter.normalize_weights();
+            }
+        }
+        log_likelihood
+    }
+
+    fn raw_toa_update<'a>(
+        se: &mut swarm_element::SwarmElement<M>,
+        anchors: &[anchor::Anchor],
+        records: impl Iterator<Item = &'a Record>,
+    ) -> f64 {
+        let mut log_likelihood = 0.0;
+        for record in records {
+            if let Record::Toa {
+                anchor, toa, std, ..
+            } = *record
+                && std > 0.0
+                && let Some(anchor) = anchors.get(anchor)
+            {
+                se.particle_filter.update_weights_toa(
+                    toa,
+                    anchor.position,
+                    std,
+                    &anchor.likelihood,
+                );
+                log_likelihood += se.particle_filter.normalize_weights();
             }
+        }
+        log_likelihood
+    }
 
-            if self.visualizer.is_some() {
-                self.capture_frame(frame);
+    // Every difference shares the reference anchor's arrival error, which correlates
+    // the observations.
+    fn tdoa_update<'a>(
+        se: &mut swarm_element::SwarmElement<M>,
+        anchors: &[anchor::Anchor],
+        reference: usize,
+        records: impl Iterator<Item = &'a Record>,
+    ) -> f64 {
+        let mut arrivals: Vec<Option<Arrival>> = vec![None; anchors.len()];
+        for record in records {
+            if let Record::Range {
+                anchor, range, std, ..
+            } = *record
+                && anchor < anchors.len()
+            {
+                arrivals[anchor] = Some(Arrival {
+                    position: anchors[anchor].position,
+                    range,
+                    std,
+                });
             }
         }
+
+        let Some(reference_arrival) = arrivals.get(reference).copied().flatten() else {
+            return 0.0;
+        };
+        let others: Vec<Arrival> = arrivals
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != reference)
+            .filter_map(|(_, a)| *a)
+            .collect();
+        if others.is_empty() {
+            return 0.0;
+        }
+
+        let measurement = TdoaMeasurement::from_arrivals(&reference_arrival, &others);
+        se.particle_filter.update_weights_tdoa(&measurement);
+        se.particle_filter.normalize_weights()
     }
 
-    fn capture_frame(&mut self, frame: usize) {
+    // Inter-agent ranging. Beliefs are snapshotted first so every element is updated
+    // against its neighbours' state after the anchor step.
+    fn cooperative_update(&mut self, cooperation: &Cooperation, records: &[Record]) -> Vec<f64> {
+        let mut log_likelihoods = vec![0.0; self.swarm_elements.len()];
+        let mut rng = self.belief_rng.next_rng();
+        let beliefs: Vec<NeighbourBelief> = self
+            .swarm_elements
+            .iter()
+            .map(|se| cooperation.mode.belief(&se.particle_filter, &mut rng))
+            .collect();
+
+        for record in records {
+            if let Record::PeerRange {
+                agent,
+                neighbour,
+                range,
+                std,
+            } = *record
+                && std > 0.0
+                && agent < self.swarm_elements.len()
+                && neighbour < beliefs.len()
+            {
+                let pf = &mut self.swarm_elements[agent].particle_filter;
+                pf.update_weights_cooperative(
+                    range,
+                    &beliefs[neighbour],
+                    std,
+                    &cooperation.likelihood,
+                );
+                log_likelihoods[agent] += pf.normalize_weights();
+            }
+        }
+        log_likelihoods
+    }
+
+    fn capture_frame(&mut self, frame: usize, records: &[Record]) {
         let viz = self.visualizer.as_mut().unwrap();
 
-        // Temp hardcoding, at some point this will be handeld by a config parser
-        let particle_size = 1.0;
-        let swarm_size = 6.0;
-        let anchors_size = 6.0;
+        let VisualizationOptions {
+            particle_size,
+            swarm_size,
+            anchors_size,
+            ellipsoids,
+            range_spheres,
+            residual_links,
+            health_plots,
+        } = self.visualization_options;
 
         viz.log(Command::SetFrame(frame as i64));
 
-        for swarm in &self.swarm_elements {
+        for (agent, swarm) in self.swarm_elements.iter().enumerate() {
             let particle_positions: Vec<[f64; 3]> = swarm
                 .particle_filter
                 .particles
@@ -119,6 +663,65 @@ impl<M: DynamicsModel> Simulation<M> {
             let err = swarm.estimation_error();
             viz.log(Command::LogScalarPlot(entity_name, err));
 
+            if health_plots {
+                let health = &self.health[agent];
+                let name = &swarm.name;
+                for (plot, value) in [
+                    ("ess", health.ess),
+                    ("resampled", if health.resampled { 1.0 } else { 0.0 }),
+                    ("log_likelihood", health.log_likelihood),
+                    ("spread", health.spread),
+                    ("step_runtime_ms", health.runtime_s * 1e3),
+                ] {
+                    viz.log(Command::LogScalarPlot(format!("{plot}/{name}"), value));
+                }
+                for &(j, innovation) in &health.innovations {
+                    viz.log(Command::LogScalarPlot(
+                        format!("innovation/{name}/anchor_{j}"),
+                        innovation,
+                    ));
+                }
+            }
+
+            if ellipsoids {
+                let ellipsoid = swarm.credible_ellipsoid();
+                viz.log(Command::LogEllipsoid(
+                    format!("{}/uncertainty", &swarm.name),
+                    ellipsoid.center.into(),
+                    ellipsoid.semi_axes.into(),
+                    ellipsoid.orientation().coords.into(),
+                ));
+            }
+
+            if range_spheres || residual_links {
+                let ranges = Self::anchor_ranges(agent, swarm, records);
+                let anchor_position = |j: usize| self.anchors[j].position;
+                if range_spheres {
+                    viz.log(Command::LogSpheres(
+                        format!("{}/range_spheres", &swarm.name),
+                        ranges.iter().map(|r| anchor_position(r.0).into()).collect(),
+                        ranges.iter().map(|r| r.1).collect(),
+                    ));
+                }
+                if residual_links {
+                    let est = swarm.est_position;
+                    viz.log(Command::LogLines(
+                        format!("{}/residuals", &swarm.name),
+                        ranges
+                            .iter()
+                            .map(|r| [anchor_position(r.0).into(), est.into()])
+                            .collect(),
+                        ranges
+                            .iter()
+                            .map(|&(j, range, std)| {
+                                let residual = range - (est - anchor_position(j)).norm();
+                                Self::residual_color(residual, std)
+                            })
+                            .collect(),
+                    ));
+                }
+            }
+
             if let Some(prev_est) = swarm.prev_positions.est_position {
                 let est_trajectory_path = format!("{}/est_position/trajectory", &swarm.name);
                 let swarm_est_position: [f64; 3] = [
@@ -172,6 +775,42 @@ impl<M: DynamicsModel> Simulation<M> {
         }
     }
 
+    // The step's anchor ranges of `agent` as (anchor, range, std). Arrival times are
+    // turned into ranges with the estimated clock bias, when there is one.
+    fn anchor_ranges(
+        agent: usize,
+        swarm: &swarm_element::SwarmElement<M>,
+        records: &[Record],
+    ) -> Vec<(usize, f64, f64)> {
+        let bias = swarm.est_clock_bias.unwrap_or(0.0);
+        records
+            .iter()
+            .filter_map(|record| match *record {
+                Record::Range {
+                    agent: a,
+                    anchor,
+                    range,
+                    std,
+                } if a == agent => Some((anchor, range, std)),
+                Record::Toa {
+                    agent: a,
+                    anchor,
+                    toa,
+                    std,
+                } if a == agent => Some((anchor, (toa - bias) * SPEED_OF_LIGHT, std)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Blue when the measured range is short of the estimate, red when it is long,
+    // saturating at three standard deviations.
+    fn residual_color(residual: f64, std: f64) -> [u8; 4] {
+        let z = (residual / (3.0 * std).max(1e-12)).clamp(-1.0, 1.0);
+        let c = RED_BLUE.eval_continuous(0.5 - 0.5 * z);
+        [c.r, c.g, c.b, 255]
+    }
+
     fn color_gradient(particles: &Vec<Particle>) -> Vec<[u8; 4]> {
         let n = particles.len() as f64;
         let lw_uniform = -n.ln();
@@ -209,18 +848,96 @@ impl<M: DynamicsModel> SimulationBuilder<M> {
         self
     }
 
-    pub fn visualizer(mut self, visualizer: RerunVisualization) -> Self {
-        self.visualizer = Some(visualizer);
+    pub fn scene(mut self, scene: Scene) -> Self {
+        self.scene = Some(scene);
+        self
+    }
+
+    pub fn cooperation(mut self, cooperation: Cooperation) -> Self {
+        self.cooperation = Some(cooperation);
+        self
+    }
+
+    pub fn measurement_mode(mut self, measurement_mode: MeasurementMode) -> Self {
+        self.measurement_mode = measurement_mode;
+        self
+    }
+
+    pub fn visualization_options(mut self, visualization_options: VisualizationOptions) -> Self {
+        self.visualization_options = visualization_options;
         self
     }
 
-    pub fn build(self) -> Simulation<M> {
+    // May be called several times; every visualizer gets every frame.
+    pub fn visualizer(mut self, visualizer: impl Visualizer + 'static) -> Self {
+        self.visualizers.push(Box::new(visualizer));
+        self
+    }
+
+    // Make the run reproducible: every element, anchor and link draws from its own
+    // stream split off `seed`. Unseeded runs keep the elements' own streams.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn build(mut self) -> Simulation<M> {
+        let anchors = self.anchors.expect("expected at least one anchor");
+        if let MeasurementMode::Tdoa { reference } = self.measurement_mode {
+            assert!(anchors.len() >= 2, "TDOA needs at least two anchors");
+            assert!(
+                reference < anchors.len(),
+                "TDOA reference anchor out of range"
+            );
+        }
+        let mut swarm_elements = self
+            .swarm_elements
+            .expect("expected at least one swarm element");
+
+        let root = match self.seed {
+            Some(seed) => {
+                let root = RngStream::new(seed);
+                for (i, se) in swarm_elements.iter_mut().enumerate() {
+                    se.set_rng_stream(root.substream(AGENT_STREAMS).substream(i as u64));
+                }
+                root
+            }
+            None => RngStream::from_entropy(),
+        };
+        let anchor_rngs = (0..anchors.len())
+            .map(|j| root.substream(ANCHOR_STREAMS).substream(j as u64))
+            .collect();
+
+        let mut visualizer: Option<Box<dyn Visualizer>> = match self.visualizers.len() {
+            0 => None,
+            1 => self.visualizers.pop(),
+            _ => Some(Box::new(FanoutVisualizer::new(self.visualizers))),
+        };
+        // the terrain never changes, so it is drawn once before the first frame
+        if let Some(viz) = visualizer.as_mut()
+            && let Some(terrain) = self.scene.as_ref().and_then(|s| s.terrain.as_ref())
+        {
+            viz.log(Command::LogStaticStrips(
+                String::from("terrain"),
+                terrain.drawn_contours(),
+            ));
+        }
+
         Simulation {
-            swarm_elements: self
-                .swarm_elements
-                .expect("expected at least one swarm element"),
-            anchors: self.anchors.expect("expected at least one anchor"),
-            visualizer: self.visualizer,
+            health: vec![FilterHealth::default(); swarm_elements.len()],
+            swarm_elements,
+            anchors,
+            scene: self.scene,
+            cooperation: self.cooperation,
+            measurement_mode: self.measurement_mode,
+            visualization_options: self.visualization_options,
+            visualizer,
+            seed: self.seed,
+            anchor_rngs,
+            link_rng: root.substream(LINK_STREAM),
+            belief_rng: root.substream(BELIEF_STREAM),
+            transmit_rng: root.substream(TRANSMIT_STREAM),
+            time: 0.0,
         }
     }
 }
@@ -262,6 +979,470 @@ mod tests {
         assert_eq!(sim.swarm_elements[0], swarm_el);
         assert_eq!(sim.anchors.len(), 1);
         assert_eq!(sim.anchors[0], anchor);
+        assert!(sim.scene.is_none());
+        assert!(sim.cooperation.is_none());
+        assert_eq!(sim.measurement_mode, MeasurementMode::Toa);
         assert!(sim.visualizer.is_none());
     }
+
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default, Clone)]
+    struct Collect(Arc<Mutex<Vec<Command>>>);
+
+    impl Visualizer for Collect {
+        fn log(&mut self, command: Command) {
+            self.0.lock().unwrap().push(command);
+        }
+    }
+
+    #[test]
+    fn every_visualizer_gets_every_frame() {
+        use visualization::visualization::NoopVisualizer;
+
+        let (first, second) = (Collect::default(), Collect::default());
+        let mut sim = Simulation::<WhiteNoiseAcceleration>::builder()
+            .swarm_elements(vec![SwarmElement::default()])
+            .anchors(vec![Anchor::default()])
+            .visualizer(first.clone())
+            .visualizer(NoopVisualizer)
+            .visualizer(second.clone())
+            .build();
+        sim.run(2, 0.1);
+
+        let first = first.0.lock().unwrap();
+        assert_eq!(*first, *second.0.lock().unwrap());
+        let frames: Vec<i64> = first
+            .iter()
+            .filter_map(|c| match c {
+                Command::SetFrame(frame) => Some(*frame),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(frames, vec![0, 1]);
+    }
+
+    #[test]
+    fn debug_layers_follow_the_options() {
+        let layers = |options: VisualizationOptions| {
+            let collect = Collect::default();
+            let anchors = vec![
+                Anchor::new(Vector3::new(10.0, 0.0, 0.0), 0.1),
+                Anchor::new(Vector3::new(0.0, 10.0, 0.0), 0.1),
+            ];
+            let mut sim = Simulation::<WhiteNoiseAcceleration>::builder()
+                .swarm_elements(vec![SwarmElement::default()])
+                .anchors(anchors)
+                .visualization_options(options)
+                .visualizer(collect.clone())
+                .build();
+            sim.run(1, 0.1);
+            drop(sim);
+            Arc::try_unwrap(collect.0).unwrap().into_inner().unwrap()
+        };
+
+        let commands = layers(VisualizationOptions {
+            range_spheres: true,
+            ..Default::default()
+        });
+        assert!(
+            commands
+                .iter()
+                .any(|c| matches!(c, Command::LogEllipsoid(..)))
+        );
+        let spheres = commands.iter().find_map(|c| match c {
+            Command::LogSpheres(_, centers, radii) => Some((centers.clone(), radii.clone())),
+            _ => None,
+        });
+        let (centers, radii) = spheres.expect("range spheres");
+        assert_eq!(centers, vec![[10.0, 0.0, 0.0], [0.0, 10.0, 0.0]]);
+        assert!(radii.iter().all(|r| *r > 0.0));
+        let links = commands.iter().find_map(|c| match c {
+            Command::LogLines(_, segments, colors) => Some((segments.len(), colors.len())),
+            _ => None,
+        });
+        assert_eq!(links, Some((2, 2)));
+
+        let commands = layers(VisualizationOptions {
+            ellipsoids: false,
+            range_spheres: false,
+            residual_links: false,
+            ..Default::default()
+        });
+        assert!(!commands.iter().any(|c| matches!(
+            c,
+            Command::LogEllipsoid(..) | Command::LogSpheres(..) | Command::LogLines(..)
+        )));
+    }
+
+    #[test]
+    fn terrain_is_drawn_once_up_front() {
+        use crate::scene::{BlockedLink, Terrain};
+
+        let collect = Collect::default();
+        let contours = vec![vec![[0.0, 0.0, 1.0], [5.0, 0.0, 2.0]]];
+        let mut sim = Simulation::<WhiteNoiseAcceleration>::builder()
+            .swarm_elements(vec![SwarmElement::default()])
+            .anchors(vec![Anchor::new(Vector3::new(10.0, 0.0, 0.0), 0.1)])
+            .scene(Scene::new(BlockedLink::Dropped).with_terrain(Terrain::new(contours, 2.0)))
+            .visualizer(collect.clone())
+            .build();
+        sim.run(2, 0.1);
+        drop(sim);
+
+        let commands = collect.0.lock().unwrap();
+        let terrain = Command::LogStaticStrips(
+            String::from("terrain"),
+            vec![vec![[0.0, 0.0, 2.0], [5.0, 0.0, 4.0]]],
+        );
+        assert_eq!(commands[0], terrain);
+        assert_eq!(commands.iter().filter(|c| **c == terrain).count(), 1);
+    }
+
+    #[test]
+    fn innovations_include_the_predicted_spread() {
+        use agents::particle_filter::{ParticleFilter, Sphere};
+
+        // the first prediction is the whole 20 m prior, far wider than the range noise
+        let mut sim = Simulation::<WhiteNoiseAcceleration>::builder()
+            .swarm_elements(vec![SwarmElement::new(
+                String::from("tag"),
+                WhiteNoiseAcceleration::default(),
+                ParticleFilter::new(&Sphere::new(20.0, Vector3::zeros()).unwrap(), 500, 0.5),
+                0.1,
+                0.1,
+            )])
+            .anchors(vec![
+                Anchor::new(Vector3::new(30.0, 0.0, 0.0), 0.1),
+                Anchor::new(Vector3::new(0.0, 30.0, 0.0), 0.1),
+            ])
+            .seed(5)
+            .build();
+        sim.swarm_elements[0].set_true_state(Vector3::new(6.0, -4.0, 0.0), Vector3::zeros(), 0.0);
+        sim.run(1, 0.1);
+        let innovations = &sim.health()[0].innovations;
+        assert_eq!(innovations.len(), 2);
+        for &(j, z) in innovations {
+            assert!(z.is_finite() && z.abs() < 3.0, "anchor {j}: innovation {z}");
+        }
+    }
+
+    #[test]
+    fn filter_health_is_reported_and_plotted() {
+        use agents::particle_filter::{ParticleFilter, Sphere};
+
+        let run = |options: VisualizationOptions| {
+            let collect = Collect::default();
+            let anchors = vec![
+                Anchor::new(Vector3::new(10.0, 0.0, 0.0), 0.1),
+                Anchor::new(Vector3::new(0.0, 10.0, 0.0), 0.1),
+            ];
+            let mut sim = Simulation::<WhiteNoiseAcceleration>::builder()
+                .swarm_elements(vec![SwarmElement::new(
+                    String::from("tag"),
+                    WhiteNoiseAcceleration::default(),
+                    ParticleFilter::new(&Sphere::new(20.0, Vector3::zeros()).unwrap(), 200, 0.5),
+                    0.1,
+                    0.1,
+                )])
+                .anchors(anchors)
+                .visualization_options(options)
+                .visualizer(collect.clone())
+                .seed(3)
+                .build();
+            sim.run(2, 0.1);
+            let health = sim.health().to_vec();
+            drop(sim);
+            let commands = Arc::try_unwrap(collect.0).unwrap().into_inner().unwrap();
+            (health, commands)
+        };
+        let plots = |commands: &[Command]| -> Vec<String> {
+            commands
+                .iter()
+                .filter_map(|c| match c {
+                    Command::LogScalarPlot(entity, _)
+                        if !entity.starts_with("estimation_error") =>
+                    {
+                        Some(entity.clone())
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let (health, commands) = run(VisualizationOptions::default());
+        assert_eq!(health.len(), 1);
+        let health = &health[0];
+        assert!(health.log_likelihood.is_finite());
+        assert!(
+            health.ess > 0.0 && health.ess <= 200.0,
+            "ess {}",
+            health.ess
+        );
+        assert!(health.spread > 0.0);
+        assert!(health.runtime_s > 0.0);
+        assert_eq!(
+            health.innovations.iter().map(|i| i.0).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+
+        let logged = plots(&commands);
+        for plot in [
+            "ess",
+            "resampled",
+            "log_likelihood",
+            "spread",
+            "step_runtime_ms",
+        ] {
+            assert!(logged.contains(&format!("{plot}/tag")), "{plot}");
+        }
+        assert!(logged.contains(&"innovation/tag/anchor_1".to_string()));
+
+        let (_, commands) = run(VisualizationOptions {
+            health_plots: false,
+            ..Default::default()
+        });
+        assert!(plots(&commands).is_empty());
+    }
+
+    #[test]
+    fn dropped_links_leave_weights_untouched() {
+        use crate::scene::{BlockedLink, Obstacle};
+
+        let swarm_el = SwarmElement::<WhiteNoiseAcceleration>::default();
+        let anchor = Anchor::new(Vector3::new(10.0, 0.0, 0.0), 0.1);
+        let wall = Obstacle::AxisAlignedBox {
+            min: Vector3::new(4.0, -1.0, -1.0),
+            max: Vector3::new(5.0, 1.0, 1.0),
+        };
+
+        let mut sim = Simulation::builder()
+            .swarm_elements(vec![swarm_el])
+            .anchors(vec![anchor])
+            .scene(Scene::new(BlockedLink::Dropped).with_obstacle(wall))
+            .build();
+        sim.swarm_elements[0].particle_filter.particles = vec![
+            Particle::new(Vector3::zeros(), 0.0),
+            Particle::new(Vector3::x(), -1.0),
+        ];
+
+        sim.run(1, 0.0);
+
+        let particles = &sim.swarm_elements[0].particle_filter.particles;
+        assert_eq!(particles[0].log_weight, 0.0);
+        assert_eq!(particles[1].log_weight, -1.0);
+    }
+
+    #[test]
+    fn cooperative_ranging_pulls_towards_neighbour_distance() {
+        use crate::cooperative::{CooperativeMode, NeighbourPolicy};
+        use agents::particle_filter::ParticleFilter;
+
+        for mode in [
+            CooperativeMode::ParticleCloud { samples: 8 },
+            CooperativeMode::EstimateWithCovariance,
+        ] {
+            let element = || {
+                SwarmElement::new(
+                    String::new(),
+                    WhiteNoiseAcceleration::default(),
+                    ParticleFilter::default(),
+                    0.05,
+                    0.05,
+                )
+            };
+            // the anchor is far enough that its range barely separates the hypotheses
+            let anchor = Anchor::new(Vector3::new(0.0, 0.0, 1000.0), 50.0);
+
+            let mut sim = Simulation::builder()
+                .swarm_elements(vec![element(), element()])
+                .anchors(vec![anchor])
+                .cooperation(Cooperation::new(NeighbourPolicy::All, mode))
+                .build();
+            // b is known to sit at its true position, a is either there too or 5 m away
+            sim.swarm_elements[0].particle_filter.particles = vec![
+                Particle::new(Vector3::zeros(), 0.0),
+                Particle::new(Vector3::new(5.0, 0.0, 0.0), 0.0),
+            ];
+            sim.swarm_elements[1].particle_filter.particles =
+                vec![Particle::new(Vector3::zeros(), 0.0); 2];
+            sim.swarm_elements[0].particle_filter.normalize_weights();
+            sim.swarm_elements[1].particle_filter.normalize_weights();
+
+            sim.run(1, 0.0);
+
+            let est = sim.swarm_elements[0].est_position;
+            assert!(est.norm() < 0.5, "{mode:?}: estimate {est}");
+        }
+    }
+
+    #[test]
+    fn peer_ranges_combine_both_ranging_noises() {
+        use crate::cooperative::{CooperativeMode, NeighbourPolicy};
+        use agents::{likelihood::LikelihoodModel, particle_filter::ParticleFilter};
+
+        let element = |sd_ranging_noise| {
+            SwarmElement::new(
+                String::new(),
+                WhiteNoiseAcceleration::default(),
+                ParticleFilter::default(),
+                5.0,
+                sd_ranging_noise,
+            )
+        };
+        let cooperation = Cooperation::new(
+            NeighbourPolicy::All,
+            CooperativeMode::EstimateWithCovariance,
+        )
+        .with_likelihood(LikelihoodModel::StudentT { nu: 4.0 });
+        let mut sim = Simulation::builder()
+            .swarm_elements(vec![element(0.3), element(0.4)])
+            .anchors(vec![Anchor::default()])
+            .cooperation(cooperation)
+            .build();
+
+        let stds: Vec<f64> = sim
+            .measure(0.1)
+            .iter()
+            .filter_map(|record| match *record {
+                Record::PeerRange { std, .. } => Some(std),
+                _ => None,
+            })
+            .collect();
+        // the velocity-reading noise of 5 m/s plays no part
+        assert_eq!(stds.len(), 2);
+        assert!(stds.iter().all(|std| (std - 0.5).abs() < 1e-12), "{stds:?}");
+    }
+
+    #[test]
+    #[should_panic(expected = "TDOA reference anchor out of range")]
+    fn tdoa_reference_must_exist() {
+        Simulation::<WhiteNoiseAcceleration>::builder()
+            .swarm_elements(vec![SwarmElement::default()])
+            .anchors(vec![Anchor::default(), Anchor::default()])
+            .measurement_mode(MeasurementMode::Tdoa { reference: 2 })
+            .build();
+    }
+
+    #[test]
+    fn tdoa_mode_localizes_on_the_right_hyperboloid() {
+        use agents::particle_filter::ParticleFilter;
+
+        let element = SwarmElement::new(
+            String::new(),
+            WhiteNoiseAcceleration::default(),
+            ParticleFilter::default(),
+            0.05,
+            0.05,
+        );
+        let anchors = vec![
+            Anchor::new(Vector3::new(-20.0, 0.0, 0.0), 0.05),
+            Anchor::new(Vector3::new(20.0, 0.0, 0.0), 0.05),
+            Anchor::new(Vector3::new(0.0, 20.0, 0.0), 0.05),
+            Anchor::new(Vector3::new(0.0, 0.0, 20.0), 0.05),
+        ];
+
+        let mut sim = Simulation::builder()
+            .swarm_elements(vec![element])
+            .anchors(anchors)
+            .measurement_mode(MeasurementMode::Tdoa { reference: 0 })
+            .build();
+        // the true position is the origin; the decoy keeps the range to the reference
+        // but not the differences
+        sim.swarm_elements[0].particle_filter.particles = vec![
+            Particle::new(Vector3::zeros(), 0.0),
+            Particle::new(Vector3::new(-20.0, 20.0, 0.0), 0.0),
+        ];
+        sim.swarm_elements[0].particle_filter.normalize_weights();
+
+        sim.run(1, 0.0);
+
+        let est = sim.swarm_elements[0].est_position;
+        assert!(est.norm() < 0.5, "estimate {est}");
+    }
+
+    #[test]
+    fn tdoa_arrivals_share_the_tag_transmit_error() {
+        use agents::particle_filter::ParticleFilter;
+
+        // a loud tag and noiseless anchors: every arrival carries the same error
+        let element = SwarmElement::new(
+            String::new(),
+            WhiteNoiseAcceleration::default(),
+            ParticleFilter::default(),
+            0.05,
+            2.0,
+        );
+        let anchors = vec![
+            Anchor::new(Vector3::new(-20.0, 0.0, 0.0), 0.0),
+            Anchor::new(Vector3::new(20.0, 0.0, 0.0), 0.0),
+            Anchor::new(Vector3::new(0.0, 20.0, 0.0), 0.0),
+        ];
+        let mut sim = Simulation::builder()
+            .swarm_elements(vec![element])
+            .anchors(anchors)
+            .measurement_mode(MeasurementMode::Tdoa { reference: 0 })
+            .seed(11)
+            .build();
+
+        let errors: Vec<(f64, f64)> = sim
+            .measure(0.0)
+            .iter()
+            .filter_map(|record| match *record {
+                Record::Range { range, std, .. } => Some((range - 20.0, std)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].0.abs() > 1e-6);
+        assert!(
+            errors
+                .iter()
+                .all(|&(e, std)| (e - errors[0].0).abs() < 1e-9 && std == 0.0),
+            "{errors:?}"
+        );
+    }
+
+    #[test]
+    fn raw_toa_mode_estimates_clock_bias() {
+        use agents::{clock::ClockModel, particle_filter::ParticleFilter};
+
+        let bias = 30e-9;
+        let mut element = SwarmElement::new(
+            String::new(),
+            WhiteNoiseAcceleration::default(),
+            ParticleFilter::default().with_clock_state(0.0, 0.0),
+            0.05,
+            0.05,
+        )
+        .with_clock(ClockModel::new(bias, 0.0, 0.0, 0.0));
+        // the true position with and without the right clock bias
+        element.particle_filter.particles = vec![
+            Particle {
+                clock_bias: bias,
+                ..Particle::new(Vector3::zeros(), 0.0)
+            },
+            Particle::new(Vector3::zeros(), 0.0),
+        ];
+        element.particle_filter.normalize_weights();
+
+        let anchors = vec![
+            Anchor::new(Vector3::new(20.0, 0.0, 0.0), 0.05),
+            Anchor::new(Vector3::new(0.0, 20.0, 0.0), 0.05),
+            Anchor::new(Vector3::new(0.0, 0.0, 20.0), 0.05),
+        ];
+        let mut sim = Simulation::builder()
+            .swarm_elements(vec![element])
+            .anchors(anchors)
+            .measurement_mode(MeasurementMode::RawToa)
+            .build();
+
+        sim.run(1, 0.0);
+
+        let est_bias = sim.swarm_elements[0].est_clock_bias.unwrap();
+        assert!(
+            (est_bias - bias).abs() <= 1e-10,
+            "estimated bias {est_bias}"
+        );
+    }
 }
@@ -0,0 +1,422 @@
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use agents::rng::RngStream;
+
+use crate::batch::{BatchConfig, BatchReport, run_scenario_batch};
+use crate::scenario::Scenario;
+
+// Stream for drawing random sweep points, clear of the per-run streams.
+const SAMPLING_STREAM: u64 = u64::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Parameter {
+    NumParticles,
+    EssTau,
+    Roughening,
+    // Ranging noise std of every anchor.
+    AnchorNoise,
+    StepSize,
+}
+
+impl Parameter {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Parameter::NumParticles => "num_particles",
+            Parameter::EssTau => "ess_tau",
+            Parameter::Roughening => "roughening",
+            Parameter::AnchorNoise => "anchor_noise",
+            Parameter::StepSize => "step_size",
+        }
+    }
+
+    // Filter parameters are set on every swarm element.
+    pub fn apply(&self, scenario: &mut Scenario, value: f64) {
+        match self {
+            Parameter::NumParticles => {
+                for se in &mut scenario.swarm_elements {
+                    se.filter.num_particles = value.round() as usize;
+                }
+            }
+            Parameter::EssTau => {
+                for se in &mut scenario.swarm_elements {
+                    se.filter.ess_tau = value;
+                }
+            }
+            Parameter::Roughening => {
+                for se in &mut scenario.swarm_elements {
+                    se.filter.roughening = value;
+                }
+            }
+            Parameter::AnchorNoise => {
+                for anchor in &mut scenario.anchors {
+                    anchor.sd_ranging_noise = value;
+                }
+            }
+            Parameter::StepSize => scenario.step_size = value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Values {
+    List(Vec<f64>),
+    // `count` evenly spaced values from `start` to `stop`, both included.
+    Linspace { start: f64, stop: f64, count: usize },
+    // Only valid with random sampling.
+    Uniform { min: f64, max: f64 },
+}
+
+impl Values {
+    fn discrete(&self) -> Option<Vec<f64>> {
+        match *self {
+            Values::List(ref values) => Some(values.clone()),
+            Values::Linspace { start, stop, count } => Some(
+                (0..count)
+                    .map(|i| match count {
+                        1 => start,
+                        _ => start + (stop - start) * i as f64 / (count - 1) as f64,
+                    })
+                    .collect(),
+            ),
+            Values::Uniform { .. } => None,
+        }
+    }
+
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> f64 {
+        match *self {
+            Values::Uniform { min, max } => rng.random_range(min..=max),
+            _ => {
+                let values = self.discrete().unwrap_or_default();
+                values[rng.random_range(0..values.len())]
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParameterRange {
+    pub parameter: Parameter,
+    #[serde(flatten)]
+    pub values: Values,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Sampling {
+    // Every combination of the parameter values.
+    #[default]
+    Grid,
+    // `samples` points, each parameter drawn independently.
+    Random {
+        samples: usize,
+    },
+}
+
+// A design of experiments over a scenario, loaded from TOML or JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sweep {
+    // Monte Carlo runs per point.
+    pub runs: usize,
+    // Every point reuses the same run seeds, so differences between points are not
+    // drowned in run-to-run noise.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub sampling: Sampling,
+    #[serde(default = "default_divergence_threshold")]
+    pub divergence_threshold: f64,
+    pub parameters: Vec<ParameterRange>,
+}
+
+fn default_divergence_threshold() -> f64 {
+    10.0
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SweepPoint {
+    pub values: Vec<f64>,
+    pub report: BatchReport,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SweepReport {
+    pub parameters: Vec<Parameter>,
+    pub points: Vec<SweepPoint>,
+}
+
+impl Sweep {
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Sweep: cannot read {}: {e}", path.display()))?;
+        let is_json = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+        let sweep: Sweep = if is_json {
+            serde_json::from_str(&text)?
+        } else {
+            toml::from_str(&text)?
+        };
+        sweep.validate()?;
+        Ok(sweep)
+    }
+
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.runs == 0 {
+            return Err("Sweep: runs must be > 0".into());
+        }
+        if self.parameters.is_empty() {
+            return Err("Sweep: expected at least one parameter".into());
+        }
+        if self.divergence_threshold.is_nan() || self.divergence_threshold <= 0.0 {
+            return Err("Sweep: divergence_threshold must be > 0".into());
+        }
+        for (i, range) in self.parameters.iter().enumerate() {
+            let name = range.parameter.name();
+            if self.parameters[..i]
+                .iter()
+                .any(|r| r.parameter == range.parameter)
+            {
+                return Err(format!("Sweep: {name} is listed twice").into());
+            }
+            match (&range.values, self.sampling) {
+                (Values::List(values), _) if values.is_empty() => {
+                    return Err(format!("Sweep: {name}: empty list").into());
+                }
+                (Values::Linspace { count: 0, .. }, _) => {
+                    return Err(format!("Sweep: {name}: linspace count must be > 0").into());
+                }
+                (Values::Uniform { .. }, Sampling::Grid) => {
+                    return Err(format!("Sweep: {name}: uniform needs random sampling").into());
+                }
+                (Values::Uniform { min, max }, _) if min.is_nan() || max.is_nan() || min > max => {
+                    return Err(format!("Sweep: {name}: uniform needs min <= max").into());
+                }
+                _ => {}
+            }
+        }
+        if let Sampling::Random { samples: 0 } = self.sampling {
+            return Err("Sweep: random samples must be > 0".into());
+        }
+        Ok(())
+    }
+
+    // Parameter values of every point, in the order of `parameters`.
+    pub fn points(&self, seed: u64) -> Vec<Vec<f64>> {
+        match self.sampling {
+            Sampling::Grid => self.parameters.iter().fold(vec![vec![]], |points, range| {
+                let values = range.values.discrete().unwrap_or_default();
+                points
+                    .iter()
+                    .flat_map(|point| {
+                        values.iter().map(move |v| {
+                            let mut point = point.clone();
+                            point.push(*v);
+                            point
+                        })
+                    })
+                    .collect()
+            }),
+            Sampling::Random { samples } => {
+                let mut rng = RngStream::new(seed).substream(SAMPLING_STREAM).next_rng();
+                (0..samples)
+                    .map(|_| {
+                        self.parameters
+                            .iter()
+                            .map(|range| range.values.sample(&mut rng))
+                            .collect()
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    // Run a Monte Carlo batch of `scenario` at every point. `on_point` sees each
+    // result as it completes.
+    pub fn run(
+        &self,
+        scenario: &Scenario,
+        seed: u64,
+        parallel: bool,
+        mut on_point: impl FnMut(&SweepPoint),
+    ) -> Result<SweepReport, Box<dyn Error>> {
+        self.validate()?;
+        let parameters: Vec<Parameter> = self.parameters.iter().map(|r| r.parameter).collect();
+
+        let mut points = Vec::new();
+        for values in self.points(seed) {
+            let mut scenario = scenario.clone();
+            for (parameter, value) in parameters.iter().zip(&values) {
+                parameter.apply(&mut scenario, *value);
+            }
+            scenario
+                .validate()
+                .map_err(|e| format!("{}: {e}", describe(&parameters, &values)))?;
+
+            let config = BatchConfig::new(self.runs, scenario.steps, scenario.step_size, seed)
+                .with_parallel(parallel)
+                .with_divergence_threshold(self.divergence_threshold);
+            let point = SweepPoint {
+                report: run_scenario_batch(&scenario, &config)?,
+                values,
+            };
+            on_point(&point);
+            points.push(point);
+        }
+        Ok(SweepReport { parameters, points })
+    }
+}
+
+// e.g. "num_particles=500 ess_tau=0.5"
+pub fn describe(parameters: &[Parameter], values: &[f64]) -> String {
+    parameters
+        .iter()
+        .zip(values)
+        .map(|(p, v)| format!("{}={v}", p.name()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl SweepPoint {
+    pub fn metrics(&self) -> [(&'static str, f64); 8] {
+        let r = &self.report;
+        let s = &r.overall;
+        [
+            ("rmse", s.rmse),
+            ("mean_error", s.mean),
+            ("median_error", s.median),
+            ("p95_error", s.p95),
+            ("cep50", s.cep50),
+            ("sep50", s.sep50),
+            ("divergence_rate", r.divergence_rate),
+            ("runtime_s", r.mean_runtime_s()),
+        ]
+    }
+}
+
+impl SweepReport {
+    // Tidy layout: one row per (point, metric).
+    pub fn write_csv<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let names: Vec<&str> = self.parameters.iter().map(Parameter::name).collect();
+        writeln!(out, "point,{},metric,value", names.join(","))?;
+        for (i, point) in self.points.iter().enumerate() {
+            let values: Vec<String> = point.values.iter().map(f64::to_string).collect();
+            for (metric, value) in point.metrics() {
+                writeln!(out, "{i},{},{metric},{value}", values.join(","))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = include_str!("../../scenarios/sweep.toml");
+    const DEFAULT: &str = include_str!("../../scenarios/default.toml");
+
+    #[test]
+    fn grid_is_the_cartesian_product() {
+        let sweep: Sweep = toml::from_str(EXAMPLE).unwrap();
+        sweep.validate().unwrap();
+        assert_eq!(sweep.sampling, Sampling::Grid);
+
+        let points = sweep.points(0);
+        let sizes: Vec<usize> = sweep
+            .parameters
+            .iter()
+            .map(|r| r.values.discrete().unwrap().len())
+            .collect();
+        assert_eq!(points.len(), sizes.iter().product::<usize>());
+        assert!(points.iter().all(|p| p.len() == sweep.parameters.len()));
+        assert_eq!(
+            Values::Linspace {
+                start: 0.0,
+                stop: 1.0,
+                count: 3
+            }
+            .discrete(),
+            Some(vec![0.0, 0.5, 1.0])
+        );
+    }
+
+    #[test]
+    fn random_sampling_respects_ranges() {
+        let text = r#"
+            runs = 1
+            sampling = { random = { samples = 50 } }
+
+            [[parameters]]
+            parameter = "ess_tau"
+            uniform = { min = 0.2, max = 0.4 }
+
+            [[parameters]]
+            parameter = "num_particles"
+            list = [100, 200]
+        "#;
+        let sweep: Sweep = toml::from_str(text).unwrap();
+        sweep.validate().unwrap();
+
+        let points = sweep.points(9);
+        assert_eq!(points.len(), 50);
+        assert!(points.iter().all(|p| (0.2..=0.4).contains(&p[0])));
+        assert!(points.iter().all(|p| p[1] == 100.0 || p[1] == 200.0));
+        assert_eq!(points, sweep.points(9));
+
+        let mut grid = sweep.clone();
+        grid.sampling = Sampling::Grid;
+        assert!(grid.validate().is_err());
+    }
+
+    #[test]
+    fn sweep_writes_one_row_per_point_and_metric() {
+        let mut scenario = Scenario::from_toml_str(DEFAULT).unwrap();
+        scenario.steps = 2;
+        let sweep = Sweep {
+            runs: 2,
+            seed: None,
+            sampling: Sampling::Grid,
+            divergence_threshold: 10.0,
+            parameters: vec![
+                ParameterRange {
+                    parameter: Parameter::NumParticles,
+                    values: Values::List(vec![50.0, 100.0]),
+                },
+                ParameterRange {
+                    parameter: Parameter::AnchorNoise,
+                    values: Values::List(vec![0.5]),
+                },
+            ],
+        };
+
+        let mut seen = 0;
+        let report = sweep.run(&scenario, 4, true, |_| seen += 1).unwrap();
+        assert_eq!(seen, 2);
+        assert_eq!(report.points[1].values, vec![100.0, 0.5]);
+
+        let mut csv = Vec::new();
+        report.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("point,num_particles,anchor_noise,metric,value")
+        );
+        assert_eq!(lines.count(), 2 * 8);
+        assert!(csv.contains("\n1,100,0.5,rmse,"));
+
+        // an invalid point is reported, not panicked on
+        let mut invalid = sweep.clone();
+        invalid.parameters[0].values = Values::List(vec![0.0]);
+        assert!(invalid.run(&scenario, 4, true, |_| {}).is_err());
+    }
+}
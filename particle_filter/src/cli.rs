@@ -0,0 +1,249 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use simulation::publisher::Destination;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "particle_filter",
+    version,
+    about = "ToA particle filter simulator"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+
+    /// More output per repetition: -v info, -vv debug, -vvv trace
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    pub verbose: u8,
+
+    /// Only report errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run a single simulation of a scenario
+    Run {
+        scenario: PathBuf,
+        /// Write every measurement and the true states to a log (.csv, otherwise binary)
+        #[arg(long)]
+        record: Option<PathBuf>,
+        /// Stream estimates as JSON lines: -, a file, udp://, tcp:// or unix:// address
+        #[arg(long)]
+        publish: Option<Destination>,
+        #[command(flatten)]
+        options: RunOptions,
+    },
+    /// Run a scenario several times and aggregate the estimation errors
+    Batch {
+        scenario: PathBuf,
+        #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+        runs: u64,
+        /// Run one simulation at a time instead of in parallel
+        #[arg(long)]
+        sequential: bool,
+        /// Final error (m) above which a track counts as diverged
+        #[arg(long, default_value_t = 10.0, value_parser = positive)]
+        divergence_threshold: f64,
+        #[command(flatten)]
+        options: RunOptions,
+    },
+    /// Run a Monte Carlo batch at every point of a parameter sweep
+    Sweep {
+        scenario: PathBuf,
+        sweep: PathBuf,
+        /// Run one simulation at a time instead of in parallel
+        #[arg(long)]
+        sequential: bool,
+        #[command(flatten)]
+        options: RunOptions,
+    },
+    /// Replay a recorded measurement log through the filters of a scenario
+    Replay {
+        scenario: PathBuf,
+        log: PathBuf,
+        /// Stream estimates as JSON lines: -, a file, udp://, tcp:// or unix:// address
+        #[arg(long)]
+        publish: Option<Destination>,
+        #[command(flatten)]
+        options: RunOptions,
+    },
+    /// Track the scenario's tags from live range reports and publish their estimates
+    #[command(group(ArgGroup::new("input").required(true).args(["udp", "tcp"])))]
+    Listen {
+        scenario: PathBuf,
+        /// Receive range reports as UDP datagrams on this address
+        #[arg(long)]
+        udp: Option<SocketAddr>,
+        /// Accept TCP connections streaming range reports on this address
+        #[arg(long)]
+        tcp: Option<SocketAddr>,
+        /// Where the estimates go: -, a file, udp://, tcp:// or unix:// address
+        #[arg(long, default_value = "-")]
+        publish: Destination,
+        /// Seed for the initial particles and the filters
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+    /// Check that a scenario file parses and builds
+    Validate { scenario: PathBuf },
+}
+
+#[derive(Debug, Clone, Default, Args)]
+pub struct RunOptions {
+    /// Seed for the random number generators
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Override the number of steps in the scenario
+    #[arg(long)]
+    pub steps: Option<usize>,
+    /// Override the step size (s) in the scenario
+    #[arg(long)]
+    pub step_size: Option<f64>,
+    /// Do not spawn a Rerun viewer
+    #[arg(long)]
+    pub headless: bool,
+    /// Save a Rerun recording (.rrd); batch runs get one each
+    #[arg(long)]
+    pub rrd: Option<PathBuf>,
+    /// Write a trace of everything drawn (.csv, otherwise NDJSON); batch runs get one each
+    #[arg(long)]
+    pub trace: Option<PathBuf>,
+    /// Directory for result files
+    #[arg(short, long)]
+    pub output_dir: Option<PathBuf>,
+}
+
+impl Cli {
+    pub fn log_level(&self) -> log::LevelFilter {
+        if self.quiet {
+            return log::LevelFilter::Error;
+        }
+        match self.verbose {
+            0 => log::LevelFilter::Warn,
+            1 => log::LevelFilter::Info,
+            2 => log::LevelFilter::Debug,
+            _ => log::LevelFilter::Trace,
+        }
+    }
+}
+
+fn positive(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(x) if x.is_finite() && x > 0.0 => Ok(x),
+        Ok(_) => Err(String::from("must be > 0")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn cli_definition_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn parses_run_with_overrides() {
+        let cli = Cli::parse_from([
+            "particle_filter",
+            "-vv",
+            "run",
+            "scenarios/default.toml",
+            "--seed",
+            "7",
+            "--steps",
+            "20",
+            "--step-size",
+            "0.05",
+            "--headless",
+            "-o",
+            "out",
+        ]);
+        assert_eq!(cli.log_level(), log::LevelFilter::Debug);
+        let Command::Run {
+            scenario,
+            record,
+            publish,
+            options,
+        } = cli.command
+        else {
+            panic!("expected run");
+        };
+        assert_eq!(scenario, PathBuf::from("scenarios/default.toml"));
+        assert_eq!(record, None);
+        assert_eq!(publish, None);
+        assert_eq!(options.seed, Some(7));
+        assert_eq!(options.steps, Some(20));
+        assert_eq!(options.step_size, Some(0.05));
+        assert!(options.headless);
+        assert_eq!(options.output_dir, Some(PathBuf::from("out")));
+    }
+
+    #[test]
+    fn parses_batch_and_quiet() {
+        let cli = Cli::parse_from(["particle_filter", "batch", "s.toml", "--runs", "50", "-q"]);
+        assert_eq!(cli.log_level(), log::LevelFilter::Error);
+        assert!(matches!(
+            cli.command,
+            Command::Batch {
+                runs: 50,
+                sequential: false,
+                ..
+            }
+        ));
+        assert!(
+            Cli::try_parse_from(["particle_filter", "batch", "s.toml", "--runs", "0"]).is_err()
+        );
+        for threshold in ["0", "-1", "nan"] {
+            assert!(Cli::try_parse_from([
+                "particle_filter",
+                "batch",
+                "s.toml",
+                "--divergence-threshold",
+                threshold,
+            ])
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn listen_needs_exactly_one_input() {
+        let cli = Cli::parse_from([
+            "particle_filter",
+            "listen",
+            "s.toml",
+            "--udp",
+            "0.0.0.0:9000",
+            "--publish",
+            "udp://127.0.0.1:9001",
+        ]);
+        let Command::Listen {
+            udp, tcp, publish, ..
+        } = cli.command
+        else {
+            panic!("expected listen");
+        };
+        assert_eq!(udp, Some("0.0.0.0:9000".parse().unwrap()));
+        assert_eq!(tcp, None);
+        assert_eq!(publish, Destination::Udp("127.0.0.1:9001".parse().unwrap()));
+
+        assert!(Cli::try_parse_from(["particle_filter", "listen", "s.toml"]).is_err());
+        assert!(Cli::try_parse_from([
+            "particle_filter",
+            "listen",
+            "s.toml",
+            "--udp",
+            "0.0.0.0:9000",
+            "--tcp",
+            "0.0.0.0:9000",
+        ])
+        .is_err());
+    }
+}
@@ -0,0 +1,337 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use agents::dynamics_model::{DynamicsModel, WhiteNoiseAcceleration};
+use agents::rng::RngStream;
+use simulation::batch::{run_scenario_batch, BatchConfig};
+use simulation::live::{Input, Tracker};
+use simulation::publisher::{Destination, Publisher};
+use simulation::recording::{read_records, steps, Record, RecordWriter};
+use simulation::scenario::Scenario;
+use simulation::simulation::Simulation;
+use simulation::sweep::{describe, Sweep};
+
+use crate::cli::RunOptions;
+
+// Load a scenario and apply the command line overrides on top of it.
+fn load_scenario(path: &Path, options: &RunOptions) -> Result<Scenario, Box<dyn Error>> {
+    let mut scenario = Scenario::from_path(path)?;
+    if let Some(steps) = options.steps {
+        scenario.steps = steps;
+    }
+    if let Some(step_size) = options.step_size {
+        scenario.step_size = step_size;
+    }
+    if options.headless {
+        scenario.visualization.enabled = false;
+    }
+    if options.rrd.is_some() {
+        scenario.visualization.rrd = options.rrd.clone();
+    }
+    if options.trace.is_some() {
+        scenario.visualization.trace = options.trace.clone();
+    }
+    if options.seed.is_some() {
+        scenario.seed = options.seed;
+    }
+    scenario.validate()?;
+    Ok(scenario)
+}
+
+fn build_simulation(
+    scenario: &Scenario,
+) -> Result<Simulation<WhiteNoiseAcceleration>, Box<dyn Error>> {
+    let mut builder = scenario.simulation_builder()?;
+    for visualizer in scenario.visualization.visualizers(None)? {
+        builder = builder.visualizer(visualizer);
+    }
+    Ok(builder.build())
+}
+
+fn create_output(dir: &Path, name: &str) -> Result<BufWriter<File>, Box<dyn Error>> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(name);
+    log::info!("writing {}", path.display());
+    Ok(BufWriter::new(File::create(path)?))
+}
+
+// Pick a seed for unseeded scenarios and report it, so any run can be repeated.
+fn ensure_seed(scenario: &mut Scenario) -> u64 {
+    let seed = *scenario
+        .seed
+        .get_or_insert_with(|| RngStream::from_entropy().seed());
+    log::info!("seed {seed}");
+    seed
+}
+
+// Per-step truth and estimate of every element, written to `estimates.csv`.
+struct Estimates(Option<BufWriter<File>>);
+
+impl Estimates {
+    fn create(options: &RunOptions) -> Result<Self, Box<dyn Error>> {
+        let Some(dir) = &options.output_dir else {
+            return Ok(Self(None));
+        };
+        let mut out = create_output(dir, "estimates.csv")?;
+        writeln!(
+            out,
+            "step,name,true_x,true_y,true_z,est_x,est_y,est_z,error"
+        )?;
+        Ok(Self(Some(out)))
+    }
+
+    fn write(
+        &mut self,
+        frame: usize,
+        sim: &Simulation<WhiteNoiseAcceleration>,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(out) = self.0.as_mut() else {
+            return Ok(());
+        };
+        for se in &sim.swarm_elements {
+            let t = se.dynamics_model.position();
+            let e = se.est_position;
+            writeln!(
+                out,
+                "{frame},{},{},{},{},{},{},{},{}",
+                se.name,
+                t.x,
+                t.y,
+                t.z,
+                e.x,
+                e.y,
+                e.z,
+                se.estimation_error()
+            )?;
+        }
+        Ok(())
+    }
+
+    fn finish(self, sim: &Simulation<WhiteNoiseAcceleration>) -> Result<(), Box<dyn Error>> {
+        if let Some(mut out) = self.0 {
+            out.flush()?;
+        }
+        for se in &sim.swarm_elements {
+            log::info!("{}: final error {:.3} m", se.name, se.estimation_error());
+        }
+        Ok(())
+    }
+}
+
+fn open_publisher(destination: Option<&Destination>) -> Result<Option<Publisher>, Box<dyn Error>> {
+    let Some(destination) = destination else {
+        return Ok(None);
+    };
+    log::info!("publishing estimates to {destination}");
+    Ok(Some(publisher(destination)?))
+}
+
+fn publisher(destination: &Destination) -> Result<Publisher, Box<dyn Error>> {
+    Publisher::open(destination).map_err(|e| format!("cannot publish to {destination}: {e}").into())
+}
+
+pub fn run(
+    path: &Path,
+    record: Option<&Path>,
+    publish: Option<&Destination>,
+    options: &RunOptions,
+) -> Result<(), Box<dyn Error>> {
+    let mut scenario = load_scenario(path, options)?;
+    ensure_seed(&mut scenario);
+    let mut sim = build_simulation(&scenario)?;
+    let mut estimates = Estimates::create(options)?;
+    let mut recorder = match record {
+        Some(log) => {
+            log::info!("recording measurements to {}", log.display());
+            Some(RecordWriter::create(log)?)
+        }
+        None => None,
+    };
+    let mut publisher = open_publisher(publish)?;
+
+    log::info!(
+        "running {} for {} steps of {} s",
+        path.display(),
+        scenario.steps,
+        scenario.step_size
+    );
+    for frame in 0..scenario.steps {
+        let records = sim.step(frame, scenario.step_size);
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.write_all(&records)?;
+        }
+        if let Some(publisher) = publisher.as_mut() {
+            publisher.publish_step(&sim.swarm_elements, sim.time(), true)?;
+        }
+        estimates.write(frame, &sim)?;
+    }
+    if let Some(mut recorder) = recorder {
+        recorder.flush()?;
+    }
+    sim.finish()?;
+    estimates.finish(&sim)
+}
+
+pub struct BatchArgs {
+    pub runs: usize,
+    pub parallel: bool,
+    pub divergence_threshold: f64,
+}
+
+pub fn batch(path: &Path, args: &BatchArgs, options: &RunOptions) -> Result<(), Box<dyn Error>> {
+    let mut scenario = load_scenario(path, options)?;
+    // a viewer per run is never wanted
+    scenario.visualization.enabled = false;
+    let seed = ensure_seed(&mut scenario);
+
+    let config = BatchConfig::new(args.runs, scenario.steps, scenario.step_size, seed)
+        .with_parallel(args.parallel)
+        .with_divergence_threshold(args.divergence_threshold);
+    log::info!(
+        "running {} {} times for {} steps of {} s",
+        path.display(),
+        args.runs,
+        scenario.steps,
+        scenario.step_size
+    );
+    let report = run_scenario_batch(&scenario, &config)?;
+
+    for r in &report.runs {
+        log::debug!(
+            "run {} (seed {}): rmse {:.3} m, final error {:.3} m, {:.3} s",
+            r.run,
+            r.seed,
+            r.rmse,
+            r.final_error,
+            r.runtime_s
+        );
+    }
+    println!("{report}");
+
+    if let Some(dir) = &options.output_dir {
+        let mut out = create_output(dir, "batch_runs.csv")?;
+        report.write_runs_csv(&mut out)?;
+        out.flush()?;
+        let mut out = create_output(dir, "batch_steps.csv")?;
+        report.write_steps_csv(&mut out)?;
+        out.flush()?;
+        let mut out = create_output(dir, "batch.json")?;
+        report.write_json(&mut out)?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+// The sweep's seed, if any, is overridden by --seed and overrides the scenario's.
+pub fn sweep(
+    scenario_path: &Path,
+    sweep_path: &Path,
+    parallel: bool,
+    options: &RunOptions,
+) -> Result<(), Box<dyn Error>> {
+    let sweep = Sweep::from_path(sweep_path)?;
+    let mut scenario = load_scenario(scenario_path, options)?;
+    scenario.visualization.enabled = false;
+    // every point reuses the run seeds, so per-run files would overwrite each other
+    let rrd = scenario.visualization.rrd.take();
+    let trace = scenario.visualization.trace.take();
+    if rrd.is_some() || trace.is_some() {
+        log::warn!("sweeps write no recordings or traces");
+    }
+    if options.seed.is_none() && sweep.seed.is_some() {
+        scenario.seed = sweep.seed;
+    }
+    let seed = ensure_seed(&mut scenario);
+
+    let parameters: Vec<_> = sweep.parameters.iter().map(|r| r.parameter).collect();
+    let report = sweep.run(&scenario, seed, parallel, |point| {
+        let s = &point.report.overall;
+        log::info!(
+            "{}: rmse {:.3} m, p95 {:.3} m, divergence {:.1} %",
+            describe(&parameters, &point.values),
+            s.rmse,
+            s.p95,
+            100.0 * point.report.divergence_rate
+        );
+    })?;
+
+    match &options.output_dir {
+        Some(dir) => {
+            let mut out = create_output(dir, "sweep.csv")?;
+            report.write_csv(&mut out)?;
+            out.flush()?;
+        }
+        None => report.write_csv(std::io::stdout().lock())?,
+    }
+    Ok(())
+}
+
+// The scenario supplies the anchors, elements and filter settings; the log replaces
+// the simulated motion and measurements. Steps and step size come from the log.
+pub fn replay(
+    path: &Path,
+    log: &Path,
+    publish: Option<&Destination>,
+    options: &RunOptions,
+) -> Result<(), Box<dyn Error>> {
+    let mut scenario = load_scenario(path, options)?;
+    ensure_seed(&mut scenario);
+    let records = read_records(log)?;
+    let mut sim = build_simulation(&scenario)?;
+    let mut estimates = Estimates::create(options)?;
+    let mut publisher = open_publisher(publish)?;
+
+    log::info!("replaying {} through {}", log.display(), path.display());
+    let limit = options.steps.unwrap_or(usize::MAX);
+    for (frame, step) in steps(&records).take(limit).enumerate() {
+        sim.replay_step(frame, step);
+        if let Some(publisher) = publisher.as_mut() {
+            // without recorded truth the error would be against the scenario's start
+            let truth_known = step.iter().any(|r| matches!(r, Record::Truth { .. }));
+            publisher.publish_step(&sim.swarm_elements, sim.time(), truth_known)?;
+        }
+        estimates.write(frame, &sim)?;
+    }
+    sim.finish()?;
+    estimates.finish(&sim)
+}
+
+pub fn listen(
+    path: &Path,
+    input: Input,
+    publish: &Destination,
+    seed: Option<u64>,
+) -> Result<(), Box<dyn Error>> {
+    let mut scenario = Scenario::from_path(path)?;
+    if seed.is_some() {
+        scenario.seed = seed;
+    }
+    ensure_seed(&mut scenario);
+    // the scenario's true motion is ignored; its tags and anchors are the real ones.
+    // Nothing is drawn, so the visualizers and their output files are left out.
+    let sim = scenario.simulation_builder()?.build();
+    let mut tracker = Tracker::new(sim.swarm_elements, sim.anchors);
+    let mut publisher = publisher(publish)?;
+
+    log::info!("listening on {input:?}");
+    tracker.listen(input, &mut publisher)?;
+    Ok(())
+}
+
+pub fn validate(path: &Path) -> Result<(), Box<dyn Error>> {
+    let scenario = Scenario::from_path(path)?;
+    // building catches what parsing cannot, e.g. missing terrain files; the
+    // visualizers are left out since they would create the output files
+    scenario.simulation_builder()?;
+    println!(
+        "{}: ok, {} anchors, {} swarm elements, {} steps of {} s",
+        path.display(),
+        scenario.anchors.len(),
+        scenario.swarm_elements.len(),
+        scenario.steps,
+        scenario.step_size
+    );
+    Ok(())
+}
@@ -1,55 +1,67 @@
-use agents::{
-    anchor::Anchor,
-    dynamics_model::WhiteNoiseAcceleration,
-    particle_filter::{ParticleFilter, Sphere},
-    swarm_element::SwarmElement,
-};
-use nalgebra::Vector3;
-use simulation::simulation::Simulation;
-use visualization::visualization::RerunVisualization;
+mod cli;
+mod commands;
 
-fn main() {
-    let swarm_name = String::from("1000");
+use std::error::Error;
 
-    let position = Vector3::new(100.0, 20.0, 5.0);
-    let velocity = Vector3::new(10.0, 0.0, 0.0);
-    let mean_a = Vector3::new(0.0, -50.0, 10.0);
-    let sigma_a = Vector3::new(0.5, 50.0, 50.0);
-    let dynamics_model = WhiteNoiseAcceleration::new(position, velocity, mean_a, sigma_a);
+use clap::Parser;
+use simulation::live::Input;
 
-    let radius = 200.0;
-    let origin = Vector3::new(10.0, 10.0, 5.0);
-    let sphere = Sphere::new(radius, origin).unwrap();
-    let num_particles = 5_000;
-    let tau = 0.5;
-    let particle_filter = ParticleFilter::new(&sphere, num_particles, tau);
+use cli::{Cli, Command};
 
-    let sd_transmission_noise = 0.1;
-    let sd_ranging_noise = 0.8;
+fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    env_logger::Builder::new()
+        .filter_level(cli.log_level())
+        .parse_default_env()
+        .init();
 
-    let swarm_element_1 = SwarmElement::new(
-        swarm_name,
-        dynamics_model,
-        particle_filter,
-        sd_transmission_noise,
-        sd_ranging_noise,
-    );
-
-    let anchor_std = 0.4;
-    let anchor1 = Anchor::new(Vector3::new(0.0, 0.0, 0.0), anchor_std);
-    let anchor2 = Anchor::new(Vector3::new(0.0, 50.0, 0.0), anchor_std);
-    let anchor3 = Anchor::new(Vector3::new(50.0, 0.0, 0.0), anchor_std);
-
-    let visualizer = RerunVisualization::new(String::from("ToA-Particle-Filter"))
-        .expect("Unable to create rerun visualization");
-
-    let mut sim = Simulation::builder()
-        .swarm_elements(vec![swarm_element_1])
-        .anchors(vec![anchor1, anchor2, anchor3])
-        .visualizer(visualizer)
-        .build();
-
-    let time_steps = 1000;
-    let step_size = 0.1;
-    sim.run(time_steps, step_size);
+    match &cli.command {
+        Command::Run {
+            scenario,
+            record,
+            publish,
+            options,
+        } => commands::run(scenario, record.as_deref(), publish.as_ref(), options),
+        Command::Batch {
+            scenario,
+            runs,
+            sequential,
+            divergence_threshold,
+            options,
+        } => {
+            let config = commands::BatchArgs {
+                runs: *runs as usize,
+                parallel: !sequential,
+                divergence_threshold: *divergence_threshold,
+            };
+            commands::batch(scenario, &config, options)
+        }
+        Command::Sweep {
+            scenario,
+            sweep,
+            sequential,
+            options,
+        } => commands::sweep(scenario, sweep, !sequential, options),
+        Command::Replay {
+            scenario,
+            log,
+            publish,
+            options,
+        } => commands::replay(scenario, log, publish.as_ref(), options),
+        Command::Listen {
+            scenario,
+            udp,
+            tcp,
+            publish,
+            seed,
+        } => {
+            let input = match (udp, tcp) {
+                (Some(addr), _) => Input::Udp(*addr),
+                (None, Some(addr)) => Input::Tcp(*addr),
+                (None, None) => unreachable!("clap requires an input"),
+            };
+            commands::listen(scenario, input, publish, *seed)
+        }
+        Command::Validate { scenario } => commands::validate(scenario),
+    }
 }
@@ -1,2 +1,3 @@
 pub mod terrain_shape;
+pub mod trace;
 pub mod visualization;
@@ -1,40 +1,115 @@
-pub fn load_contours_centered(
-    path: &str,
-) -> Result<Vec<Vec<[f32; 3]>>, Box<dyn std::error::Error>> {
+use std::path::Path;
+
+// Contour lines of a PolylineZ shapefile, one strip per feature, in the map
+// coordinates of the file. Kept in f64: projected eastings and northings are too
+// large for f32 to hold to the metre.
+pub fn load_contours(
+    path: impl AsRef<Path>,
+) -> Result<Vec<Vec<[f64; 3]>>, Box<dyn std::error::Error>> {
     let lines: Vec<shapefile::PolylineZ> = shapefile::read_shapes_as(path)?;
     let contours = lines
         .into_iter()
         .map(|pl| {
             pl.parts()
                 .iter()
-                .flat_map(|part| part.iter().map(|p| [p.x as f32, p.y as f32, p.z as f32]))
+                .flat_map(|part| part.iter().map(|p| [p.x, p.y, p.z]))
                 .collect()
         })
         .collect();
-    Ok(to_origin_and_scale(contours))
+    Ok(contours)
 }
 
-pub fn to_origin_and_scale(mut geoms: Vec<Vec<[f32; 3]>>) -> Vec<Vec<[f32; 3]>> {
-    let mut min_x = f32::INFINITY;
-    let mut min_y = f32::INFINITY;
-
-    for feature in &geoms {
-        for [x, y, _z] in feature {
-            if *x < min_x {
-                min_x = *x;
-            }
-            if *y < min_y {
-                min_y = *y;
-            }
-        }
-    }
+// Outer ring of the first polygon of a Polygon shapefile, in map coordinates.
+pub fn load_footprint(path: impl AsRef<Path>) -> Result<Vec<[f64; 2]>, Box<dyn std::error::Error>> {
+    let polygons: Vec<shapefile::Polygon> = shapefile::read_shapes_as(path)?;
+    let outer = polygons
+        .iter()
+        .flat_map(|polygon| polygon.rings())
+        .find(|ring| matches!(ring, shapefile::PolygonRing::Outer(_)))
+        .ok_or("no polygon in shapefile")?;
+    Ok(outer.points().iter().map(|p| [p.x, p.y]).collect())
+}
 
+// Move map coordinates into the simulation frame, whose origin sits at `origin` on
+// the map, and stretch heights above it by `vertical_exaggeration`.
+pub fn to_origin_and_scale(
+    mut geoms: Vec<Vec<[f64; 3]>>,
+    origin: [f64; 3],
+    vertical_exaggeration: f64,
+) -> Vec<Vec<[f64; 3]>> {
     for feature in &mut geoms {
         for p in feature {
-            p[0] = p[0] - min_x;
-            p[1] = p[1] - min_y;
+            p[0] -= origin[0];
+            p[1] -= origin[1];
+            p[2] = (p[2] - origin[2]) * vertical_exaggeration;
         }
     }
-
     geoms
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_coordinates_move_to_the_shared_origin() {
+        let contours = vec![vec![
+            [512_010.5, 6_650_020.25, 130.0],
+            [512_000.0, 6_650_000.0, 120.0],
+        ]];
+        let local = to_origin_and_scale(contours, [512_000.0, 6_650_000.0, 120.0], 2.0);
+        assert_eq!(local, vec![vec![[10.5, 20.25, 20.0], [0.0, 0.0, 0.0]]]);
+    }
+
+    #[test]
+    fn contours_keep_full_map_precision() {
+        use shapefile::{PointZ, PolylineZ, ShapeWriter};
+
+        let line = PolylineZ::new(vec![
+            PointZ::new(512_000.25, 6_650_000.75, 120.5, 0.0),
+            PointZ::new(512_001.25, 6_650_000.75, 121.5, 0.0),
+        ]);
+        let path = std::env::temp_dir().join(format!("contours_{}.shp", std::process::id()));
+        ShapeWriter::from_path(&path)
+            .unwrap()
+            .write_shapes(&[line])
+            .unwrap();
+        let contours = load_contours(&path);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("shx")).unwrap();
+
+        let local = to_origin_and_scale(contours.unwrap(), [512_000.0, 6_650_000.0, 120.0], 1.0);
+        assert_eq!(local, vec![vec![[0.25, 0.75, 0.5], [1.25, 0.75, 1.5]]]);
+    }
+
+    #[test]
+    fn footprint_is_the_outer_ring() {
+        use shapefile::{Point, Polygon, PolygonRing, ShapeWriter};
+
+        let ring = vec![
+            Point::new(512_000.0, 6_650_000.0),
+            Point::new(512_000.0, 6_650_010.0),
+            Point::new(512_010.0, 6_650_010.0),
+            Point::new(512_000.0, 6_650_000.0),
+        ];
+        let polygon = Polygon::with_rings(vec![PolygonRing::Outer(ring)]);
+        let path = std::env::temp_dir().join(format!("footprint_{}.shp", std::process::id()));
+        ShapeWriter::from_path(&path)
+            .unwrap()
+            .write_shapes(&[polygon])
+            .unwrap();
+        let footprint = load_footprint(&path);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("shx")).unwrap();
+
+        assert_eq!(
+            footprint.unwrap(),
+            vec![
+                [512_000.0, 6_650_000.0],
+                [512_000.0, 6_650_010.0],
+                [512_010.0, 6_650_010.0],
+                [512_000.0, 6_650_000.0],
+            ]
+        );
+    }
+}
@@ -0,0 +1,282 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use serde_json::json;
+
+use crate::visualization::{Command, Visualizer};
+
+const CSV_HEADER: &str = "frame,kind,entity,index,x,y,z,value";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    // One row per point, trajectory end or scalar.
+    Csv,
+    // One JSON object per command.
+    Ndjson,
+}
+
+impl TraceFormat {
+    // `.csv` is CSV, anything else NDJSON.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => TraceFormat::Csv,
+            _ => TraceFormat::Ndjson,
+        }
+    }
+}
+
+// Everything that would be drawn, as text, for runs without a viewer. Colours and
+// sizes are left out. Writing stops at the first error, which `finish` returns.
+pub struct TraceWriter<W: Write + Send> {
+    out: W,
+    format: TraceFormat,
+    frame: i64,
+    error: Option<io::Error>,
+}
+
+impl TraceWriter<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        Self::new(
+            BufWriter::new(File::create(path)?),
+            TraceFormat::from_path(path),
+        )
+    }
+}
+
+impl<W: Write + Send> TraceWriter<W> {
+    pub fn new(mut out: W, format: TraceFormat) -> io::Result<Self> {
+        if format == TraceFormat::Csv {
+            writeln!(out, "{CSV_HEADER}")?;
+        }
+        Ok(Self {
+            out,
+            format,
+            frame: 0,
+            error: None,
+        })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn write(&mut self, command: Command) -> io::Result<()> {
+        let frame = self.frame;
+        match (self.format, command) {
+            (_, Command::SetFrame(frame)) => self.frame = frame,
+            (TraceFormat::Csv, Command::LogPoints(entity, points, _, _)) => {
+                for (i, [x, y, z]) in points.into_iter().enumerate() {
+                    writeln!(self.out, "{frame},points,{entity},{i},{x},{y},{z},")?;
+                }
+            }
+            (TraceFormat::Csv, Command::LogTrajectory(entity, from, to)) => {
+                for (i, [x, y, z]) in [from, to].into_iter().enumerate() {
+                    writeln!(self.out, "{frame},trajectory,{entity},{i},{x},{y},{z},")?;
+                }
+            }
+            (TraceFormat::Csv, Command::LogScalarPlot(entity, value)) => {
+                writeln!(self.out, "{frame},scalar,{entity},,,,,{value}")?;
+            }
+            // the centre, then the semi-axes
+            (TraceFormat::Csv, Command::LogEllipsoid(entity, center, semi_axes, _)) => {
+                for (i, [x, y, z]) in [center, semi_axes].into_iter().enumerate() {
+                    writeln!(self.out, "{frame},ellipsoid,{entity},{i},{x},{y},{z},")?;
+                }
+            }
+            (TraceFormat::Csv, Command::LogSpheres(entity, centers, radii)) => {
+                for (i, ([x, y, z], r)) in centers.into_iter().zip(radii).enumerate() {
+                    writeln!(self.out, "{frame},sphere,{entity},{i},{x},{y},{z},{r}")?;
+                }
+            }
+            // both ends of every segment
+            (TraceFormat::Csv, Command::LogLines(entity, segments, _)) => {
+                for (i, [x, y, z]) in segments.into_iter().flatten().enumerate() {
+                    writeln!(self.out, "{frame},line,{entity},{i},{x},{y},{z},")?;
+                }
+            }
+            // every vertex, numbered through the strips
+            (TraceFormat::Csv, Command::LogStaticStrips(entity, strips)) => {
+                for (i, [x, y, z]) in strips.into_iter().flatten().enumerate() {
+                    writeln!(self.out, "{frame},static,{entity},{i},{x},{y},{z},")?;
+                }
+            }
+            (TraceFormat::Ndjson, Command::LogPoints(entity, points, _, _)) => {
+                let line = json!({
+                    "frame": frame,
+                    "kind": "points",
+                    "entity": entity,
+                    "points": points,
+                });
+                writeln!(self.out, "{line}")?;
+            }
+            (TraceFormat::Ndjson, Command::LogTrajectory(entity, from, to)) => {
+                let line = json!({
+                    "frame": frame,
+                    "kind": "trajectory",
+                    "entity": entity,
+                    "from": from,
+                    "to": to,
+                });
+                writeln!(self.out, "{line}")?;
+            }
+            (TraceFormat::Ndjson, Command::LogScalarPlot(entity, value)) => {
+                let line = json!({
+                    "frame": frame,
+                    "kind": "scalar",
+                    "entity": entity,
+                    "value": value,
+                });
+                writeln!(self.out, "{line}")?;
+            }
+            (TraceFormat::Ndjson, Command::LogEllipsoid(entity, center, semi_axes, rotation)) => {
+                let line = json!({
+                    "frame": frame,
+                    "kind": "ellipsoid",
+                    "entity": entity,
+                    "center": center,
+                    "semi_axes": semi_axes,
+                    "rotation": rotation,
+                });
+                writeln!(self.out, "{line}")?;
+            }
+            (TraceFormat::Ndjson, Command::LogSpheres(entity, centers, radii)) => {
+                let line = json!({
+                    "frame": frame,
+                    "kind": "spheres",
+                    "entity": entity,
+                    "centers": centers,
+                    "radii": radii,
+                });
+                writeln!(self.out, "{line}")?;
+            }
+            (TraceFormat::Ndjson, Command::LogLines(entity, segments, _)) => {
+                let line = json!({
+                    "frame": frame,
+                    "kind": "lines",
+                    "entity": entity,
+                    "segments": segments,
+                });
+                writeln!(self.out, "{line}")?;
+            }
+            (TraceFormat::Ndjson, Command::LogStaticStrips(entity, strips)) => {
+                let line = json!({
+                    "frame": frame,
+                    "kind": "static",
+                    "entity": entity,
+                    "strips": strips,
+                });
+                writeln!(self.out, "{line}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> Visualizer for TraceWriter<W> {
+    fn log(&mut self, command: Command) {
+        if self.error.is_none()
+            && let Err(e) = self.write(command)
+        {
+            self.error = Some(e);
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.out.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands() -> Vec<Command> {
+        vec![
+            Command::SetFrame(3),
+            Command::LogPoints(
+                String::from("a/est_position"),
+                vec![[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]],
+                1.0,
+                None,
+            ),
+            Command::LogTrajectory(String::from("a/traj"), [0.0; 3], [1.0; 3]),
+            Command::LogScalarPlot(String::from("estimation_error/a"), 0.5),
+            Command::LogSpheres(String::from("a/ranges"), vec![[0.0; 3]], vec![2.5]),
+        ]
+    }
+
+    fn trace(format: TraceFormat) -> String {
+        let mut writer = TraceWriter::new(Vec::new(), format).unwrap();
+        for command in commands() {
+            writer.log(command);
+        }
+        writer.finish().unwrap();
+        String::from_utf8(writer.into_inner()).unwrap()
+    }
+
+    // Accepts `limit` bytes, then fails.
+    struct Full {
+        limit: usize,
+    }
+
+    impl Write for Full {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf.len() > self.limit {
+                return Err(io::Error::new(io::ErrorKind::StorageFull, "full"));
+            }
+            self.limit -= buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_errors_are_kept_for_finish() {
+        let mut writer = TraceWriter::new(Full { limit: 100 }, TraceFormat::Ndjson).unwrap();
+        for command in commands() {
+            writer.log(command);
+        }
+        let error = writer.finish().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::StorageFull);
+
+        let mut writer = TraceWriter::new(Full { limit: 4096 }, TraceFormat::Ndjson).unwrap();
+        for command in commands() {
+            writer.log(command);
+        }
+        assert!(writer.finish().is_ok());
+    }
+
+    #[test]
+    fn csv_has_a_row_per_point() {
+        let text = trace(TraceFormat::Csv);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 7);
+        assert_eq!(lines[0], CSV_HEADER);
+        assert_eq!(lines[2], "3,points,a/est_position,1,4,5,6,");
+        assert_eq!(lines[5], "3,scalar,estimation_error/a,,,,,0.5");
+        assert_eq!(lines[6], "3,sphere,a/ranges,0,0,0,0,2.5");
+    }
+
+    #[test]
+    fn ndjson_has_a_line_per_command() {
+        let text = trace(TraceFormat::Ndjson);
+        let lines: Vec<serde_json::Value> = text
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["frame"], 3);
+        assert_eq!(lines[0]["points"][1][2], 6.0);
+        assert_eq!(lines[1]["to"], json!([1.0, 1.0, 1.0]));
+        assert_eq!(lines[2]["value"], 0.5);
+        assert_eq!(lines[3]["radii"], json!([2.5]));
+    }
+}
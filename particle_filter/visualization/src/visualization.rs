@@ -1,18 +1,101 @@
 use rerun::archetypes::Clear;
-use rerun::{Points3D, RecordingStream, Scalars, SpawnOptions};
+use rerun::components::FillMode;
+use rerun::{
+    Ellipsoids3D, LineStrips3D, Points3D, Quaternion, RecordingStream, Scalars, SpawnOptions,
+};
 use std::collections::HashMap;
+use std::io;
+use std::path::Path;
 use std::{
     sync::mpsc::{self, SyncSender},
     thread::{self, JoinHandle},
 };
 
-use crate::terrain_shape;
-
+#[derive(Debug, Clone, PartialEq)]
 pub enum Command {
     SetFrame(i64),
     LogPoints(String, Vec<[f64; 3]>, f64, Option<Vec<[u8; 4]>>),
     LogTrajectory(String, [f64; 3], [f64; 3]),
     LogScalarPlot(String, f64),
+    // Centre, semi-axes and orientation as an [x, y, z, w] quaternion.
+    LogEllipsoid(String, [f64; 3], [f64; 3], [f64; 4]),
+    // Centres and radii, drawn as wireframes.
+    LogSpheres(String, Vec<[f64; 3]>, Vec<f64>),
+    // Line segments, one colour each.
+    LogLines(String, Vec<[[f64; 3]; 2]>, Vec<[u8; 4]>),
+    // Polylines that hold for the whole recording, e.g. terrain contours.
+    LogStaticStrips(String, Vec<Vec<[f64; 3]>>),
+}
+
+// Anything the simulation can draw to.
+pub trait Visualizer: Send {
+    fn log(&mut self, command: Command);
+
+    // Called once after the last frame: flush, and report any error `log` could not.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Visualizer for Box<dyn Visualizer> {
+    fn log(&mut self, command: Command) {
+        (**self).log(command);
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+// Swallows everything, e.g. to exercise the drawing code in headless runs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopVisualizer;
+
+impl Visualizer for NoopVisualizer {
+    fn log(&mut self, _command: Command) {}
+}
+
+// Sends every command to each of several visualizers.
+#[derive(Default)]
+pub struct FanoutVisualizer {
+    visualizers: Vec<Box<dyn Visualizer>>,
+}
+
+impl FanoutVisualizer {
+    pub fn new(visualizers: Vec<Box<dyn Visualizer>>) -> Self {
+        Self { visualizers }
+    }
+
+    pub fn push(&mut self, visualizer: impl Visualizer + 'static) {
+        self.visualizers.push(Box::new(visualizer));
+    }
+
+    pub fn len(&self) -> usize {
+        self.visualizers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.visualizers.is_empty()
+    }
+}
+
+impl Visualizer for FanoutVisualizer {
+    fn log(&mut self, command: Command) {
+        if let Some((last, rest)) = self.visualizers.split_last_mut() {
+            for visualizer in rest {
+                visualizer.log(command.clone());
+            }
+            last.log(command);
+        }
+    }
+
+    // Every visualizer is finished; the first error is returned.
+    fn finish(&mut self) -> io::Result<()> {
+        self.visualizers
+            .iter_mut()
+            .map(|visualizer| visualizer.finish())
+            .fold(Ok(()), Result::and)
+    }
 }
 
 pub struct RerunVisualization {
@@ -21,6 +104,7 @@ pub struct RerunVisualization {
 }
 
 impl RerunVisualization {
+    // Spawn a live viewer.
     pub fn new(visulization_name: String) -> Result<Self, Box<dyn std::error::Error>> {
         let rec = rerun::RecordingStreamBuilder::new(visulization_name).spawn_opts(
             &SpawnOptions {
@@ -30,7 +114,19 @@ impl RerunVisualization {
             },
             None,
         )?;
+        Ok(Self::with_stream(rec))
+    }
+
+    // Record to an `.rrd` file for opening in the viewer later.
+    pub fn save(
+        visulization_name: String,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let rec = rerun::RecordingStreamBuilder::new(visulization_name).save(path.as_ref())?;
+        Ok(Self::with_stream(rec))
+    }
 
+    fn with_stream(rec: RecordingStream) -> Self {
         let (tx, rx) = mpsc::sync_channel::<Command>(1000);
 
         let handle = thread::spawn(move || {
@@ -133,34 +229,64 @@ impl RerunVisualization {
                         rec.log(path, &Scalars::single(value))
                             .expect("Rerun: unable to log trajectory");
                     }
+                    Command::LogEllipsoid(path, center, semi_axes, [x, y, z, w]) => {
+                        let ellipsoid = Ellipsoids3D::from_centers_and_half_sizes(
+                            [center.map(|c| c as f32)],
+                            [semi_axes.map(|a| a as f32)],
+                        )
+                        .with_quaternions([Quaternion::from_xyzw([x, y, z, w].map(|q| q as f32))])
+                        .with_fill_mode(FillMode::MajorWireframe);
+                        rec.log(path, &ellipsoid)
+                            .expect("Rerun: unable to log ellipsoid");
+                    }
+                    Command::LogSpheres(path, centers, radii) => {
+                        let spheres = Ellipsoids3D::from_centers_and_radii(
+                            centers.into_iter().map(|c| c.map(|x| x as f32)),
+                            radii.into_iter().map(|r| r as f32),
+                        )
+                        .with_fill_mode(FillMode::MajorWireframe);
+                        rec.log(path, &spheres)
+                            .expect("Rerun: unable to log spheres");
+                    }
+                    Command::LogLines(path, segments, colors) => {
+                        let strips = segments
+                            .into_iter()
+                            .map(|segment| segment.map(|p| p.map(|x| x as f32)).to_vec());
+                        rec.log(path, &LineStrips3D::new(strips).with_colors(colors))
+                            .expect("Rerun: unable to log lines");
+                    }
+                    Command::LogStaticStrips(path, strips) => {
+                        let strips = strips.into_iter().map(|strip| {
+                            strip
+                                .iter()
+                                .map(|p| p.map(|x| x as f32))
+                                .collect::<Vec<_>>()
+                        });
+                        rec.log_static(
+                            path,
+                            &LineStrips3D::new(strips).with_colors([[150, 120, 90, 255]]),
+                        )
+                        .expect("Rerun: unable to log static strips");
+                    }
                 }
             }
         });
 
-        Ok(Self {
+        Self {
             tx: Some(tx),
             handle: Some(handle),
-        })
+        }
     }
+}
 
-    pub fn log(&mut self, command: Command) {
+impl Visualizer for RerunVisualization {
+    fn log(&mut self, command: Command) {
         self.tx
             .as_ref()
             .unwrap()
             .send(command)
             .expect("logging thread has died unexpectedly");
     }
-
-    #[allow(dead_code)]
-    fn draw_terrain(rec: &RecordingStream) {
-        let contours = terrain_shape::load_contours_centered("contours.shp")
-            .expect("Unable to load contours from file");
-        for (i, strip) in contours.iter().enumerate() {
-            let path = format!("/contours/{i:06}");
-            rec.log_static(path, &rerun::LineStrips3D::new([strip.clone()]))
-                .expect("Unable to draw tarrain contours");
-        }
-    }
 }
 
 impl Drop for RerunVisualization {